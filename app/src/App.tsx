@@ -1,6 +1,12 @@
-import { useQuery } from "@tanstack/react-query";
-import { useState } from "react";
-import { API_URL, ProgrammeResult, searchProgrammes } from "./lib/api";
+import { useQuery, useQueryClient } from "@tanstack/react-query";
+import { FormEvent, useState } from "react";
+import {
+  API_URL,
+  fetchMe,
+  login,
+  ProgrammeResult,
+  searchProgrammes,
+} from "./lib/api";
 import { Input } from "./components/ui/input";
 import { Search, Clock, Calendar, Tv } from "lucide-react";
 import { Button } from "./components/ui/button";
@@ -17,6 +23,63 @@ import { TvPlayer } from "./components/tv-player";
 import { useDebounce } from "./hooks/useDebounce";
 
 export default function App() {
+  const { data: me, isLoading } = useQuery({
+    queryKey: ["me"],
+    queryFn: fetchMe,
+    retry: false,
+  });
+
+  if (isLoading) {
+    return null;
+  }
+  if (!me) {
+    return <LoginForm />;
+  }
+  return <SearchPage />;
+}
+
+const LoginForm = () => {
+  const queryClient = useQueryClient();
+  const [username, setUsername] = useState("");
+  const [password, setPassword] = useState("");
+  const [error, setError] = useState<string | null>(null);
+
+  const onSubmit = async (e: FormEvent) => {
+    e.preventDefault();
+    try {
+      const user = await login(username, password);
+      queryClient.setQueryData(["me"], user);
+    } catch {
+      setError("Invalid username or password");
+    }
+  };
+
+  return (
+    <div className="min-h-screen bg-background p-4 flex items-center justify-center">
+      <form
+        onSubmit={onSubmit}
+        className="flex flex-col gap-3 w-full max-w-xs"
+      >
+        <Input
+          placeholder="Username"
+          value={username}
+          onChange={(e) => setUsername(e.target.value)}
+          autoFocus
+        />
+        <Input
+          type="password"
+          placeholder="Password"
+          value={password}
+          onChange={(e) => setPassword(e.target.value)}
+        />
+        {error && <p className="text-sm text-destructive">{error}</p>}
+        <Button type="submit">Sign in</Button>
+      </form>
+    </div>
+  );
+};
+
+function SearchPage() {
   const [searchQuery, setSearchQuery] = useState("");
   const [includeHidden, setIncludeHidden] = useState(false);
   const [selectedUrl, setSelectedUrl] = useState<string | null>(null);
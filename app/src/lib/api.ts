@@ -8,8 +8,29 @@ export const API_URL =
 export const api = ky.create({
   prefixUrl: API_URL,
   retry: 0,
+  credentials: "include",
 });
 
+export interface User {
+  id: number;
+  username: string;
+  admin: boolean;
+  createdAt: string;
+  token: string | null;
+}
+
+export function fetchMe() {
+  return api.get("api/me").json<User>();
+}
+
+export function login(username: string, password: string) {
+  return api.post("api/login", { json: { username, password } }).json<User>();
+}
+
+export function logout() {
+  return api.post("api/logout");
+}
+
 export function searchProgrammes(query: string, includeHidden?: boolean) {
   return api
     .get("search", {
@@ -26,14 +47,21 @@ export interface ProgrammeResult {
   programmeDesc: string;
   start: string;
   stop: string;
+  catchupUrl: string | null;
+  starred: boolean;
+  favourite: boolean;
 }
 
 export interface ChannelResult {
   channelName: string;
   url: string;
+  favourite: boolean;
 }
 
 export interface SearchResult {
   programmes: ProgrammeResult[];
+  totalProgrammes: number;
+  offset: number;
+  limit: number;
   channels: ChannelResult[];
 }
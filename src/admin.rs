@@ -0,0 +1,210 @@
+use std::time::Instant;
+
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    Extension, Json,
+};
+use chrono::{DateTime, Local, Utc};
+use serde::Serialize;
+
+use crate::{
+    auth::{require_admin, CurrentUser},
+    refresh::{Backoff, SourceConfig},
+    AppState, FileFetch,
+};
+
+/// The most recent failed refresh of a source, cleared by the next successful one.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FetchError {
+    message: String,
+    at: DateTime<Utc>,
+}
+
+impl FetchError {
+    pub fn new(error: &anyhow::Error) -> Self {
+        Self {
+            message: format!("{error:#}"),
+            at: Utc::now(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SourceStatus {
+    pub cache_ttl_secs: u64,
+    pub cached: bool,
+    pub fetched_at: Option<DateTime<Utc>>,
+    pub age_secs: Option<u64>,
+    pub stale: bool,
+    pub last_attempt_at: Option<DateTime<Utc>>,
+    pub last_error: Option<FetchError>,
+    /// Failed refreshes since the last successful one.
+    pub consecutive_failures: u32,
+    /// Seconds until a failed refresh may be retried, while backing off.
+    pub backoff_remaining_secs: Option<u64>,
+    pub next_scheduled_refresh: Option<DateTime<Utc>>,
+    pub refreshing: bool,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PlaylistStatus {
+    #[serde(flatten)]
+    pub source: SourceStatus,
+    pub entries: usize,
+    pub filtered_entries: usize,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EpgStatus {
+    #[serde(flatten)]
+    pub source: SourceStatus,
+    pub channels: usize,
+    pub programmes: usize,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AdminStatus {
+    playlist: PlaylistStatus,
+    epg: EpgStatus,
+}
+
+/// Wall clock time of `instant`, which may lie in the future.
+fn to_utc(instant: Instant) -> DateTime<Utc> {
+    let now = Instant::now();
+    let utc_now = Utc::now();
+    match now.checked_duration_since(instant) {
+        Some(ago) => utc_now - chrono::Duration::from_std(ago).unwrap_or_default(),
+        None => utc_now + chrono::Duration::from_std(instant - now).unwrap_or_default(),
+    }
+}
+
+fn source_status(
+    config: &SourceConfig,
+    fetched: Option<Instant>,
+    backoff: &Backoff,
+    last_error: Option<FetchError>,
+    refreshing: bool,
+) -> SourceStatus {
+    SourceStatus {
+        cache_ttl_secs: config.cache_ttl.as_secs(),
+        cached: fetched.is_some(),
+        fetched_at: fetched.map(to_utc),
+        age_secs: fetched.map(|fetched| fetched.elapsed().as_secs()),
+        stale: fetched.is_none_or(|fetched| fetched.elapsed() > config.cache_ttl),
+        last_attempt_at: backoff.last_attempt.map(to_utc),
+        last_error,
+        consecutive_failures: backoff.failures,
+        backoff_remaining_secs: backoff.remaining().map(|remaining| remaining.as_secs()),
+        next_scheduled_refresh: config
+            .schedule
+            .as_ref()
+            .and_then(|schedule| schedule.next_after(&Local::now()))
+            .map(|next| next.with_timezone(&Utc)),
+        refreshing,
+    }
+}
+
+pub fn playlist_status(app_state: &AppState) -> PlaylistStatus {
+    let cached = app_state.cached_playlist.read().unwrap();
+    let fetch = cached.as_ref();
+    PlaylistStatus {
+        source: source_status(
+            &app_state.playlist_config,
+            fetch.map(FileFetch::fetched),
+            &app_state.playlist_backoff.read().unwrap(),
+            app_state.playlist_last_error.read().unwrap().clone(),
+            app_state.playlist_refresh_lock.try_lock().is_err(),
+        ),
+        entries: fetch.map_or(0, |fetch| fetch.playlist.entries.len()),
+        filtered_entries: fetch.map_or(0, |fetch| fetch.playlist.filtered_entries.len()),
+    }
+}
+
+pub fn epg_status(app_state: &AppState) -> EpgStatus {
+    let cached = app_state.cached_epg.read().unwrap();
+    let fetch = cached.as_ref();
+    EpgStatus {
+        source: source_status(
+            &app_state.epg_config,
+            fetch.map(FileFetch::fetched),
+            &app_state.epg_backoff.read().unwrap(),
+            app_state.epg_last_error.read().unwrap().clone(),
+            app_state.epg_refresh_lock.try_lock().is_err(),
+        ),
+        channels: fetch.map_or(0, |fetch| fetch.epg.channels.len()),
+        programmes: fetch.map_or(0, |fetch| fetch.epg.programmes.len()),
+    }
+}
+
+fn admin_status(app_state: &AppState) -> AdminStatus {
+    AdminStatus {
+        playlist: playlist_status(app_state),
+        epg: epg_status(app_state),
+    }
+}
+
+pub async fn status(
+    State(app_state): State<AppState>,
+    Extension(CurrentUser(user)): Extension<CurrentUser>,
+) -> Result<Json<AdminStatus>, (StatusCode, String)> {
+    require_admin(&user)?;
+    Ok(Json(admin_status(&app_state)))
+}
+
+/// Refreshes the playlist or EPG right away instead of waiting for the cache to expire.
+pub async fn refresh(
+    Path(source): Path<String>,
+    State(app_state): State<AppState>,
+    Extension(CurrentUser(user)): Extension<CurrentUser>,
+) -> Result<Json<AdminStatus>, (StatusCode, String)> {
+    require_admin(&user)?;
+    tracing::info!(source, user = %user.username, "Forced refresh requested");
+    let result = match source.as_str() {
+        "playlist" => app_state.force_refresh_playlist().await.map(|_| ()),
+        "epg" => app_state.force_refresh_epg().await.map(|_| ()),
+        _ => return Err((StatusCode::NOT_FOUND, format!("Unknown source {source}"))),
+    };
+
+    result.map_err(|error| {
+        tracing::warn!(?error, source, "Forced refresh failed");
+        (
+            StatusCode::BAD_GATEWAY,
+            format!("Failed to refresh {source}: {error:#}"),
+        )
+    })?;
+    Ok(Json(admin_status(&app_state)))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn test_source_status_reports_backoff_and_staleness() {
+        let config = SourceConfig {
+            cache_ttl: Duration::from_secs(60),
+            ..SourceConfig::from_env("TEST")
+        };
+        let mut backoff = Backoff::default();
+        backoff.attempt();
+        backoff.failed(&config);
+        let status = source_status(&config, None, &backoff, None, false);
+        assert!(!status.cached && status.stale);
+        assert_eq!(status.consecutive_failures, 1);
+        assert!(status.backoff_remaining_secs.is_some());
+
+        let long_ago = Instant::now() - config.cache_ttl * 2;
+        let status = source_status(&config, Some(long_ago), &Backoff::default(), None, false);
+        assert!(status.cached && status.stale);
+        assert_eq!(status.backoff_remaining_secs, None);
+        assert!(status.age_secs.unwrap() >= config.cache_ttl.as_secs() * 2);
+    }
+}
@@ -0,0 +1,628 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::{Path as FsPath, PathBuf},
+    sync::{Arc, RwLock},
+};
+
+use anyhow::{anyhow, Context, Result};
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    Extension, Json,
+};
+use chrono::{DateTime, FixedOffset, Local, Utc};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::TcpStream,
+};
+
+use crate::{
+    auth::{legacy_owner, CurrentUser},
+    epg::{Epg, Programme},
+    playlist::{Playlist, PlaylistEntry},
+    routes::{SearchFilters, SearchQuery},
+    store::{load_json, save_json},
+    AppState,
+};
+
+#[derive(Debug, Clone)]
+pub struct AlertConfig {
+    /// SMTP relay for email alerts; email is disabled when unset.
+    pub smtp_host: Option<String>,
+    pub smtp_port: u16,
+    pub smtp_from: String,
+}
+
+impl AlertConfig {
+    pub fn from_env() -> Self {
+        Self {
+            smtp_host: std::env::var("SMTP_HOST")
+                .ok()
+                .filter(|host| !host.is_empty()),
+            smtp_port: std::env::var("SMTP_PORT")
+                .ok()
+                .and_then(|value| value.parse().ok())
+                .unwrap_or(25),
+            smtp_from: std::env::var("SMTP_FROM")
+                .unwrap_or_else(|_| "sparrow-tv@localhost".to_string()),
+        }
+    }
+}
+
+/// Where to send an alert about new matches.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum AlertTarget {
+    /// POSTs the matches as JSON.
+    Webhook { url: String },
+    /// POSTs a plain text summary to an ntfy topic URL.
+    Ntfy { url: String, token: Option<String> },
+    /// Mails a summary through the configured SMTP relay.
+    Email { to: String },
+}
+
+/// A search kept around to alert on programmes that start matching as the EPG refreshes.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SavedSearch {
+    pub id: u64,
+    #[serde(default = "legacy_owner")]
+    pub user_id: u64,
+    pub name: String,
+    pub query: SearchQuery,
+    #[serde(default)]
+    pub targets: Vec<AlertTarget>,
+    pub created_at: DateTime<Utc>,
+    /// Programmes already alerted on, keyed by [`match_key`] with their stop time so
+    /// they can be forgotten once they have ended.
+    #[serde(default)]
+    pub notified: BTreeMap<String, DateTime<Utc>>,
+}
+
+/// Saved searches persisted as JSON in the data directory.
+#[derive(Debug, Clone)]
+pub struct SavedSearches {
+    path: PathBuf,
+    searches: Arc<RwLock<Vec<SavedSearch>>>,
+}
+
+impl SavedSearches {
+    pub fn load(data_dir: &FsPath) -> Self {
+        let path = data_dir.join("saved_searches.json");
+        Self {
+            searches: Arc::new(RwLock::new(load_json(&path))),
+            path,
+        }
+    }
+
+    pub fn list(&self) -> Vec<SavedSearch> {
+        self.searches.read().unwrap().clone()
+    }
+
+    fn insert(&self, mut search: SavedSearch) -> SavedSearch {
+        let mut searches = self.searches.write().unwrap();
+        search.id = searches.iter().map(|s| s.id).max().unwrap_or(0) + 1;
+        searches.push(search.clone());
+        drop(searches);
+        self.save();
+        search
+    }
+
+    fn update(&self, id: u64, update: impl FnOnce(&mut SavedSearch)) {
+        if let Some(search) = self
+            .searches
+            .write()
+            .unwrap()
+            .iter_mut()
+            .find(|s| s.id == id)
+        {
+            update(search);
+        }
+        self.save();
+    }
+
+    fn remove(&self, id: u64) -> bool {
+        let mut searches = self.searches.write().unwrap();
+        let count = searches.len();
+        searches.retain(|s| s.id != id);
+        let removed = searches.len() != count;
+        drop(searches);
+        self.save();
+        removed
+    }
+
+    fn save(&self) {
+        save_json(&self.path, &*self.searches.read().unwrap());
+    }
+}
+
+fn match_key(programme: &Programme) -> String {
+    format!("{}@{}", programme.channel, programme.start.timestamp())
+}
+
+/// A matching programme as sent to alert targets.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AlertMatch {
+    channel_id: String,
+    channel_name: String,
+    title: String,
+    desc: String,
+    start: DateTime<FixedOffset>,
+    stop: DateTime<FixedOffset>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct AlertPayload<'a> {
+    search_id: u64,
+    search_name: &'a str,
+    query: &'a str,
+    matches: &'a [AlertMatch],
+}
+
+/// Playlist group titles by channel id, built once to check many programmes against.
+pub struct ChannelGroups<'a> {
+    visible: HashMap<&'a str, &'a str>,
+    all: HashMap<&'a str, &'a str>,
+}
+
+impl<'a> ChannelGroups<'a> {
+    pub fn new(playlist: &'a Playlist) -> Self {
+        let groups = |entries: &'a [PlaylistEntry]| {
+            let mut groups = HashMap::new();
+            for entry in entries {
+                groups
+                    .entry(entry.tvg_id.as_str())
+                    .or_insert(entry.group_title.as_str());
+            }
+            groups
+        };
+        Self {
+            visible: groups(&playlist.filtered_entries),
+            all: groups(&playlist.entries),
+        }
+    }
+}
+
+/// Upcoming or airing programmes matching `search`, including ones already alerted on.
+pub fn find_matches<'a>(
+    epg: &'a Epg,
+    groups: &ChannelGroups,
+    search: &SavedSearch,
+    now: DateTime<Utc>,
+) -> Vec<&'a Programme> {
+    let filters = match SearchFilters::from_query(&search.query, Local::now()) {
+        Ok(filters) => filters,
+        Err((_, error)) => {
+            tracing::warn!(
+                id = search.id,
+                error,
+                "Skipping saved search with invalid filters"
+            );
+            return Vec::new();
+        }
+    };
+    let include_hidden = search.query.include_hidden == Some(true);
+    let groups = if include_hidden {
+        &groups.all
+    } else {
+        &groups.visible
+    };
+
+    epg.search(&search.query.search_query, now, now)
+        .into_iter()
+        .filter(|p| {
+            let group = groups.get(p.channel.as_str()).copied();
+            (include_hidden || group.is_some()) && filters.matches(p, group)
+        })
+        .collect()
+}
+
+/// Alerts every saved search's targets about matches not seen on earlier refreshes.
+/// Runs on a blocking thread after each EPG refresh, since broad searches over a large
+/// EPG take a while.
+pub fn evaluate_saved_searches(app_state: &AppState, epg: &Epg) {
+    let searches = app_state.saved_searches.list();
+    if searches.is_empty() {
+        return;
+    }
+    let Some(playlist) = app_state.cached_playlist_snapshot() else {
+        tracing::warn!("No cached playlist yet, skipping saved searches");
+        return;
+    };
+
+    let now = Utc::now();
+    let groups = ChannelGroups::new(&playlist);
+    let channel_names: HashMap<&str, &str> = epg
+        .channels
+        .iter()
+        .map(|c| (c.id.as_str(), c.display_name.as_str()))
+        .collect();
+    for search in searches {
+        let new_matches: Vec<&Programme> = find_matches(epg, &groups, &search, now)
+            .into_iter()
+            .filter(|p| !search.notified.contains_key(&match_key(p)))
+            .collect();
+        if search.notified.values().any(|stop| *stop <= now) {
+            app_state.saved_searches.update(search.id, |search| {
+                search.notified.retain(|_, stop| *stop > now);
+            });
+        }
+        if new_matches.is_empty() {
+            continue;
+        }
+
+        let notified: Vec<(String, DateTime<Utc>)> = new_matches
+            .iter()
+            .map(|p| (match_key(p), p.stop.to_utc()))
+            .collect();
+        let matches: Vec<AlertMatch> = new_matches
+            .into_iter()
+            .map(|p| AlertMatch {
+                channel_id: p.channel.clone(),
+                channel_name: channel_names
+                    .get(p.channel.as_str())
+                    .map_or_else(|| p.channel.clone(), |name| name.to_string()),
+                title: p.title.clone(),
+                desc: p.desc.clone(),
+                start: p.start,
+                stop: p.stop,
+            })
+            .collect();
+
+        let app_state = app_state.clone();
+        tokio::spawn(async move {
+            let mut delivered = true;
+            for target in &search.targets {
+                if let Err(error) = send_alert(&app_state, target, &search, &matches).await {
+                    tracing::warn!(?error, id = search.id, "Failed to send saved search alert");
+                    delivered = false;
+                }
+            }
+            // Matches any target missed are retried on the next refresh, which may alert
+            // the targets that did get them a second time.
+            if delivered {
+                tracing::info!(
+                    id = search.id,
+                    matches = matches.len(),
+                    "Alerted on new saved search matches"
+                );
+                app_state.saved_searches.update(search.id, |search| {
+                    search.notified.extend(notified);
+                });
+            }
+        });
+    }
+}
+
+async fn send_alert(
+    app_state: &AppState,
+    target: &AlertTarget,
+    search: &SavedSearch,
+    matches: &[AlertMatch],
+) -> Result<()> {
+    let subject = format!("New matches for \"{}\"", search.name);
+    match target {
+        AlertTarget::Webhook { url } => {
+            let payload = serde_json::to_vec(&AlertPayload {
+                search_id: search.id,
+                search_name: &search.name,
+                query: &search.query.search_query,
+                matches,
+            })?;
+            app_state
+                .client
+                .post(url)
+                .header("Content-Type", "application/json")
+                .body(payload)
+                .send()
+                .await?
+                .error_for_status()?;
+        }
+        AlertTarget::Ntfy { url, token } => {
+            let mut request = app_state
+                .client
+                .post(url)
+                .header("Title", subject)
+                .header("Tags", "tv")
+                .body(summary(matches));
+            if let Some(token) = token {
+                request = request.bearer_auth(token);
+            }
+            request.send().await?.error_for_status()?;
+        }
+        AlertTarget::Email { to } => {
+            send_email(&app_state.alert_config, to, &subject, &summary(matches)).await?;
+        }
+    }
+    Ok(())
+}
+
+fn summary(matches: &[AlertMatch]) -> String {
+    matches
+        .iter()
+        .map(|m| {
+            format!(
+                "{} - {} {}",
+                m.title,
+                m.channel_name,
+                m.start.with_timezone(&Local).format("%a %d %b %H:%M")
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Sends a plain text mail through an SMTP relay that accepts unauthenticated mail, such
+/// as a local Postfix.
+async fn send_email(config: &AlertConfig, to: &str, subject: &str, body: &str) -> Result<()> {
+    let host = config
+        .smtp_host
+        .as_deref()
+        .ok_or_else(|| anyhow!("SMTP_HOST is not set"))?;
+    let stream = TcpStream::connect((host, config.smtp_port))
+        .await
+        .with_context(|| format!("failed to connect to SMTP relay {host}"))?;
+    let (reader, mut writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+
+    expect_reply(&mut reader, 220).await?;
+    for (command, code) in [
+        ("EHLO sparrow-tv".to_string(), 250),
+        (format!("MAIL FROM:<{}>", config.smtp_from), 250),
+        (format!("RCPT TO:<{to}>"), 250),
+        ("DATA".to_string(), 354),
+    ] {
+        writer
+            .write_all(format!("{command}\r\n").as_bytes())
+            .await?;
+        expect_reply(&mut reader, code).await?;
+    }
+    writer
+        .write_all(email_message(&config.smtp_from, to, subject, body, Utc::now()).as_bytes())
+        .await?;
+    expect_reply(&mut reader, 250).await?;
+    writer.write_all(b"QUIT\r\n").await?;
+    Ok(())
+}
+
+/// Reads a possibly multi-line SMTP reply and checks its status code.
+async fn expect_reply(reader: &mut (impl AsyncBufReadExt + Unpin), expected: u16) -> Result<()> {
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line).await? == 0 {
+            return Err(anyhow!("SMTP relay closed the connection"));
+        }
+        let code: u16 = line
+            .get(..3)
+            .and_then(|code| code.parse().ok())
+            .ok_or_else(|| anyhow!("malformed SMTP reply: {}", line.trim_end()))?;
+        // `250-` continues a multi-line reply, `250 ` ends it.
+        if line.as_bytes().get(3) == Some(&b'-') {
+            continue;
+        }
+        if code != expected {
+            return Err(anyhow!("SMTP relay replied: {}", line.trim_end()));
+        }
+        return Ok(());
+    }
+}
+
+/// The DATA section of a mail, including the terminating dot line.
+fn email_message(from: &str, to: &str, subject: &str, body: &str, date: DateTime<Utc>) -> String {
+    // Line breaks in a header would end it and let the rest be read as more headers or body.
+    let subject = encode_header(&subject.replace(['\r', '\n'], " "));
+    let mut message = format!(
+        "From: {from}\r\nTo: {to}\r\nSubject: {subject}\r\nDate: {}\r\nContent-Type: text/plain; charset=utf-8\r\n\r\n",
+        date.to_rfc2822()
+    );
+    for line in body.lines() {
+        // Dot-stuffing, so a line with a lone dot doesn't end the message early.
+        if line.starts_with('.') {
+            message.push('.');
+        }
+        message.push_str(line);
+        message.push_str("\r\n");
+    }
+    message.push_str(".\r\n");
+    message
+}
+
+/// Headers are ASCII only, anything else goes in RFC 2047 encoded words. Each word is
+/// kept within the 75 character limit and folded onto its own line.
+fn encode_header(value: &str) -> String {
+    if value.is_ascii() {
+        return value.to_string();
+    }
+
+    let mut words = Vec::new();
+    let mut rest = value;
+    while !rest.is_empty() {
+        let mut end = rest.len().min(45);
+        while !rest.is_char_boundary(end) {
+            end -= 1;
+        }
+        let (word, tail) = rest.split_at(end);
+        words.push(format!("=?utf-8?B?{}?=", base64(word.as_bytes())));
+        rest = tail;
+    }
+    words.join("\r\n ")
+}
+
+fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let triple = chunk.iter().enumerate().fold(0u32, |triple, (i, &byte)| {
+            triple | ((byte as u32) << (16 - 8 * i))
+        });
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(ALPHABET[((triple >> (18 - 6 * i)) & 0x3f) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CreateSavedSearchRequest {
+    name: Option<String>,
+    query: SearchQuery,
+    #[serde(default)]
+    targets: Vec<AlertTarget>,
+}
+
+pub async fn list_saved_searches(
+    State(app_state): State<AppState>,
+    Extension(CurrentUser(user)): Extension<CurrentUser>,
+) -> Json<Vec<SavedSearch>> {
+    let mut searches = app_state.saved_searches.list();
+    searches.retain(|s| user.can_manage(s.user_id));
+    Json(searches)
+}
+
+/// Saves a search. Programmes matching it right now are treated as already seen, so only
+/// matches appearing on later refreshes trigger alerts.
+pub async fn create_saved_search(
+    State(app_state): State<AppState>,
+    Extension(CurrentUser(user)): Extension<CurrentUser>,
+    Json(request): Json<CreateSavedSearchRequest>,
+) -> Result<(StatusCode, Json<SavedSearch>), (StatusCode, String)> {
+    SearchFilters::from_query(&request.query, Local::now())
+        .map_err(|(status, error)| (status, error.to_string()))?;
+    for target in &request.targets {
+        match target {
+            AlertTarget::Webhook { url } | AlertTarget::Ntfy { url, .. }
+                if reqwest::Url::parse(url).is_err() =>
+            {
+                return Err((StatusCode::BAD_REQUEST, format!("Invalid alert URL: {url}")));
+            }
+            AlertTarget::Email { to }
+                if !to.contains('@') || to.contains(['\r', '\n', '<', '>']) =>
+            {
+                return Err((
+                    StatusCode::BAD_REQUEST,
+                    format!("Invalid email address: {to}"),
+                ));
+            }
+            AlertTarget::Email { .. } if app_state.alert_config.smtp_host.is_none() => {
+                return Err((
+                    StatusCode::BAD_REQUEST,
+                    "Email alerts need SMTP_HOST to be set".to_string(),
+                ));
+            }
+            _ => {}
+        }
+    }
+
+    let name = request
+        .name
+        .unwrap_or_default()
+        .replace(['\r', '\n'], " ")
+        .trim()
+        .to_string();
+    let name = if name.is_empty() {
+        request.query.search_query.replace(['\r', '\n'], " ")
+    } else {
+        name
+    };
+    let mut search = SavedSearch {
+        id: 0,
+        user_id: user.id,
+        name,
+        query: request.query,
+        targets: request.targets,
+        created_at: Utc::now(),
+        notified: BTreeMap::new(),
+    };
+    if let (Some(epg), Some(playlist)) = (
+        app_state.cached_epg_snapshot(),
+        app_state.cached_playlist_snapshot(),
+    ) {
+        search.notified = find_matches(&epg, &ChannelGroups::new(&playlist), &search, Utc::now())
+            .into_iter()
+            .map(|p| (match_key(p), p.stop.to_utc()))
+            .collect();
+    }
+
+    let search = app_state.saved_searches.insert(search);
+    tracing::info!(id = search.id, name = %search.name, "Added saved search");
+    Ok((StatusCode::CREATED, Json(search)))
+}
+
+pub async fn delete_saved_search(
+    Path(id): Path<u64>,
+    State(app_state): State<AppState>,
+    Extension(CurrentUser(user)): Extension<CurrentUser>,
+) -> Result<StatusCode, (StatusCode, &'static str)> {
+    let owned = app_state
+        .saved_searches
+        .list()
+        .iter()
+        .any(|s| s.id == id && user.can_manage(s.user_id));
+    if !owned || !app_state.saved_searches.remove(id) {
+        return Err((StatusCode::NOT_FOUND, "Saved search not found"));
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_email_message_is_dot_stuffed() {
+        let date = DateTime::parse_from_rfc3339("2024-10-17T12:00:00Z")
+            .unwrap()
+            .to_utc();
+        let message = email_message(
+            "tv@localhost",
+            "me@example.com",
+            "New matches",
+            "Rapport - SVT1\n.hidden\nSportnytt - SVT2",
+            date,
+        );
+
+        assert!(message.starts_with("From: tv@localhost\r\nTo: me@example.com\r\n"));
+        assert!(message.contains("Date: Thu, 17 Oct 2024 12:00:00 +0000\r\n"));
+        assert!(
+            message.ends_with("\r\n\r\nRapport - SVT1\r\n..hidden\r\nSportnytt - SVT2\r\n.\r\n")
+        );
+
+        let message = email_message("a@b", "c@d", "x\r\n.\r\nRSET", "", date);
+        assert!(message.contains("Subject: x  .  RSET\r\nDate:"));
+
+        let message = email_message("a@b", "c@d", "Nya träffar: Så ska det låta", "", date);
+        assert!(message.contains(
+            "Subject: =?utf-8?B?TnlhIHRyw6RmZmFyOiBTw6Ugc2thIGRldCBsw6V0YQ==?=\r\nDate:"
+        ));
+
+        let message = email_message("a@b", "c@d", &"ö".repeat(30), "", date);
+        let subject = message
+            .split("Subject: ")
+            .nth(1)
+            .unwrap()
+            .split("\r\nDate:")
+            .next()
+            .unwrap();
+        let words: Vec<&str> = subject.split("\r\n ").collect();
+        assert_eq!(words.len(), 2);
+        assert!(words
+            .iter()
+            .all(|word| word.starts_with("=?utf-8?B?") && word.len() <= 75));
+    }
+
+    #[tokio::test]
+    async fn test_expect_reply_reads_multiline_replies() {
+        let mut reply =
+            BufReader::new(&b"250-relay.local\r\n250-SIZE 1000\r\n250 OK\r\n354 Go\r\n"[..]);
+        expect_reply(&mut reply, 250).await.unwrap();
+        assert!(expect_reply(&mut reply, 250).await.is_err());
+    }
+}
@@ -0,0 +1,626 @@
+use std::{
+    collections::HashMap,
+    path::{Path as FsPath, PathBuf},
+    sync::{Arc, OnceLock, RwLock},
+};
+
+use argon2::{
+    password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
+use axum::{
+    extract::{FromRef, Path, Query, Request, State},
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Extension, Json,
+};
+use chrono::{DateTime, Duration, Utc};
+use rand_core::{OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    store::{load_json, save_json},
+    AppState,
+};
+
+const SESSION_COOKIE: &str = "sparrow_session";
+const SESSION_DAYS: i64 = 30;
+const MIN_PASSWORD_LENGTH: usize = 8;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct User {
+    pub id: u64,
+    pub username: String,
+    /// Argon2 PHC string.
+    password_hash: String,
+    /// Secret for URLs that players fetch without a session, like `/?token=`.
+    token: String,
+    pub admin: bool,
+    pub created_at: DateTime<Utc>,
+}
+
+impl User {
+    pub fn token(&self) -> &str {
+        &self.token
+    }
+
+    /// Whether this user may see and change something owned by `owner_id`. Admins can
+    /// manage everyone's.
+    pub fn can_manage(&self, owner_id: u64) -> bool {
+        self.admin || self.id == owner_id
+    }
+}
+
+/// Owner of anything saved before there were user accounts: the admin created from
+/// `PASSWORD` on first start.
+pub fn legacy_owner() -> u64 {
+    1
+}
+
+/// The user a request was authenticated as, set by [`require_auth`].
+#[derive(Debug, Clone)]
+pub struct CurrentUser(pub User);
+
+#[derive(Debug, Clone)]
+struct Session {
+    user_id: u64,
+    expires: DateTime<Utc>,
+}
+
+/// Accounts persisted as JSON in the data directory, and the login sessions for them,
+/// which only live in memory.
+#[derive(Debug, Clone)]
+pub struct Users {
+    path: PathBuf,
+    users: Arc<RwLock<Vec<User>>>,
+    sessions: Arc<RwLock<HashMap<String, Session>>>,
+}
+
+impl Users {
+    /// Loads the accounts, creating an admin from `PASSWORD` on first start so existing
+    /// setups keep a way in.
+    pub fn load(data_dir: &FsPath) -> Self {
+        let path = data_dir.join("users.json");
+        let users = Self {
+            users: Arc::new(RwLock::new(load_json(&path))),
+            sessions: Arc::new(RwLock::new(HashMap::new())),
+            path,
+        };
+
+        if users.users.read().unwrap().is_empty() {
+            match std::env::var("PASSWORD") {
+                Ok(password) if !password.is_empty() => {
+                    let username =
+                        std::env::var("ADMIN_USERNAME").unwrap_or_else(|_| "admin".to_string());
+                    match users.create(&username, &password, true) {
+                        Ok(user) => tracing::info!(
+                            username = %user.username,
+                            "Created admin user from PASSWORD, sign in to get playlist URLs"
+                        ),
+                        Err(error) => tracing::error!(?error, "Failed to create admin user"),
+                    }
+                }
+                _ => tracing::warn!("No users exist and PASSWORD is not set, nobody can sign in"),
+            }
+        }
+        users
+    }
+
+    fn list(&self) -> Vec<User> {
+        self.users.read().unwrap().clone()
+    }
+
+    fn get(&self, id: u64) -> Option<User> {
+        self.users
+            .read()
+            .unwrap()
+            .iter()
+            .find(|u| u.id == id)
+            .cloned()
+    }
+
+    fn find_by_username(&self, username: &str) -> Option<User> {
+        self.users
+            .read()
+            .unwrap()
+            .iter()
+            .find(|u| u.username.eq_ignore_ascii_case(username))
+            .cloned()
+    }
+
+    fn find_by_token(&self, token: &str) -> Option<User> {
+        self.users
+            .read()
+            .unwrap()
+            .iter()
+            .find(|u| constant_time_eq(u.token.as_bytes(), token.as_bytes()))
+            .cloned()
+    }
+
+    fn create(&self, username: &str, password: &str, admin: bool) -> anyhow::Result<User> {
+        let password_hash = hash_password(password)?;
+        let mut users = self.users.write().unwrap();
+        if users
+            .iter()
+            .any(|u| u.username.eq_ignore_ascii_case(username))
+        {
+            anyhow::bail!("username is taken");
+        }
+        let user = User {
+            id: users.iter().map(|u| u.id).max().unwrap_or(0) + 1,
+            username: username.to_string(),
+            password_hash,
+            token: random_token(),
+            admin,
+            created_at: Utc::now(),
+        };
+        users.push(user.clone());
+        drop(users);
+        self.save();
+        Ok(user)
+    }
+
+    fn update(&self, id: u64, update: impl FnOnce(&mut User)) -> Option<User> {
+        let updated = self
+            .users
+            .write()
+            .unwrap()
+            .iter_mut()
+            .find(|u| u.id == id)
+            .map(|user| {
+                update(user);
+                user.clone()
+            });
+        self.save();
+        updated
+    }
+
+    fn remove(&self, id: u64) -> bool {
+        let mut users = self.users.write().unwrap();
+        let count = users.len();
+        users.retain(|u| u.id != id);
+        let removed = users.len() != count;
+        drop(users);
+        self.sessions
+            .write()
+            .unwrap()
+            .retain(|_, session| session.user_id != id);
+        self.save();
+        removed
+    }
+
+    fn save(&self) {
+        save_json(&self.path, &*self.users.read().unwrap());
+    }
+
+    fn start_session(&self, user_id: u64) -> String {
+        let id = random_token();
+        let now = Utc::now();
+        let mut sessions = self.sessions.write().unwrap();
+        sessions.retain(|_, session| session.expires > now);
+        sessions.insert(
+            id.clone(),
+            Session {
+                user_id,
+                expires: now + Duration::days(SESSION_DAYS),
+            },
+        );
+        id
+    }
+
+    fn session_user(&self, session_id: &str) -> Option<User> {
+        let session = self.sessions.read().unwrap().get(session_id).cloned()?;
+        if session.expires <= Utc::now() {
+            self.end_session(session_id);
+            return None;
+        }
+        self.get(session.user_id)
+    }
+
+    fn end_session(&self, session_id: &str) {
+        self.sessions.write().unwrap().remove(session_id);
+    }
+}
+
+impl FromRef<AppState> for Users {
+    fn from_ref(app_state: &AppState) -> Self {
+        app_state.users.clone()
+    }
+}
+
+fn hash_password(password: &str) -> anyhow::Result<String> {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|error| anyhow::anyhow!("failed to hash password: {error}"))
+}
+
+fn verify_password(password: &str, password_hash: &str) -> bool {
+    PasswordHash::new(password_hash).is_ok_and(|hash| {
+        Argon2::default()
+            .verify_password(password.as_bytes(), &hash)
+            .is_ok()
+    })
+}
+
+/// Verifies a login off the async runtime, hashing against a dummy when the user doesn't
+/// exist so response times don't reveal which usernames do.
+async fn verify_login(user: Option<User>, password: String) -> Option<User> {
+    static DUMMY_HASH: OnceLock<String> = OnceLock::new();
+    tokio::task::spawn_blocking(move || {
+        let password_hash = match &user {
+            Some(user) => user.password_hash.clone(),
+            None => DUMMY_HASH
+                .get_or_init(|| hash_password("not a real password").unwrap_or_default())
+                .clone(),
+        };
+        let valid = verify_password(&password, &password_hash);
+        user.filter(|_| valid)
+    })
+    .await
+    .ok()
+    .flatten()
+}
+
+/// 32 random bytes as hex.
+pub fn random_token() -> String {
+    let mut bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut bytes);
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+fn session_cookie(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .filter_map(|cookie| cookie.trim().split_once('='))
+        .find(|(name, _)| *name == SESSION_COOKIE)
+        .map(|(_, value)| value)
+}
+
+fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenQuery {
+    token: Option<String>,
+}
+
+/// Rejects requests without a valid `?token=`, bearer token or session cookie, and makes
+/// the user available to handlers as an `Extension<CurrentUser>`.
+pub async fn require_auth(
+    State(users): State<Users>,
+    mut request: Request,
+    next: Next,
+) -> Result<Response, (StatusCode, &'static str)> {
+    let query_token = Query::<TokenQuery>::try_from_uri(request.uri())
+        .ok()
+        .and_then(|Query(query)| query.token);
+    let headers = request.headers();
+    let user = query_token
+        .as_deref()
+        .or_else(|| bearer_token(headers))
+        .and_then(|token| users.find_by_token(token))
+        .or_else(|| session_cookie(headers).and_then(|session| users.session_user(session)))
+        .ok_or((StatusCode::UNAUTHORIZED, "Unauthorized"))?;
+
+    request.extensions_mut().insert(CurrentUser(user));
+    Ok(next.run(request).await)
+}
+
+/// A user as shown to clients. The token is only included for the user themselves.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UserInfo {
+    id: u64,
+    username: String,
+    admin: bool,
+    created_at: DateTime<Utc>,
+    token: Option<String>,
+}
+
+impl UserInfo {
+    fn public(user: &User) -> Self {
+        Self {
+            id: user.id,
+            username: user.username.clone(),
+            admin: user.admin,
+            created_at: user.created_at,
+            token: None,
+        }
+    }
+
+    fn own(user: &User) -> Self {
+        Self {
+            token: Some(user.token.clone()),
+            ..Self::public(user)
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LoginRequest {
+    username: String,
+    password: String,
+}
+
+fn set_cookie(headers: &HeaderMap, value: &str, max_age: i64) -> HeaderValue {
+    let secure = headers
+        .get("x-forwarded-proto")
+        .is_some_and(|proto| proto == "https");
+    HeaderValue::from_str(&format!(
+        "{SESSION_COOKIE}={value}; Path=/; HttpOnly; SameSite=Lax; Max-Age={max_age}{}",
+        if secure { "; Secure" } else { "" }
+    ))
+    .unwrap()
+}
+
+pub async fn login(
+    State(app_state): State<AppState>,
+    headers: HeaderMap,
+    Json(request): Json<LoginRequest>,
+) -> Result<Response, (StatusCode, &'static str)> {
+    let user = app_state.users.find_by_username(request.username.trim());
+    let user = verify_login(user, request.password)
+        .await
+        .ok_or((StatusCode::UNAUTHORIZED, "Invalid username or password"))?;
+
+    let session = app_state.users.start_session(user.id);
+    tracing::info!(username = %user.username, "User signed in");
+    let mut response = Json(UserInfo::own(&user)).into_response();
+    response.headers_mut().insert(
+        header::SET_COOKIE,
+        set_cookie(&headers, &session, SESSION_DAYS * 24 * 60 * 60),
+    );
+    Ok(response)
+}
+
+pub async fn logout(State(app_state): State<AppState>, headers: HeaderMap) -> Response {
+    if let Some(session) = session_cookie(&headers) {
+        app_state.users.end_session(session);
+    }
+    let mut response = StatusCode::NO_CONTENT.into_response();
+    response
+        .headers_mut()
+        .insert(header::SET_COOKIE, set_cookie(&headers, "", 0));
+    response
+}
+
+pub async fn me(Extension(CurrentUser(user)): Extension<CurrentUser>) -> Json<UserInfo> {
+    Json(UserInfo::own(&user))
+}
+
+/// Issues a new token, invalidating playlist and EPG URLs that used the old one.
+pub async fn regenerate_token(
+    State(app_state): State<AppState>,
+    Extension(CurrentUser(user)): Extension<CurrentUser>,
+) -> Result<Json<UserInfo>, (StatusCode, &'static str)> {
+    let user = app_state
+        .users
+        .update(user.id, |user| user.token = random_token())
+        .ok_or((StatusCode::NOT_FOUND, "User not found"))?;
+    Ok(Json(UserInfo::own(&user)))
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ChangePasswordRequest {
+    current_password: String,
+    new_password: String,
+}
+
+pub async fn change_password(
+    State(app_state): State<AppState>,
+    Extension(CurrentUser(user)): Extension<CurrentUser>,
+    Json(request): Json<ChangePasswordRequest>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    if request.new_password.len() < MIN_PASSWORD_LENGTH {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("Password must be at least {MIN_PASSWORD_LENGTH} characters"),
+        ));
+    }
+    if verify_login(Some(user.clone()), request.current_password)
+        .await
+        .is_none()
+    {
+        return Err((
+            StatusCode::UNAUTHORIZED,
+            "Current password is wrong".to_string(),
+        ));
+    }
+
+    let password_hash = tokio::task::spawn_blocking(move || hash_password(&request.new_password))
+        .await
+        .map_err(|error| (StatusCode::INTERNAL_SERVER_ERROR, error.to_string()))?
+        .map_err(|error| (StatusCode::INTERNAL_SERVER_ERROR, error.to_string()))?;
+    app_state
+        .users
+        .update(user.id, |user| user.password_hash = password_hash);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub fn require_admin(user: &User) -> Result<(), (StatusCode, String)> {
+    if user.admin {
+        Ok(())
+    } else {
+        Err((StatusCode::FORBIDDEN, "Admins only".to_string()))
+    }
+}
+
+pub async fn list_users(
+    State(app_state): State<AppState>,
+    Extension(CurrentUser(user)): Extension<CurrentUser>,
+) -> Result<Json<Vec<UserInfo>>, (StatusCode, String)> {
+    require_admin(&user)?;
+    Ok(Json(
+        app_state
+            .users
+            .list()
+            .iter()
+            .map(UserInfo::public)
+            .collect(),
+    ))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreateUserRequest {
+    username: String,
+    password: String,
+    #[serde(default)]
+    admin: bool,
+}
+
+pub async fn create_user(
+    State(app_state): State<AppState>,
+    Extension(CurrentUser(user)): Extension<CurrentUser>,
+    Json(request): Json<CreateUserRequest>,
+) -> Result<(StatusCode, Json<UserInfo>), (StatusCode, String)> {
+    require_admin(&user)?;
+    let username = request.username.trim().to_string();
+    if username.is_empty() || username.contains(char::is_whitespace) {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "Username must be a single word".to_string(),
+        ));
+    }
+    if request.password.len() < MIN_PASSWORD_LENGTH {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("Password must be at least {MIN_PASSWORD_LENGTH} characters"),
+        ));
+    }
+
+    let users = app_state.users.clone();
+    let created = tokio::task::spawn_blocking(move || {
+        users.create(&username, &request.password, request.admin)
+    })
+    .await
+    .map_err(|error| (StatusCode::INTERNAL_SERVER_ERROR, error.to_string()))?
+    .map_err(|error| (StatusCode::CONFLICT, error.to_string()))?;
+    tracing::info!(username = %created.username, by = %user.username, "Created user");
+    Ok((StatusCode::CREATED, Json(UserInfo::own(&created))))
+}
+
+pub async fn delete_user(
+    Path(id): Path<u64>,
+    State(app_state): State<AppState>,
+    Extension(CurrentUser(user)): Extension<CurrentUser>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    require_admin(&user)?;
+    if id == user.id {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "You can't delete yourself".to_string(),
+        ));
+    }
+    if !app_state.users.remove(id) {
+        return Err((StatusCode::NOT_FOUND, "User not found".to_string()));
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{body::Body, middleware, routing::get, Router};
+    use tower::ServiceExt;
+
+    use super::*;
+
+    #[test]
+    fn test_password_hashes_verify() {
+        let hash = hash_password("correct horse").unwrap();
+        assert!(hash.starts_with("$argon2"));
+        assert!(verify_password("correct horse", &hash));
+        assert!(!verify_password("wrong horse", &hash));
+        assert!(!verify_password("correct horse", "not a hash"));
+    }
+
+    #[test]
+    fn test_session_cookie_is_found_among_others() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::COOKIE,
+            HeaderValue::from_static("theme=dark; sparrow_session=abc123; other=1"),
+        );
+        assert_eq!(session_cookie(&headers), Some("abc123"));
+        assert_eq!(bearer_token(&headers), None);
+
+        assert_eq!(random_token().len(), 64);
+        assert_ne!(random_token(), random_token());
+    }
+
+    #[tokio::test]
+    async fn test_require_auth_accepts_token_bearer_and_session_cookie() {
+        let dir = std::env::temp_dir().join(format!("auth-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let users = Users::load(&dir);
+        let user = users.create("alice", "correct horse", false).unwrap();
+        let session = users.start_session(user.id);
+        let app =
+            Router::new()
+                .route(
+                    "/api/me",
+                    get(
+                        |Extension(CurrentUser(user)): Extension<CurrentUser>| async move {
+                            user.username
+                        },
+                    ),
+                )
+                .route_layer(middleware::from_fn_with_state(users, require_auth));
+        let status = |request: axum::http::request::Builder| {
+            let app = app.clone();
+            async move {
+                let response = app
+                    .oneshot(request.body(Body::empty()).unwrap())
+                    .await
+                    .unwrap();
+                response.status()
+            }
+        };
+        let get = |uri: &str| Request::get(uri.to_string());
+
+        assert_eq!(
+            status(get(&format!("/api/me?token={}", user.token))).await,
+            StatusCode::OK
+        );
+        assert_eq!(
+            status(get("/api/me").header(header::AUTHORIZATION, format!("Bearer {}", user.token)))
+                .await,
+            StatusCode::OK
+        );
+        assert_eq!(
+            status(get("/api/me").header(header::COOKIE, format!("sparrow_session={session}")))
+                .await,
+            StatusCode::OK
+        );
+
+        assert_eq!(status(get("/api/me")).await, StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            status(get("/api/me?token=wrong")).await,
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            status(get("/api/me").header(header::AUTHORIZATION, "Bearer wrong")).await,
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            status(get("/api/me").header(header::COOKIE, "sparrow_session=wrong")).await,
+            StatusCode::UNAUTHORIZED
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
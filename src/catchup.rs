@@ -0,0 +1,323 @@
+use axum::{
+    body::Body,
+    extract::{Path, State},
+    http::{HeaderMap, Response, StatusCode},
+};
+use chrono::{DateTime, Datelike, Duration, Timelike, Utc};
+
+use crate::{
+    epg::Programme,
+    playlist::{Catchup, PlaylistEntry},
+    proxy, AppState,
+};
+
+impl Catchup {
+    /// Whether the archive still holds `programme`, which must have started already.
+    pub fn is_available(&self, programme: &Programme, now: DateTime<Utc>) -> bool {
+        programme.start <= now
+            && self
+                .days
+                .is_none_or(|days| programme.start >= now - Duration::days(days.into()))
+    }
+}
+
+/// How far back the archive of any of `entries` reaches, if any has catchup.
+pub fn max_catchup_days(entries: &[PlaylistEntry]) -> Option<u32> {
+    entries
+        .iter()
+        .filter_map(|entry| entry.catchup.as_ref())
+        .map(|catchup| catchup.days.unwrap_or(1))
+        .max()
+}
+
+/// The best entry for a channel that has catchup enabled.
+pub fn catchup_entry<'a>(
+    entries: &'a [PlaylistEntry],
+    tvg_id: &str,
+    priority: &[String],
+) -> Option<&'a PlaylistEntry> {
+    entries
+        .iter()
+        .filter(|entry| entry.catchup.is_some() && !tvg_id.is_empty() && entry.tvg_id == tvg_id)
+        .min_by_key(|entry| proxy::priority_rank(&entry.name, priority))
+}
+
+/// Link to play `programme` from the archive through [`catchup_stream`].
+pub fn catchup_path(programme: &Programme) -> String {
+    format!(
+        "/catchup/{}/{}",
+        programme.channel,
+        programme.start.timestamp()
+    )
+}
+
+/// Builds the upstream archive URL for `programme` on `entry`.
+pub fn catchup_url(
+    entry: &PlaylistEntry,
+    programme: &Programme,
+    now: DateTime<Utc>,
+) -> Option<String> {
+    let catchup = entry.catchup.as_ref()?;
+    let start = programme.start.to_utc();
+    let stop = programme.stop.to_utc();
+
+    match (catchup.mode.as_str(), &catchup.source) {
+        ("append", Some(source)) => Some(format!(
+            "{}{}",
+            entry.url,
+            fill_template(source, start, stop, now)
+        )),
+        ("shift", _) => {
+            let separator = if entry.url.contains('?') { '&' } else { '?' };
+            Some(format!(
+                "{}{separator}utc={}&lutc={}",
+                entry.url,
+                start.timestamp(),
+                now.timestamp()
+            ))
+        }
+        ("xc", _) | (_, None) => xtream_timeshift_url(&entry.url, programme),
+        (_, Some(source)) => Some(fill_template(source, start, stop, now)),
+    }
+}
+
+/// Xtream Codes serves archives from `timeshift.php`, addressed by the credentials and
+/// stream id of a live URL like `http://host/[live/]user/pass/id[.ts]`.
+fn xtream_timeshift_url(url: &str, programme: &Programme) -> Option<String> {
+    let (scheme, rest) = url.split_once("://")?;
+    let (host, path) = rest.split_once('/')?;
+    let segments: Vec<&str> = path
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .collect();
+    let [username, password, stream] = match segments.as_slice() {
+        ["live", username, password, stream] | [username, password, stream] => {
+            [*username, *password, *stream]
+        }
+        _ => return None,
+    };
+    let stream_id = stream.split('.').next().filter(|id| !id.is_empty())?;
+    let minutes = (programme.stop - programme.start).num_minutes().max(1);
+
+    Some(format!(
+        "{scheme}://{host}/streaming/timeshift.php?username={username}&password={password}&stream={stream_id}&start={}&duration={minutes}",
+        programme.start.format("%Y-%m-%d:%H-%M")
+    ))
+}
+
+/// Replaces `{name}` and `${name}` placeholders, leaving unknown ones untouched.
+fn fill_template(
+    template: &str,
+    start: DateTime<Utc>,
+    stop: DateTime<Utc>,
+    now: DateTime<Utc>,
+) -> String {
+    let mut filled = String::with_capacity(template.len());
+    let mut rest = template;
+
+    while let Some(open) = rest.find('{') {
+        let Some(close) = rest[open..].find('}').map(|close| open + close) else {
+            break;
+        };
+        let prefix = &rest[..open];
+        let (prefix, dollar) = match prefix.strip_suffix('$') {
+            Some(prefix) => (prefix, true),
+            None => (prefix, false),
+        };
+        filled.push_str(prefix);
+        match placeholder_value(&rest[open + 1..close], start, stop, now) {
+            Some(value) => filled.push_str(&value),
+            None => {
+                if dollar {
+                    filled.push('$');
+                }
+                filled.push_str(&rest[open..=close]);
+            }
+        }
+        rest = &rest[close + 1..];
+    }
+
+    filled.push_str(rest);
+    filled
+}
+
+fn placeholder_value(
+    token: &str,
+    start: DateTime<Utc>,
+    stop: DateTime<Utc>,
+    now: DateTime<Utc>,
+) -> Option<String> {
+    let (name, argument) = match token.split_once(':') {
+        Some((name, argument)) => (name, Some(argument)),
+        None => (token, None),
+    };
+    let duration = (stop - start).num_seconds();
+
+    let value = match name {
+        "utc" | "start" => start.timestamp(),
+        "utcend" | "end" => stop.timestamp(),
+        "lutc" | "now" | "timestamp" => now.timestamp(),
+        "offset" => (now - start).num_seconds(),
+        // `{duration:60}` is the duration divided by 60, i.e. in minutes.
+        "duration" => match argument {
+            Some(divisor) => duration / divisor.parse::<i64>().ok().filter(|&d| d > 0)?,
+            None => duration,
+        },
+        "Y" => start.year().into(),
+        "m" => return Some(format!("{:02}", start.month())),
+        "d" => return Some(format!("{:02}", start.day())),
+        "H" => return Some(format!("{:02}", start.hour())),
+        "M" => return Some(format!("{:02}", start.minute())),
+        "S" => return Some(format!("{:02}", start.second())),
+        _ => return None,
+    };
+    Some(value.to_string())
+}
+
+/// Streams a past programme from the provider's archive.
+pub async fn catchup_stream(
+    Path((channel, start)): Path<(String, i64)>,
+    State(app_state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Response<Body>, (StatusCode, String)> {
+    let epg = app_state.fetch_epg().await.map_err(|e| {
+        tracing::error!("Failed to fetch EPG: {:?}", e);
+        (
+            StatusCode::SERVICE_UNAVAILABLE,
+            "Failed to fetch EPG".to_string(),
+        )
+    })?;
+    let playlist = app_state.fetch_playlist().await.map_err(|e| {
+        tracing::error!("Failed to fetch playlist: {:?}", e);
+        (
+            StatusCode::SERVICE_UNAVAILABLE,
+            "Failed to fetch playlist".to_string(),
+        )
+    })?;
+
+    let programme = DateTime::from_timestamp(start, 0)
+        .and_then(|start| epg.find(&channel, start))
+        .ok_or((StatusCode::NOT_FOUND, "Programme not found".to_string()))?;
+    let entry = catchup_entry(
+        &playlist.entries,
+        &channel,
+        &app_state.proxy_config.failover_priority,
+    )
+    .ok_or((
+        StatusCode::NOT_FOUND,
+        "Channel has no catchup archive".to_string(),
+    ))?;
+
+    let now = Utc::now();
+    if !entry
+        .catchup
+        .as_ref()
+        .is_some_and(|catchup| catchup.is_available(programme, now))
+    {
+        return Err((
+            StatusCode::NOT_FOUND,
+            "Programme is not in the catchup archive".to_string(),
+        ));
+    }
+    let url = catchup_url(entry, programme, now).ok_or((
+        StatusCode::NOT_FOUND,
+        "Could not build a catchup URL for this channel".to_string(),
+    ))?;
+
+    tracing::debug!(%url, title = %programme.title, "Opening catchup stream");
+    let upstream = proxy::open_stream(&app_state, &url, proxy::forwarded_request_headers(&headers))
+        .await
+        .map_err(|error| {
+            (
+                StatusCode::BAD_GATEWAY,
+                format!("Failed to fetch catchup stream: {}", error),
+            )
+        })?;
+
+    proxy::build_response(upstream.status, &upstream.headers, upstream.body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{epg, playlist};
+
+    fn entry(url: &str, mode: &str, source: Option<&str>) -> PlaylistEntry {
+        PlaylistEntry {
+            catchup: Some(Catchup {
+                mode: mode.to_string(),
+                days: Some(7),
+                source: source.map(str::to_string),
+            }),
+            ..playlist::tests::entry("svt1.se", "SVT1 HD SE", url)
+        }
+    }
+
+    fn programme() -> Programme {
+        epg::tests::programme("Rapport", "2024-10-17T19:30:00+02:00")
+    }
+
+    #[test]
+    fn test_catchup_url_fills_templates() {
+        let now = DateTime::parse_from_rfc3339("2024-10-18T12:00:00Z")
+            .unwrap()
+            .to_utc();
+        let programme = programme();
+
+        let default = entry(
+            "http://abc.xyz/live/1",
+            "default",
+            Some("http://abc.xyz/archive/1/{Y}{m}{d}-{H}{M}.ts?start=${start}&dur={duration:60}&x={unknown}"),
+        );
+        assert_eq!(
+            catchup_url(&default, &programme, now).unwrap(),
+            "http://abc.xyz/archive/1/20241017-1730.ts?start=1729186200&dur=30&x={unknown}"
+        );
+
+        let append = entry(
+            "http://abc.xyz/live/1.m3u8",
+            "append",
+            Some("?utc={utc}&lutc={lutc}"),
+        );
+        assert_eq!(
+            catchup_url(&append, &programme, now).unwrap(),
+            "http://abc.xyz/live/1.m3u8?utc=1729186200&lutc=1729252800"
+        );
+
+        let shift = entry("http://abc.xyz/live/1?token=a", "shift", None);
+        assert_eq!(
+            catchup_url(&shift, &programme, now).unwrap(),
+            "http://abc.xyz/live/1?token=a&utc=1729186200&lutc=1729252800"
+        );
+    }
+
+    #[test]
+    fn test_catchup_url_builds_xtream_timeshift() {
+        let now = Utc::now();
+        let xtream = entry("http://abc.xyz:8080/user/pass/360", "default", None);
+        assert_eq!(
+            catchup_url(&xtream, &programme(), now).unwrap(),
+            "http://abc.xyz:8080/streaming/timeshift.php?username=user&password=pass&stream=360&start=2024-10-17:19-30&duration=30"
+        );
+
+        let live = entry("http://abc.xyz:8080/live/user/pass/360.ts", "xc", None);
+        assert!(catchup_url(&live, &programme(), now)
+            .unwrap()
+            .contains("username=user&password=pass&stream=360&"));
+
+        let unknown = entry("http://abc.xyz/channel.m3u8", "default", None);
+        assert_eq!(catchup_url(&unknown, &programme(), now), None);
+    }
+
+    #[test]
+    fn test_catchup_availability_respects_days() {
+        let catchup = entry("http://abc.xyz/1", "default", None).catchup.unwrap();
+        let programme = programme();
+        let at = |rfc3339: &str| DateTime::parse_from_rfc3339(rfc3339).unwrap().to_utc();
+
+        assert!(!catchup.is_available(&programme, at("2024-10-17T17:00:00Z")));
+        assert!(catchup.is_available(&programme, at("2024-10-17T17:45:00Z")));
+        assert!(catchup.is_available(&programme, at("2024-10-24T17:00:00Z")));
+        assert!(!catchup.is_available(&programme, at("2024-10-24T18:00:00Z")));
+    }
+}
@@ -1,15 +1,25 @@
-use chrono::{DateTime, FixedOffset};
+use chrono::{DateTime, FixedOffset, Utc};
 use rayon::prelude::*;
 use serde::{Deserialize, Deserializer, Serialize};
 use std::collections::{HashMap, HashSet};
 use std::io::Read;
+use std::ops::Range;
+use std::sync::OnceLock;
+
+use crate::search::SearchIndex;
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Epg {
     #[serde(rename = "channel", default)]
     pub channels: Vec<Channel>,
+    /// Sorted by channel and then start time; build through [`Epg::new`] after changes.
     #[serde(rename = "programme", default)]
     pub programmes: Vec<Programme>,
+    /// Each channel's slice of `programmes`.
+    #[serde(skip)]
+    schedules: HashMap<String, Range<usize>>,
+    #[serde(skip)]
+    search_index: OnceLock<SearchIndex>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -36,14 +46,59 @@ pub struct Programme {
     pub channel: String,
     pub title: String,
     pub desc: String,
+    #[serde(
+        rename(deserialize = "category"),
+        default,
+        deserialize_with = "deserialize_text_elements"
+    )]
+    pub categories: Vec<String>,
+    #[serde(rename(deserialize = "episode-num"), default)]
+    pub episode_nums: Vec<EpisodeNum>,
+    /// Set by an empty `<new/>` element for first airings.
+    #[serde(default, deserialize_with = "deserialize_present")]
+    pub new: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct EpisodeNum {
+    #[serde(default)]
+    pub system: Option<String>,
+    #[serde(rename(deserialize = "$value"), default)]
+    pub value: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct TextElement {
+    #[serde(rename = "$value", default)]
+    value: String,
+}
+
+impl Programme {
+    /// The episode number, preferring the machine readable `xmltv_ns` system.
+    pub fn episode_num(&self) -> Option<&str> {
+        self.episode_nums
+            .iter()
+            .find(|e| e.system.as_deref() == Some("xmltv_ns"))
+            .or_else(|| self.episode_nums.first())
+            .map(|e| e.value.trim())
+            .filter(|value| !value.is_empty())
+    }
 }
 
 impl Epg {
+    pub fn new(channels: Vec<Channel>, programmes: Vec<Programme>) -> Self {
+        let mut epg = Self {
+            channels,
+            programmes,
+            schedules: HashMap::new(),
+            search_index: OnceLock::new(),
+        };
+        epg.index_schedules();
+        epg
+    }
+
     pub fn empty() -> Self {
-        Self {
-            channels: Vec::new(),
-            programmes: Vec::new(),
-        }
+        Self::new(Vec::new(), Vec::new())
     }
 
     pub fn from_reader(reader: impl Read) -> Result<Epg, Box<dyn std::error::Error>> {
@@ -55,17 +110,95 @@ impl Epg {
 
     pub fn from_xml_str(input: &str) -> Result<Epg, Box<dyn std::error::Error>> {
         let input = strip_utf8_bom(input);
-        match serde_xml_rs::from_str(input) {
-            Ok(epg) => Ok(epg),
+        let mut epg: Epg = match serde_xml_rs::from_str(input) {
+            Ok(epg) => epg,
             Err(original_error) => {
                 let sanitized = sanitize_epg_xml(input);
                 if sanitized == input {
                     return Err(Box::new(original_error));
                 }
 
-                serde_xml_rs::from_str(&sanitized).map_err(|_| Box::new(original_error) as _)
+                serde_xml_rs::from_str(&sanitized).map_err(|_| Box::new(original_error))?
+            }
+        };
+        epg.index_schedules();
+        // Loaded EPGs get searched, so index now rather than on the first search.
+        epg.search_index();
+        Ok(epg)
+    }
+
+    fn index_schedules(&mut self) {
+        self.programmes
+            .par_sort_by(|a, b| a.channel.cmp(&b.channel).then(a.start.cmp(&b.start)));
+
+        let mut schedules = HashMap::new();
+        let mut channel_start = 0;
+        for index in 1..=self.programmes.len() {
+            if index == self.programmes.len()
+                || self.programmes[index].channel != self.programmes[channel_start].channel
+            {
+                schedules.insert(
+                    self.programmes[channel_start].channel.clone(),
+                    channel_start..index,
+                );
+                channel_start = index;
             }
         }
+        self.schedules = schedules;
+    }
+
+    /// All programmes on `channel`, sorted by start time.
+    pub fn schedule(&self, channel: &str) -> &[Programme] {
+        self.schedules
+            .get(channel)
+            .map_or(&[], |range| &self.programmes[range.clone()])
+    }
+
+    /// The programme on `channel` starting exactly at `start`.
+    pub fn find(&self, channel: &str, start: DateTime<Utc>) -> Option<&Programme> {
+        let schedule = self.schedule(channel);
+        let position = schedule.partition_point(|p| p.start < start);
+        schedule.get(position).filter(|p| p.start == start)
+    }
+
+    /// The programme airing on `channel` at `at` and the one after it.
+    pub fn now_and_next(
+        &self,
+        channel: &str,
+        at: DateTime<Utc>,
+    ) -> (Option<&Programme>, Option<&Programme>) {
+        let schedule = self.schedule(channel);
+        let next_position = schedule.partition_point(|p| p.start <= at);
+        let current = next_position
+            .checked_sub(1)
+            .map(|position| &schedule[position])
+            .filter(|programme| programme.stop > at);
+        (current, schedule.get(next_position))
+    }
+
+    /// Programmes on `channel` airing at some point in `[from, to)`. Relies on a channel's
+    /// programmes not overlapping each other, so their stop times are sorted too.
+    pub fn overlapping(
+        &self,
+        channel: &str,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+    ) -> &[Programme] {
+        let schedule = self.schedule(channel);
+        let first = schedule.partition_point(|p| p.stop <= from);
+        let end = schedule.partition_point(|p| p.start < to).max(first);
+        &schedule[first..end]
+    }
+
+    /// Programmes on any channel airing at some point in `[from, to)`, grouped by channel.
+    pub fn overlapping_all(
+        &self,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+    ) -> impl Iterator<Item = &Programme> {
+        self.schedules
+            .keys()
+            .flat_map(move |channel| self.overlapping(channel, from, to))
     }
 
     pub fn channel_map(&self) -> HashMap<String, Channel> {
@@ -76,23 +209,47 @@ impl Epg {
             .collect()
     }
 
-    pub fn search(&self, search_term: &str) -> Vec<Programme> {
-        let search_term = search_term.to_lowercase();
-        let time_now = chrono::Utc::now();
-
-        let mut matching_programmes: Vec<Programme> = self
-            .programmes
-            .par_iter()
-            .filter(|p| {
-                p.stop >= time_now
-                    && (p.title.to_lowercase().contains(&search_term)
-                        || p.desc.to_lowercase().contains(&search_term))
+    /// Full-text index over programme titles and descriptions, built on first use.
+    pub fn search_index(&self) -> &SearchIndex {
+        self.search_index
+            .get_or_init(|| SearchIndex::build(&self.programmes))
+    }
+
+    /// Programmes matching `query` that are still on after `since`, most relevant first.
+    /// Title hits outrank description hits and programmes closer to `now` come first.
+    pub fn search(
+        &self,
+        query: &str,
+        since: DateTime<Utc>,
+        now: DateTime<Utc>,
+    ) -> Vec<&Programme> {
+        self.search_boosted(query, since, now, |_| 1.0)
+    }
+
+    /// Like `search`, with each programme's score multiplied by `boost`.
+    pub fn search_boosted(
+        &self,
+        query: &str,
+        since: DateTime<Utc>,
+        now: DateTime<Utc>,
+        boost: impl Fn(&Programme) -> f64,
+    ) -> Vec<&Programme> {
+        let mut matching_programmes: Vec<(&Programme, f64)> = self
+            .search_index()
+            .search(&self.programmes, query)
+            .into_iter()
+            .map(|(index, score)| (&self.programmes[index], score))
+            .filter(|(p, _)| p.stop >= since)
+            .map(|(p, score)| {
+                let hours_away = (p.start.to_utc() - now).num_minutes().abs() as f64 / 60.0;
+                (p, boost(p) * score / (1.0 + hours_away / 24.0))
             })
-            .cloned()
             .collect();
-        matching_programmes.sort_by_key(|p| p.start);
+        matching_programmes.sort_by(|(a, a_score), (b, b_score)| {
+            b_score.total_cmp(a_score).then(a.start.cmp(&b.start))
+        });
 
-        matching_programmes
+        matching_programmes.into_iter().map(|(p, _)| p).collect()
     }
 
     pub fn to_xml(&self) -> Result<String, Box<dyn std::error::Error>> {
@@ -124,16 +281,45 @@ impl Epg {
                 escape_xml(&programme.title)
             ));
             body.push_str(&format!("\n<desc>{}</desc>", escape_xml(&programme.desc)));
+            for category in &programme.categories {
+                body.push_str(&format!("\n<category>{}</category>", escape_xml(category)));
+            }
+            for episode_num in &programme.episode_nums {
+                match &episode_num.system {
+                    Some(system) => body.push_str(&format!(
+                        "\n<episode-num system=\"{}\">{}</episode-num>",
+                        escape_xml(system),
+                        escape_xml(&episode_num.value)
+                    )),
+                    None => body.push_str(&format!(
+                        "\n<episode-num>{}</episode-num>",
+                        escape_xml(&episode_num.value)
+                    )),
+                }
+            }
+            if programme.new {
+                body.push_str("\n<new/>");
+            }
             body.push_str("\n</programme>");
         }
         Ok(format!("{}{}{}", header, body, footer))
     }
 
-    pub fn filter_channels(&mut self, channels_to_keep: &[String]) {
+    /// A copy holding only `channels_to_keep` and their programmes.
+    pub fn filter_channels(&self, channels_to_keep: &[String]) -> Epg {
         let channels_to_keep: HashSet<&String> = channels_to_keep.iter().collect();
-        self.channels.retain(|c| channels_to_keep.contains(&c.id));
-        self.programmes
-            .retain(|p| channels_to_keep.contains(&p.channel));
+        let channels = self
+            .channels
+            .iter()
+            .filter(|c| channels_to_keep.contains(&c.id))
+            .cloned()
+            .collect();
+        let programmes = channels_to_keep
+            .iter()
+            .flat_map(|channel| self.schedule(channel))
+            .cloned()
+            .collect();
+        Epg::new(channels, programmes)
     }
 }
 
@@ -276,10 +462,41 @@ where
     DateTime::parse_from_str(&s, "%Y%m%d%H%M%S %z").map_err(serde::de::Error::custom)
 }
 
+fn deserialize_text_elements<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let elements: Vec<TextElement> = Deserialize::deserialize(deserializer)?;
+    Ok(elements.into_iter().map(|e| e.value).collect())
+}
+
+fn deserialize_present<'de, D>(deserializer: D) -> Result<bool, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    serde::de::IgnoredAny::deserialize(deserializer).map(|_| true)
+}
+
 #[cfg(test)]
-mod tests {
+pub mod tests {
     use super::*;
 
+    /// A 30 minute programme on `svt1.se` starting at `start` (RFC 3339). Tests set any
+    /// other fields with struct update syntax.
+    pub fn programme(title: &str, start: &str) -> Programme {
+        let start = DateTime::parse_from_rfc3339(start).unwrap();
+        Programme {
+            start,
+            stop: start + chrono::Duration::minutes(30),
+            channel: "svt1.se".to_string(),
+            title: title.to_string(),
+            desc: String::new(),
+            categories: Vec::new(),
+            episode_nums: Vec::new(),
+            new: false,
+        }
+    }
+
     const SAMPLE_EPG: &str = r#"<?xml version="1.0" encoding="utf-8"?>
 <!DOCTYPE tv SYSTEM "xmltv.dtd">
 <tv generator-info-name="NXT" generator-info-url="nxtplay.xyz">
@@ -313,6 +530,112 @@ mod tests {
         assert_eq!(programme.stop.to_rfc3339(), "2024-10-17T14:00:00+01:00");
     }
 
+    #[test]
+    fn test_parse_programme_episode_metadata() -> Result<(), Box<dyn std::error::Error>> {
+        let xml = r#"
+            <programme start="20241017130900 +0100" stop="20241017140000 +0100" channel="example.com">
+                <title>Test Programme</title>
+                <desc>Test Description</desc>
+                <category lang="en">Drama</category>
+                <category lang="en">Crime</category>
+                <episode-num system="onscreen">S01E05</episode-num>
+                <episode-num system="xmltv_ns">0.4.</episode-num>
+                <new/>
+            </programme>
+        "#;
+
+        let programme: Programme = serde_xml_rs::from_str(xml)?;
+        assert_eq!(programme.categories, ["Drama", "Crime"]);
+        assert_eq!(programme.episode_num(), Some("0.4."));
+        assert!(programme.new);
+
+        let epg = Epg::new(Vec::new(), vec![programme]);
+        let reparsed = Epg::from_xml_str(&epg.to_xml()?)?;
+        assert_eq!(reparsed.programmes[0].episode_nums, epg.programmes[0].episode_nums);
+        assert!(reparsed.programmes[0].new);
+
+        let plain: Programme = serde_xml_rs::from_str(
+            r#"<programme start="20241017130900 +0100" stop="20241017140000 +0100" channel="a"><title>T</title><desc>D</desc></programme>"#,
+        )?;
+        assert!(plain.categories.is_empty());
+        assert_eq!(plain.episode_num(), None);
+        assert!(!plain.new);
+        Ok(())
+    }
+
+    #[test]
+    fn test_now_and_next_uses_channel_schedule() -> Result<(), Box<dyn std::error::Error>> {
+        let xml = r#"<tv>
+    <programme start="20241017140000 +0100" stop="20241017150000 +0100" channel="a.se"><title>Second</title><desc/></programme>
+    <programme start="20241017130000 +0100" stop="20241017140000 +0100" channel="a.se"><title>First</title><desc/></programme>
+    <programme start="20241017133000 +0100" stop="20241017143000 +0100" channel="b.se"><title>Other</title><desc/></programme>
+    <programme start="20241017160000 +0100" stop="20241017170000 +0100" channel="a.se"><title>Third</title><desc/></programme>
+</tv>"#;
+        let epg = Epg::from_xml_str(xml)?;
+        let at = |rfc3339: &str| DateTime::parse_from_rfc3339(rfc3339).unwrap().to_utc();
+        fn titles<'a>(
+            (now, next): (Option<&'a Programme>, Option<&'a Programme>),
+        ) -> (Option<&'a str>, Option<&'a str>) {
+            (now.map(|p| p.title.as_str()), next.map(|p| p.title.as_str()))
+        }
+
+        assert_eq!(
+            titles(epg.now_and_next("a.se", at("2024-10-17T13:30:00+01:00"))),
+            (Some("First"), Some("Second"))
+        );
+        assert_eq!(
+            titles(epg.now_and_next("a.se", at("2024-10-17T14:00:00+01:00"))),
+            (Some("Second"), Some("Third"))
+        );
+        // A gap in the schedule has nothing airing but still knows what is next.
+        assert_eq!(
+            titles(epg.now_and_next("a.se", at("2024-10-17T15:30:00+01:00"))),
+            (None, Some("Third"))
+        );
+        assert_eq!(
+            titles(epg.now_and_next("missing", at("2024-10-17T13:30:00+01:00"))),
+            (None, None)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_overlapping_and_filter_channels() -> Result<(), Box<dyn std::error::Error>> {
+        let xml = r#"<tv>
+    <channel id="a.se"><display-name>A</display-name></channel>
+    <channel id="b.se"><display-name>B</display-name></channel>
+    <programme start="20241017140000 +0100" stop="20241017150000 +0100" channel="a.se"><title>Second</title><desc/></programme>
+    <programme start="20241017130000 +0100" stop="20241017140000 +0100" channel="a.se"><title>First</title><desc/></programme>
+    <programme start="20241017133000 +0100" stop="20241017143000 +0100" channel="b.se"><title>Other</title><desc/></programme>
+    <programme start="20241017160000 +0100" stop="20241017170000 +0100" channel="a.se"><title>Third</title><desc/></programme>
+</tv>"#;
+        let epg = Epg::from_xml_str(xml)?;
+        let at = |rfc3339: &str| DateTime::parse_from_rfc3339(rfc3339).unwrap().to_utc();
+        let titles = |programmes: &[Programme]| -> Vec<String> {
+            programmes.iter().map(|p| p.title.clone()).collect()
+        };
+
+        let from = at("2024-10-17T13:59:00+01:00");
+        assert_eq!(
+            titles(epg.overlapping("a.se", from, at("2024-10-17T16:00:00+01:00"))),
+            ["First", "Second"]
+        );
+        assert_eq!(epg.overlapping_all(from, at("2024-10-17T14:00:00+01:00")).count(), 2);
+        assert!(epg
+            .overlapping("a.se", at("2024-10-17T17:00:00+01:00"), DateTime::<Utc>::MAX_UTC)
+            .is_empty());
+        assert_eq!(
+            epg.find("a.se", at("2024-10-17T14:00:00+01:00")).map(|p| p.title.as_str()),
+            Some("Second")
+        );
+
+        let filtered = epg.filter_channels(&["b.se".to_string()]);
+        assert_eq!(filtered.channels.len(), 1);
+        assert_eq!(titles(filtered.schedule("b.se")), ["Other"]);
+        assert!(filtered.schedule("a.se").is_empty());
+        Ok(())
+    }
+
     #[test]
     fn test_to_xml() -> Result<(), Box<dyn std::error::Error>> {
         let epg = Epg::from_reader(SAMPLE_EPG.as_bytes())?;
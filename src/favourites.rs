@@ -0,0 +1,202 @@
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path as FsPath, PathBuf},
+    sync::{Arc, RwLock},
+};
+
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    Extension, Json,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    auth::CurrentUser,
+    playlist::{Playlist, PlaylistEntry},
+    proxy,
+    store::{load_json, save_json},
+    AppState,
+};
+
+/// Each user's favourite channels as EPG channel ids in their chosen order, persisted as
+/// JSON in the data directory.
+#[derive(Debug, Clone)]
+pub struct Favourites {
+    path: PathBuf,
+    by_user: Arc<RwLock<HashMap<u64, Vec<String>>>>,
+}
+
+impl Favourites {
+    pub fn load(data_dir: &FsPath) -> Self {
+        let path = data_dir.join("favourites.json");
+        Self {
+            by_user: Arc::new(RwLock::new(load_json(&path))),
+            path,
+        }
+    }
+
+    pub fn get(&self, user_id: u64) -> Vec<String> {
+        self.by_user
+            .read()
+            .unwrap()
+            .get(&user_id)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    pub fn set(&self, user_id: u64, channels: Vec<String>) {
+        let mut seen = HashSet::new();
+        let channels: Vec<String> = channels
+            .into_iter()
+            .filter(|channel| !channel.is_empty() && seen.insert(channel.clone()))
+            .collect();
+        self.by_user.write().unwrap().insert(user_id, channels);
+        self.save();
+    }
+
+    fn save(&self) {
+        save_json(&self.path, &*self.by_user.read().unwrap());
+    }
+}
+
+/// The preferred playlist entry of each of a user's favourite channels, in their order.
+/// Favourites missing from the playlist are skipped.
+pub fn favourite_entries<'a>(
+    app_state: &AppState,
+    playlist: &'a Playlist,
+    user_id: u64,
+) -> Vec<&'a PlaylistEntry> {
+    app_state
+        .favourites
+        .get(user_id)
+        .iter()
+        .filter_map(|channel| {
+            proxy::preferred_entry(
+                &playlist.entries,
+                channel,
+                &app_state.proxy_config.failover_priority,
+            )
+        })
+        .collect()
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FavouriteChannel {
+    channel_id: String,
+    /// The `tvg-chno` the channel gets in the user's playlist.
+    chno: Option<usize>,
+    channel_name: Option<String>,
+    channel_group: Option<String>,
+    channel_logo: Option<String>,
+    url: Option<String>,
+}
+
+async fn favourite_channels(
+    app_state: &AppState,
+    user_id: u64,
+) -> Result<Vec<FavouriteChannel>, (StatusCode, &'static str)> {
+    let playlist = app_state.fetch_playlist().await.map_err(|e| {
+        tracing::error!("Failed to fetch playlist: {:?}", e);
+        (StatusCode::SERVICE_UNAVAILABLE, "Failed to fetch playlist")
+    })?;
+
+    let mut chno = 0;
+    Ok(app_state
+        .favourites
+        .get(user_id)
+        .into_iter()
+        .map(|channel_id| {
+            let entry = proxy::preferred_entry(
+                &playlist.entries,
+                &channel_id,
+                &app_state.proxy_config.failover_priority,
+            );
+            // Numbers follow `favourite_entries`, which leaves out missing channels.
+            let chno = entry.map(|_| {
+                chno += 1;
+                chno
+            });
+            FavouriteChannel {
+                channel_id,
+                chno,
+                channel_name: entry.map(|e| e.name.clone()),
+                channel_group: entry.map(|e| e.group_title.clone()),
+                channel_logo: entry.map(|e| e.tvg_logo.clone()),
+                url: entry.map(|e| e.url.clone()),
+            }
+        })
+        .collect())
+}
+
+pub async fn list_favourites(
+    State(app_state): State<AppState>,
+    Extension(CurrentUser(user)): Extension<CurrentUser>,
+) -> Result<Json<Vec<FavouriteChannel>>, (StatusCode, &'static str)> {
+    favourite_channels(&app_state, user.id).await.map(Json)
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SetFavouritesRequest {
+    /// EPG channel ids in the order they should appear.
+    channels: Vec<String>,
+}
+
+/// Replaces the user's favourites, which is also how they are reordered.
+pub async fn set_favourites(
+    State(app_state): State<AppState>,
+    Extension(CurrentUser(user)): Extension<CurrentUser>,
+    Json(request): Json<SetFavouritesRequest>,
+) -> Result<Json<Vec<FavouriteChannel>>, (StatusCode, &'static str)> {
+    app_state.favourites.set(user.id, request.channels);
+    favourite_channels(&app_state, user.id).await.map(Json)
+}
+
+/// Adds a channel to the end of the user's favourites.
+pub async fn add_favourite(
+    Path(channel_id): Path<String>,
+    State(app_state): State<AppState>,
+    Extension(CurrentUser(user)): Extension<CurrentUser>,
+) -> Result<Json<Vec<FavouriteChannel>>, (StatusCode, &'static str)> {
+    let mut channels = app_state.favourites.get(user.id);
+    channels.push(channel_id);
+    app_state.favourites.set(user.id, channels);
+    favourite_channels(&app_state, user.id).await.map(Json)
+}
+
+pub async fn remove_favourite(
+    Path(channel_id): Path<String>,
+    State(app_state): State<AppState>,
+    Extension(CurrentUser(user)): Extension<CurrentUser>,
+) -> Result<StatusCode, (StatusCode, &'static str)> {
+    let mut channels = app_state.favourites.get(user.id);
+    let count = channels.len();
+    channels.retain(|channel| *channel != channel_id);
+    if channels.len() == count {
+        return Err((StatusCode::NOT_FOUND, "Channel is not a favourite"));
+    }
+    app_state.favourites.set(user.id, channels);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_set_drops_duplicates_and_keeps_order() {
+        let dir = std::env::temp_dir().join(format!("favourites-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let favourites = Favourites::load(&dir);
+        favourites.set(
+            1,
+            vec!["B.se".into(), "A.se".into(), "B.se".into(), String::new()],
+        );
+        assert_eq!(favourites.get(1), ["B.se", "A.se"]);
+        assert!(favourites.get(2).is_empty());
+
+        assert_eq!(Favourites::load(&dir).get(1), ["B.se", "A.se"]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,444 @@
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    Json,
+};
+use chrono::{DateTime, Duration, DurationRound, FixedOffset, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::{epg::Programme, proxy, AppState};
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GuideProgramme {
+    title: String,
+    desc: String,
+    start: DateTime<FixedOffset>,
+    stop: DateTime<FixedOffset>,
+}
+
+impl From<&Programme> for GuideProgramme {
+    fn from(programme: &Programme) -> Self {
+        Self {
+            title: programme.title.clone(),
+            desc: programme.desc.clone(),
+            start: programme.start,
+            stop: programme.stop,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NowNext {
+    channel_id: String,
+    channel_name: String,
+    channel_group: String,
+    channel_logo: String,
+    url: String,
+    now: Option<GuideProgramme>,
+    next: Option<GuideProgramme>,
+    /// How far into the current programme we are, 0-100.
+    progress: Option<u8>,
+}
+
+/// What is on now and next for every channel in the filtered playlist.
+pub async fn now_playing(
+    State(app_state): State<AppState>,
+) -> Result<Json<Vec<NowNext>>, (StatusCode, &'static str)> {
+    let playlist = app_state.fetch_playlist().await.map_err(|e| {
+        tracing::error!("Failed to fetch playlist: {:?}", e);
+        (StatusCode::SERVICE_UNAVAILABLE, "Failed to fetch playlist")
+    })?;
+    let epg = app_state.fetch_epg().await.map_err(|e| {
+        tracing::error!("Failed to fetch EPG: {:?}", e);
+        (StatusCode::SERVICE_UNAVAILABLE, "Failed to fetch EPG")
+    })?;
+
+    let now = Utc::now();
+    let channels = playlist
+        .filtered_entries
+        .iter()
+        .map(|entry| {
+            let (current, next) = epg.now_and_next(&entry.tvg_id, now);
+            NowNext {
+                channel_id: entry.tvg_id.clone(),
+                channel_name: entry.name.clone(),
+                channel_group: entry.group_title.clone(),
+                channel_logo: entry.tvg_logo.clone(),
+                url: entry.url.clone(),
+                progress: current.map(|programme| progress(programme, now)),
+                now: current.map(GuideProgramme::from),
+                next: next.map(GuideProgramme::from),
+            }
+        })
+        .collect();
+
+    Ok(Json(channels))
+}
+
+const DEFAULT_GUIDE_HOURS: i64 = 3;
+const MAX_GUIDE_DAYS: i64 = 2;
+const MAX_SCHEDULE_DAYS: u32 = 14;
+const DEFAULT_GUIDE_LIMIT: usize = 50;
+const MAX_GUIDE_LIMIT: usize = 500;
+
+#[derive(Debug, Deserialize)]
+pub struct GuideQuery {
+    from: Option<DateTime<Utc>>,
+    to: Option<DateTime<Utc>>,
+    group: Option<String>,
+    offset: Option<usize>,
+    limit: Option<usize>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GuideGrid {
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+    /// Channels matching the query before paging.
+    total: usize,
+    offset: usize,
+    channels: Vec<GuideChannel>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GuideChannel {
+    channel_id: String,
+    channel_name: String,
+    channel_group: String,
+    channel_logo: String,
+    url: String,
+    slots: Vec<GuideSlot>,
+}
+
+/// One cell of a channel's row, covering `[start, stop)` inside the requested window.
+#[derive(Debug, Serialize)]
+#[serde(tag = "kind", rename_all = "camelCase")]
+pub enum GuideSlot {
+    #[serde(rename_all = "camelCase")]
+    Programme {
+        start: DateTime<Utc>,
+        stop: DateTime<Utc>,
+        programme: GuideProgramme,
+    },
+    /// No programme is known for this part of the window.
+    #[serde(rename_all = "camelCase")]
+    Gap {
+        start: DateTime<Utc>,
+        stop: DateTime<Utc>,
+    },
+}
+
+/// A page of channels from the filtered playlist with their programmes laid out over a
+/// time window, defaulting to the next few hours.
+pub async fn guide(
+    Query(query): Query<GuideQuery>,
+    State(app_state): State<AppState>,
+) -> Result<Json<GuideGrid>, (StatusCode, &'static str)> {
+    let from = match query.from {
+        Some(from) => from,
+        None => Utc::now()
+            .duration_trunc(Duration::minutes(30))
+            .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, "Invalid current time"))?,
+    };
+    let to = query
+        .to
+        .unwrap_or(from + Duration::hours(DEFAULT_GUIDE_HOURS));
+    if to <= from {
+        return Err((StatusCode::BAD_REQUEST, "`to` must be after `from`"));
+    }
+    if to - from > Duration::days(MAX_GUIDE_DAYS) {
+        return Err((StatusCode::BAD_REQUEST, "Guide window is too long"));
+    }
+    let offset = query.offset.unwrap_or(0);
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_GUIDE_LIMIT)
+        .min(MAX_GUIDE_LIMIT);
+
+    let playlist = app_state.fetch_playlist().await.map_err(|e| {
+        tracing::error!("Failed to fetch playlist: {:?}", e);
+        (StatusCode::SERVICE_UNAVAILABLE, "Failed to fetch playlist")
+    })?;
+    let epg = app_state.fetch_epg().await.map_err(|e| {
+        tracing::error!("Failed to fetch EPG: {:?}", e);
+        (StatusCode::SERVICE_UNAVAILABLE, "Failed to fetch EPG")
+    })?;
+
+    let entries: Vec<_> = playlist
+        .filtered_entries
+        .iter()
+        .filter(|entry| {
+            query
+                .group
+                .as_ref()
+                .is_none_or(|group| entry.group_title.eq_ignore_ascii_case(group))
+        })
+        .collect();
+    let channels = entries
+        .iter()
+        .skip(offset)
+        .take(limit)
+        .map(|entry| GuideChannel {
+            channel_id: entry.tvg_id.clone(),
+            channel_name: entry.name.clone(),
+            channel_group: entry.group_title.clone(),
+            channel_logo: entry.tvg_logo.clone(),
+            url: entry.url.clone(),
+            slots: grid_slots(epg.overlapping(&entry.tvg_id, from, to), from, to),
+        })
+        .collect();
+
+    Ok(Json(GuideGrid {
+        from,
+        to,
+        total: entries.len(),
+        offset,
+        channels,
+    }))
+}
+
+/// Clips `programmes` to `[from, to)` and fills whatever they leave uncovered with gaps,
+/// so the slots tile the window exactly.
+fn grid_slots(programmes: &[Programme], from: DateTime<Utc>, to: DateTime<Utc>) -> Vec<GuideSlot> {
+    let mut slots = Vec::new();
+    let mut cursor = from;
+    for programme in programmes {
+        // A programme overlapping its predecessor only gets the part not already shown.
+        let start = programme.start.to_utc().max(cursor);
+        let stop = programme.stop.to_utc().min(to);
+        if stop <= start {
+            continue;
+        }
+        if start > cursor {
+            slots.push(GuideSlot::Gap {
+                start: cursor,
+                stop: start,
+            });
+        }
+        slots.push(GuideSlot::Programme {
+            start,
+            stop,
+            programme: GuideProgramme::from(programme),
+        });
+        cursor = stop;
+    }
+    if cursor < to {
+        slots.push(GuideSlot::Gap {
+            start: cursor,
+            stop: to,
+        });
+    }
+    slots
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ScheduleQuery {
+    days: Option<u32>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ChannelSchedule {
+    channel_id: String,
+    channel_name: String,
+    channel_logo: Option<String>,
+    /// Every playlist entry for the channel, preferred variant first.
+    entries: Vec<ChannelVariant>,
+    programmes: Vec<ScheduleProgramme>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ChannelVariant {
+    name: String,
+    group: String,
+    logo: String,
+    url: String,
+    /// Left out of the served playlist by the group and name filters.
+    hidden: bool,
+    catchup: bool,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ScheduleProgramme {
+    title: String,
+    desc: String,
+    start: DateTime<FixedOffset>,
+    stop: DateTime<FixedOffset>,
+    categories: Vec<String>,
+    episode_num: Option<String>,
+    new: bool,
+}
+
+impl From<&Programme> for ScheduleProgramme {
+    fn from(programme: &Programme) -> Self {
+        Self {
+            title: programme.title.clone(),
+            desc: programme.desc.clone(),
+            start: programme.start,
+            stop: programme.stop,
+            categories: programme.categories.clone(),
+            episode_num: programme.episode_num().map(str::to_string),
+            new: programme.new,
+        }
+    }
+}
+
+/// Everything known about one channel: its playlist variants and the programmes from now
+/// until `days` ahead, including the one currently airing.
+pub async fn channel_schedule(
+    Path(channel_id): Path<String>,
+    Query(ScheduleQuery { days }): Query<ScheduleQuery>,
+    State(app_state): State<AppState>,
+) -> Result<Json<ChannelSchedule>, (StatusCode, &'static str)> {
+    let days = days.unwrap_or(1).clamp(1, MAX_SCHEDULE_DAYS);
+
+    let playlist = app_state.fetch_playlist().await.map_err(|e| {
+        tracing::error!("Failed to fetch playlist: {:?}", e);
+        (StatusCode::SERVICE_UNAVAILABLE, "Failed to fetch playlist")
+    })?;
+    let epg = app_state.fetch_epg().await.map_err(|e| {
+        tracing::error!("Failed to fetch EPG: {:?}", e);
+        (StatusCode::SERVICE_UNAVAILABLE, "Failed to fetch EPG")
+    })?;
+
+    let mut entries: Vec<_> = playlist
+        .entries
+        .iter()
+        .filter(|entry| !channel_id.is_empty() && entry.tvg_id == channel_id)
+        .collect();
+    entries.sort_by_key(|entry| {
+        proxy::priority_rank(&entry.name, &app_state.proxy_config.failover_priority)
+    });
+    let channel = epg.channels.iter().find(|channel| channel.id == channel_id);
+    if channel.is_none() && entries.is_empty() {
+        return Err((StatusCode::NOT_FOUND, "Channel not found"));
+    }
+
+    let now = Utc::now();
+    let programmes = epg
+        .overlapping(&channel_id, now, now + Duration::days(days.into()))
+        .iter()
+        .map(ScheduleProgramme::from)
+        .collect();
+
+    Ok(Json(ChannelSchedule {
+        channel_name: channel
+            .map(|channel| channel.display_name.clone())
+            .or_else(|| entries.first().map(|entry| entry.name.clone()))
+            .unwrap_or_default(),
+        channel_logo: channel
+            .and_then(|channel| channel.icon.as_ref())
+            .map(|icon| icon.src.clone())
+            .or_else(|| {
+                entries
+                    .iter()
+                    .map(|entry| entry.tvg_logo.clone())
+                    .find(|logo| !logo.is_empty())
+            }),
+        entries: entries
+            .iter()
+            .map(|entry| ChannelVariant {
+                name: entry.name.clone(),
+                group: entry.group_title.clone(),
+                logo: entry.tvg_logo.clone(),
+                url: entry.url.clone(),
+                hidden: !playlist
+                    .filtered_entries
+                    .iter()
+                    .any(|filtered| filtered.url == entry.url),
+                catchup: entry.catchup.is_some(),
+            })
+            .collect(),
+        channel_id,
+        programmes,
+    }))
+}
+
+fn progress(programme: &Programme, now: DateTime<Utc>) -> u8 {
+    let total = (programme.stop - programme.start).num_seconds();
+    if total <= 0 {
+        return 100;
+    }
+    let elapsed = (now - programme.start.to_utc())
+        .num_seconds()
+        .clamp(0, total);
+    (elapsed * 100 / total) as u8
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::epg;
+
+    fn programme(title: &str, start: &str, stop: &str) -> Programme {
+        Programme {
+            stop: DateTime::parse_from_rfc3339(stop).unwrap(),
+            ..epg::tests::programme(title, start)
+        }
+    }
+
+    #[test]
+    fn test_grid_slots_clip_programmes_and_fill_gaps() {
+        let at = |rfc3339: &str| DateTime::parse_from_rfc3339(rfc3339).unwrap().to_utc();
+        let programmes = [
+            programme("Morning", "2024-10-17T08:00:00Z", "2024-10-17T10:30:00Z"),
+            programme("Rapport", "2024-10-17T11:00:00Z", "2024-10-17T11:30:00Z"),
+            programme("Overlap", "2024-10-17T11:15:00Z", "2024-10-17T12:30:00Z"),
+        ];
+
+        let slots = grid_slots(
+            &programmes,
+            at("2024-10-17T10:00:00Z"),
+            at("2024-10-17T13:00:00Z"),
+        );
+        let layout: Vec<_> = slots
+            .iter()
+            .map(|slot| match slot {
+                GuideSlot::Programme {
+                    start,
+                    stop,
+                    programme,
+                } => (
+                    programme.title.as_str(),
+                    start.to_rfc3339(),
+                    stop.to_rfc3339(),
+                ),
+                GuideSlot::Gap { start, stop } => ("", start.to_rfc3339(), stop.to_rfc3339()),
+            })
+            .collect();
+        let expected = [
+            ("Morning", "10:00", "10:30"),
+            ("", "10:30", "11:00"),
+            ("Rapport", "11:00", "11:30"),
+            ("Overlap", "11:30", "12:30"),
+            ("", "12:30", "13:00"),
+        ];
+        assert_eq!(layout.len(), expected.len());
+        for ((title, start, stop), (expected_title, expected_start, expected_stop)) in
+            layout.iter().zip(expected)
+        {
+            assert_eq!(*title, expected_title);
+            assert_eq!(start, &format!("2024-10-17T{expected_start}:00+00:00"));
+            assert_eq!(stop, &format!("2024-10-17T{expected_stop}:00+00:00"));
+        }
+
+        let empty = grid_slots(&[], at("2024-10-17T10:00:00Z"), at("2024-10-17T13:00:00Z"));
+        assert!(matches!(empty.as_slice(), [GuideSlot::Gap { .. }]));
+    }
+
+    #[test]
+    fn test_progress_is_clamped_percentage() {
+        let programme = epg::tests::programme("Rapport", "2024-10-17T19:30:00+02:00");
+        let at = |rfc3339: &str| DateTime::parse_from_rfc3339(rfc3339).unwrap().to_utc();
+
+        assert_eq!(progress(&programme, at("2024-10-17T17:30:00Z")), 0);
+        assert_eq!(progress(&programme, at("2024-10-17T17:45:00Z")), 50);
+        assert_eq!(progress(&programme, at("2024-10-17T19:00:00Z")), 100);
+    }
+}
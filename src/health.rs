@@ -0,0 +1,158 @@
+use std::time::Duration;
+
+use axum::{extract::State, http::StatusCode, Json};
+use serde::Serialize;
+
+use crate::{admin, AppState};
+
+/// Cached data older than this makes the app report itself degraded.
+const DEFAULT_DEGRADED_AFTER: Duration = Duration::from_secs(24 * 60 * 60);
+
+#[derive(Debug, Clone)]
+pub struct HealthConfig {
+    /// Whether readiness waits for an EPG as well as a playlist.
+    pub require_epg: bool,
+    pub degraded_after: Duration,
+}
+
+impl HealthConfig {
+    pub fn from_env() -> Self {
+        Self {
+            require_epg: std::env::var("READY_REQUIRES_EPG")
+                .is_ok_and(|value| value == "1" || value.eq_ignore_ascii_case("true")),
+            degraded_after: std::env::var("DEGRADED_AFTER_SECS")
+                .ok()
+                .and_then(|value| value.parse().ok())
+                .map_or(DEFAULT_DEGRADED_AFTER, Duration::from_secs),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Readiness {
+    Ready,
+    /// Usable, but serving data older than `degraded_after`.
+    Degraded,
+    NotReady,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SourceHealth {
+    cached: bool,
+    age_secs: Option<u64>,
+    stale: bool,
+    /// Whether the last refresh attempt failed.
+    failing: bool,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ReadyReport {
+    status: Readiness,
+    reasons: Vec<String>,
+    playlist: SourceHealth,
+    epg: SourceHealth,
+}
+
+impl From<&admin::SourceStatus> for SourceHealth {
+    fn from(status: &admin::SourceStatus) -> Self {
+        Self {
+            cached: status.cached,
+            age_secs: status.age_secs,
+            stale: status.stale,
+            failing: status.last_error.is_some(),
+        }
+    }
+}
+
+fn ready_report(config: &HealthConfig, playlist: SourceHealth, epg: SourceHealth) -> ReadyReport {
+    let mut status = Readiness::Ready;
+    let mut reasons = Vec::new();
+    for (name, source, required) in [
+        ("playlist", &playlist, true),
+        ("EPG", &epg, config.require_epg),
+    ] {
+        match source.age_secs {
+            None if required => {
+                status = Readiness::NotReady;
+                reasons.push(format!("no {name} has been fetched yet"));
+            }
+            None => reasons.push(format!("no {name} has been fetched yet")),
+            Some(age) if age > config.degraded_after.as_secs() => {
+                if status == Readiness::Ready {
+                    status = Readiness::Degraded;
+                }
+                reasons.push(format!("{name} is {age}s old"));
+            }
+            Some(_) => {}
+        }
+    }
+
+    ReadyReport {
+        status,
+        reasons,
+        playlist,
+        epg,
+    }
+}
+
+/// Liveness: the process is up and serving requests.
+pub async fn healthz() -> Json<serde_json::Value> {
+    Json(serde_json::json!({ "status": "ok" }))
+}
+
+/// Readiness: 503 until the sources needed to serve playlists are cached, 200 otherwise,
+/// including when degraded so orchestrators keep routing to a stale but working app.
+pub async fn readyz(State(app_state): State<AppState>) -> (StatusCode, Json<ReadyReport>) {
+    let report = ready_report(
+        &app_state.health_config,
+        SourceHealth::from(&admin::playlist_status(&app_state).source),
+        SourceHealth::from(&admin::epg_status(&app_state).source),
+    );
+    let status = match report.status {
+        Readiness::NotReady => StatusCode::SERVICE_UNAVAILABLE,
+        Readiness::Ready | Readiness::Degraded => StatusCode::OK,
+    };
+    (status, Json(report))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn source(age_secs: Option<u64>) -> SourceHealth {
+        SourceHealth {
+            cached: age_secs.is_some(),
+            age_secs,
+            stale: false,
+            failing: false,
+        }
+    }
+
+    #[test]
+    fn test_ready_report() {
+        let config = HealthConfig {
+            require_epg: false,
+            degraded_after: Duration::from_secs(100),
+        };
+        let report = ready_report(&config, source(None), source(Some(1)));
+        assert_eq!(report.status, Readiness::NotReady);
+
+        let report = ready_report(&config, source(Some(1)), source(None));
+        assert_eq!(report.status, Readiness::Ready);
+        assert_eq!(report.reasons, ["no EPG has been fetched yet"]);
+
+        let report = ready_report(&config, source(Some(1)), source(Some(500)));
+        assert_eq!(report.status, Readiness::Degraded);
+
+        let config = HealthConfig {
+            require_epg: true,
+            ..config
+        };
+        let report = ready_report(&config, source(Some(500)), source(None));
+        assert_eq!(report.status, Readiness::NotReady);
+        assert_eq!(report.reasons.len(), 2);
+    }
+}
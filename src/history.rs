@@ -0,0 +1,334 @@
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path as FsPath, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, RwLock,
+    },
+};
+
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    Extension, Json,
+};
+use chrono::{DateTime, FixedOffset, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    auth::CurrentUser,
+    proxy,
+    store::{load_json, save_json},
+    AppState,
+};
+
+/// Sessions shorter than this are channel zapping rather than watching.
+const MIN_SESSION_SECS: i64 = 10;
+/// Oldest sessions are dropped beyond this many, across all users.
+const MAX_SESSIONS: usize = 10_000;
+const DEFAULT_HISTORY_LIMIT: usize = 50;
+const MAX_HISTORY_LIMIT: usize = 500;
+
+/// One user watching a live channel through the proxy.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct WatchSession {
+    pub user_id: u64,
+    /// EPG channel id (the playlist's `tvg_id`).
+    pub channel: String,
+    pub channel_name: String,
+    pub url: String,
+    pub started_at: DateTime<Utc>,
+    pub duration_secs: u64,
+    /// What the EPG had airing when the session started.
+    pub programme: Option<String>,
+    pub programme_start: Option<DateTime<FixedOffset>>,
+}
+
+/// Viewing sessions persisted as JSON in the data directory, oldest first.
+#[derive(Debug, Clone)]
+pub struct WatchHistory {
+    path: PathBuf,
+    sessions: Arc<RwLock<Vec<WatchSession>>>,
+    /// Set while a background save is queued, so a burst of changes is written once.
+    save_pending: Arc<AtomicBool>,
+    saving: Arc<Mutex<()>>,
+}
+
+impl WatchHistory {
+    pub fn load(data_dir: &FsPath) -> Self {
+        let path = data_dir.join("history.json");
+        Self {
+            sessions: Arc::new(RwLock::new(load_json(&path))),
+            path,
+            save_pending: Arc::new(AtomicBool::new(false)),
+            saving: Arc::new(Mutex::new(())),
+        }
+    }
+
+    /// The user's sessions, newest first.
+    pub fn list(&self, user_id: u64) -> Vec<WatchSession> {
+        self.sessions
+            .read()
+            .unwrap()
+            .iter()
+            .rev()
+            .filter(|s| s.user_id == user_id)
+            .cloned()
+            .collect()
+    }
+
+    fn insert(&self, session: WatchSession) {
+        let mut sessions = self.sessions.write().unwrap();
+        sessions.push(session);
+        let excess = sessions.len().saturating_sub(MAX_SESSIONS);
+        sessions.drain(..excess);
+        drop(sessions);
+        self.save_soon();
+    }
+
+    fn clear(&self, user_id: u64) {
+        self.sessions
+            .write()
+            .unwrap()
+            .retain(|s| s.user_id != user_id);
+        self.save_soon();
+    }
+
+    /// Writes the history on a blocking thread, as it can hold thousands of sessions and
+    /// is saved whenever a stream closes.
+    fn save_soon(&self) {
+        if self.save_pending.swap(true, Ordering::AcqRel) {
+            return;
+        }
+        let history = self.clone();
+        tokio::task::spawn_blocking(move || {
+            let _saving = history.saving.lock().unwrap();
+            history.save_pending.store(false, Ordering::Release);
+            // Copied so streams closing meanwhile don't wait for the write.
+            let sessions = history.sessions.read().unwrap().clone();
+            save_json(&history.path, &sessions);
+        });
+    }
+}
+
+/// Records a viewing session when dropped, which happens once the client stops reading
+/// the stream.
+pub struct WatchGuard {
+    history: WatchHistory,
+    session: WatchSession,
+}
+
+impl Drop for WatchGuard {
+    fn drop(&mut self) {
+        let duration = Utc::now() - self.session.started_at;
+        if duration.num_seconds() < MIN_SESSION_SECS {
+            return;
+        }
+        let mut session = self.session.clone();
+        session.duration_secs = duration.num_seconds() as u64;
+        tracing::debug!(
+            user_id = session.user_id,
+            channel = %session.channel,
+            duration_secs = session.duration_secs,
+            "Recording watch session"
+        );
+        self.history.insert(session);
+    }
+}
+
+/// Starts tracking `user_id` watching `url`, if it is a live channel from the playlist.
+pub fn start_session(app_state: &AppState, user_id: u64, url: &str) -> Option<WatchGuard> {
+    let (channel, channel_name) = app_state
+        .cached_playlist
+        .read()
+        .unwrap()
+        .as_ref()?
+        .playlist
+        .filtered_entries
+        .iter()
+        .find(|e| e.url == url)
+        .map(|e| (e.tvg_id.clone(), e.name.clone()))?;
+
+    let started_at = Utc::now();
+    let epg = app_state.cached_epg_snapshot();
+    let airing = epg
+        .as_ref()
+        .and_then(|epg| epg.now_and_next(&channel, started_at).0);
+
+    Some(WatchGuard {
+        history: app_state.history.clone(),
+        session: WatchSession {
+            user_id,
+            programme: airing.map(|p| p.title.clone()),
+            programme_start: airing.map(|p| p.start),
+            channel,
+            channel_name,
+            url: url.to_string(),
+            started_at,
+            duration_secs: 0,
+        },
+    })
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RecentChannel {
+    channel: String,
+    channel_name: String,
+    url: String,
+    last_watched: DateTime<Utc>,
+    last_programme: Option<String>,
+}
+
+/// Distinct channels from `sessions` (newest first), most recently watched first.
+fn recent_channels(sessions: &[WatchSession]) -> Vec<RecentChannel> {
+    let mut seen = HashSet::new();
+    sessions
+        .iter()
+        .filter(|s| seen.insert(s.channel.as_str()))
+        .map(|s| RecentChannel {
+            channel: s.channel.clone(),
+            channel_name: s.channel_name.clone(),
+            url: s.url.clone(),
+            last_watched: s.started_at,
+            last_programme: s.programme.clone(),
+        })
+        .collect()
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ChannelStats {
+    channel: String,
+    channel_name: String,
+    sessions: usize,
+    total_secs: u64,
+    last_watched: DateTime<Utc>,
+}
+
+/// Totals per channel from `sessions` (newest first), most watched first.
+fn channel_stats(sessions: &[WatchSession]) -> Vec<ChannelStats> {
+    let mut by_channel: HashMap<&str, ChannelStats> = HashMap::new();
+    for session in sessions {
+        let stats = by_channel
+            .entry(&session.channel)
+            .or_insert_with(|| ChannelStats {
+                channel: session.channel.clone(),
+                channel_name: session.channel_name.clone(),
+                sessions: 0,
+                total_secs: 0,
+                last_watched: session.started_at,
+            });
+        stats.sessions += 1;
+        stats.total_secs += session.duration_secs;
+    }
+
+    let mut stats: Vec<ChannelStats> = by_channel.into_values().collect();
+    stats.sort_by(|a, b| {
+        b.total_secs
+            .cmp(&a.total_secs)
+            .then(b.last_watched.cmp(&a.last_watched))
+    });
+    stats
+}
+
+#[derive(Debug, Deserialize)]
+pub struct HistoryQuery {
+    limit: Option<usize>,
+}
+
+impl HistoryQuery {
+    fn limit(&self) -> usize {
+        self.limit
+            .unwrap_or(DEFAULT_HISTORY_LIMIT)
+            .min(MAX_HISTORY_LIMIT)
+    }
+}
+
+pub async fn list_history(
+    Query(query): Query<HistoryQuery>,
+    State(app_state): State<AppState>,
+    Extension(CurrentUser(user)): Extension<CurrentUser>,
+) -> Json<Vec<WatchSession>> {
+    let mut sessions = app_state.history.list(user.id);
+    sessions.truncate(query.limit());
+    Json(sessions)
+}
+
+pub async fn clear_history(
+    State(app_state): State<AppState>,
+    Extension(CurrentUser(user)): Extension<CurrentUser>,
+) -> StatusCode {
+    app_state.history.clear(user.id);
+    StatusCode::NO_CONTENT
+}
+
+/// Recently watched channels for "continue watching", with URLs from the current playlist
+/// where the channel is still in it.
+pub async fn list_recent_channels(
+    Query(query): Query<HistoryQuery>,
+    State(app_state): State<AppState>,
+    Extension(CurrentUser(user)): Extension<CurrentUser>,
+) -> Json<Vec<RecentChannel>> {
+    let mut channels = recent_channels(&app_state.history.list(user.id));
+    channels.truncate(query.limit());
+
+    if let Some(fetch) = app_state.cached_playlist.read().unwrap().as_ref() {
+        for channel in &mut channels {
+            if let Some(entry) = proxy::preferred_entry(
+                &fetch.playlist.filtered_entries,
+                &channel.channel,
+                &app_state.proxy_config.failover_priority,
+            ) {
+                channel.url = entry.url.clone();
+            }
+        }
+    }
+    Json(channels)
+}
+
+pub async fn list_channel_stats(
+    State(app_state): State<AppState>,
+    Extension(CurrentUser(user)): Extension<CurrentUser>,
+) -> Json<Vec<ChannelStats>> {
+    Json(channel_stats(&app_state.history.list(user.id)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn session(channel: &str, minutes_ago: i64, duration_secs: u64) -> WatchSession {
+        WatchSession {
+            user_id: 1,
+            channel: channel.to_string(),
+            channel_name: channel.to_string(),
+            url: format!("http://abc.xyz/{channel}"),
+            started_at: Utc::now() - chrono::Duration::minutes(minutes_ago),
+            duration_secs,
+            programme: None,
+            programme_start: None,
+        }
+    }
+
+    #[test]
+    fn test_recent_channels_and_stats() {
+        let sessions = vec![
+            session("B.se", 5, 60),
+            session("A.se", 30, 600),
+            session("B.se", 60, 120),
+        ];
+
+        let recent: Vec<_> = recent_channels(&sessions)
+            .into_iter()
+            .map(|c| c.channel)
+            .collect();
+        assert_eq!(recent, ["B.se", "A.se"]);
+
+        let stats = channel_stats(&sessions);
+        assert_eq!(stats[0].channel, "A.se");
+        assert_eq!((stats[1].sessions, stats[1].total_secs), (2, 180));
+        assert_eq!(stats[1].last_watched, sessions[0].started_at);
+    }
+}
@@ -0,0 +1,619 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Result};
+use axum::{
+    body::{Body, Bytes},
+    extract::{Path, State},
+    http::{Response, StatusCode},
+};
+use futures::StreamExt;
+use tokio::sync::Notify;
+
+use crate::{
+    auth::random_token,
+    proxy::{self, OnLag},
+    ts::{
+        parse_pat, parse_pmt_video_pid, TsPacket, TsPacketAligner, CLOCK_HZ, PAT_PID,
+        TS_PACKET_SIZE,
+    },
+    AppState,
+};
+
+const DEFAULT_SEGMENT_DURATION: Duration = Duration::from_secs(4);
+const DEFAULT_WINDOW_SEGMENTS: usize = 6;
+const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+const FIRST_SEGMENT_TIMEOUT: Duration = Duration::from_secs(20);
+/// Bytes to read without finding TS sync before giving up on an upstream.
+const SYNC_SEARCH_LIMIT: usize = 64 * 1024;
+/// A PCR jump larger than this (or backwards) is treated as a stream discontinuity.
+const MAX_PCR_GAP: u64 = 10 * CLOCK_HZ;
+
+#[derive(Debug, Clone)]
+pub struct HlsConfig {
+    pub segment_duration: Duration,
+    pub window_segments: usize,
+    /// Sessions nobody has requested a playlist or segment from for this long are stopped.
+    pub idle_timeout: Duration,
+}
+
+impl HlsConfig {
+    pub fn from_env() -> Self {
+        let secs = |name: &str| {
+            std::env::var(name)
+                .ok()
+                .and_then(|value| value.parse().ok())
+                .filter(|&secs| secs > 0)
+                .map(Duration::from_secs)
+        };
+
+        Self {
+            segment_duration: secs("HLS_SEGMENT_SECS").unwrap_or(DEFAULT_SEGMENT_DURATION),
+            window_segments: std::env::var("HLS_WINDOW_SEGMENTS")
+                .ok()
+                .and_then(|value| value.parse().ok())
+                .filter(|&segments| segments > 0)
+                .unwrap_or(DEFAULT_WINDOW_SEGMENTS),
+            idle_timeout: secs("HLS_IDLE_TIMEOUT_SECS").unwrap_or(DEFAULT_IDLE_TIMEOUT),
+        }
+    }
+}
+
+/// A finished chunk of the transport stream, starting with PAT/PMT and a keyframe.
+#[derive(Debug, Clone)]
+pub struct SegmentData {
+    pub duration: Duration,
+    pub discontinuity: bool,
+    pub data: Bytes,
+}
+
+/// Aligns raw upstream chunks to TS packets and feeds them through a [`Segmenter`].
+#[derive(Debug)]
+pub struct StreamSegmenter {
+    aligner: TsPacketAligner,
+    segmenter: Segmenter,
+    unsynced_bytes: usize,
+}
+
+impl StreamSegmenter {
+    pub fn new(target_duration: Duration) -> Self {
+        Self {
+            aligner: TsPacketAligner::default(),
+            segmenter: Segmenter::new(target_duration),
+            unsynced_bytes: 0,
+        }
+    }
+
+    /// Returns the segments completed by `chunk`, failing if the upstream is not MPEG-TS.
+    pub fn push(&mut self, chunk: &[u8]) -> Result<Vec<SegmentData>> {
+        let Some(packets) = self.aligner.push(chunk) else {
+            self.unsynced_bytes += chunk.len();
+            if self.unsynced_bytes > SYNC_SEARCH_LIMIT {
+                return Err(anyhow!("upstream is not an MPEG-TS stream"));
+            }
+            return Ok(Vec::new());
+        };
+        self.unsynced_bytes = 0;
+        Ok(packets
+            .chunks(TS_PACKET_SIZE)
+            .filter_map(|packet| self.segmenter.push(packet))
+            .collect())
+    }
+
+    pub fn finish(&mut self) -> Option<SegmentData> {
+        self.segmenter.finish()
+    }
+}
+
+/// Cuts a packet-aligned transport stream into segments without touching the media.
+/// Segments are cut on video keyframes, or on PATs for streams without video.
+#[derive(Debug)]
+pub struct Segmenter {
+    target_duration: Duration,
+    pmt_pid: Option<u16>,
+    video_pid: Option<u16>,
+    pat: Option<Vec<u8>>,
+    pmt: Option<Vec<u8>>,
+    current: Vec<u8>,
+    current_started: Instant,
+    start_pcr: Option<u64>,
+    last_pcr: Option<u64>,
+    discontinuity: bool,
+}
+
+impl Segmenter {
+    pub fn new(target_duration: Duration) -> Self {
+        Self {
+            target_duration,
+            pmt_pid: None,
+            video_pid: None,
+            pat: None,
+            pmt: None,
+            current: Vec::new(),
+            current_started: Instant::now(),
+            start_pcr: None,
+            last_pcr: None,
+            discontinuity: false,
+        }
+    }
+
+    pub fn push(&mut self, data: &[u8]) -> Option<SegmentData> {
+        let packet = TsPacket::new(data)?;
+        let pid = packet.pid();
+        let unit_start = packet.payload_unit_start();
+
+        if pid == PAT_PID && unit_start {
+            if let Some(pmt_pid) = packet.payload().and_then(parse_pat) {
+                self.pmt_pid = Some(pmt_pid);
+            }
+            self.pat = Some(data.to_vec());
+        } else if Some(pid) == self.pmt_pid && unit_start {
+            if let Some(video_pid) = packet.payload().and_then(parse_pmt_video_pid) {
+                self.video_pid = Some(video_pid);
+            }
+            self.pmt = Some(data.to_vec());
+        }
+
+        let mut completed = None;
+        if let Some(pcr) = packet.pcr() {
+            let jumped = self
+                .last_pcr
+                .is_some_and(|last| pcr < last || pcr - last > MAX_PCR_GAP);
+            if jumped {
+                // Timestamps restarted (usually an upstream reconnect), close what we have.
+                completed = self.cut(true);
+                self.start_pcr = Some(pcr);
+            }
+            self.start_pcr.get_or_insert(pcr);
+            self.last_pcr = Some(pcr);
+        }
+
+        let duration = self.current_duration();
+        let boundary = match self.video_pid {
+            Some(video_pid) => {
+                (pid == video_pid && unit_start && packet.random_access())
+                    || (pid == PAT_PID && duration >= self.target_duration * 3)
+            }
+            None => pid == PAT_PID,
+        };
+        if completed.is_none() && boundary && duration >= self.target_duration {
+            completed = self.cut(false);
+        }
+
+        if self.current.is_empty() && pid != PAT_PID {
+            // Every segment must be decodable on its own, so lead with the latest tables.
+            for table in [&self.pat, &self.pmt].into_iter().flatten() {
+                self.current.extend_from_slice(table);
+            }
+        }
+        self.current.extend_from_slice(data);
+        completed
+    }
+
+    /// Returns whatever is buffered as a final, possibly short, segment.
+    pub fn finish(&mut self) -> Option<SegmentData> {
+        self.cut(false)
+    }
+
+    fn current_duration(&self) -> Duration {
+        match (self.start_pcr, self.last_pcr) {
+            (Some(start), Some(last)) if last >= start => {
+                Duration::from_secs_f64((last - start) as f64 / CLOCK_HZ as f64)
+            }
+            _ => self.current_started.elapsed(),
+        }
+    }
+
+    fn cut(&mut self, next_is_discontinuity: bool) -> Option<SegmentData> {
+        if self.current.is_empty() {
+            return None;
+        }
+        let segment = SegmentData {
+            duration: self.current_duration(),
+            discontinuity: self.discontinuity,
+            data: Bytes::from(std::mem::take(&mut self.current)),
+        };
+        self.discontinuity = next_is_discontinuity;
+        self.start_pcr = self.last_pcr;
+        self.current_started = Instant::now();
+        Some(segment)
+    }
+}
+
+/// A segment's entry in a media playlist.
+#[derive(Debug, Clone)]
+pub struct SegmentInfo {
+    pub sequence: u64,
+    pub duration: Duration,
+    pub discontinuity: bool,
+}
+
+#[derive(Debug, Clone)]
+struct Segment {
+    info: SegmentInfo,
+    data: Bytes,
+}
+
+#[derive(Debug, Default)]
+struct SessionState {
+    segments: VecDeque<Segment>,
+    next_sequence: u64,
+    discontinuity_sequence: u64,
+    ended: bool,
+    error: Option<String>,
+}
+
+/// One upstream stream being remuxed into a rolling live playlist.
+#[derive(Debug)]
+pub struct HlsSession {
+    id: String,
+    url: String,
+    state: RwLock<SessionState>,
+    last_access: Mutex<Instant>,
+    changed: Notify,
+}
+
+impl HlsSession {
+    fn new(id: String, url: String) -> Self {
+        Self {
+            id,
+            url,
+            state: RwLock::new(SessionState::default()),
+            last_access: Mutex::new(Instant::now()),
+            changed: Notify::new(),
+        }
+    }
+
+    fn touch(&self) {
+        *self.last_access.lock().unwrap() = Instant::now();
+    }
+
+    fn idle_for(&self) -> Duration {
+        self.last_access.lock().unwrap().elapsed()
+    }
+
+    fn is_ended(&self) -> bool {
+        self.state.read().unwrap().ended
+    }
+
+    fn push_segment(&self, segment: SegmentData, window_segments: usize) {
+        let mut state = self.state.write().unwrap();
+        let sequence = state.next_sequence;
+        state.next_sequence += 1;
+        state.segments.push_back(Segment {
+            info: SegmentInfo {
+                sequence,
+                duration: segment.duration,
+                discontinuity: segment.discontinuity,
+            },
+            data: segment.data,
+        });
+        while state.segments.len() > window_segments {
+            if let Some(removed) = state.segments.pop_front() {
+                if removed.info.discontinuity {
+                    state.discontinuity_sequence += 1;
+                }
+            }
+        }
+        drop(state);
+        self.changed.notify_waiters();
+    }
+
+    fn finish(&self, error: Option<String>) {
+        let mut state = self.state.write().unwrap();
+        state.ended = true;
+        state.error = error;
+        drop(state);
+        self.changed.notify_waiters();
+    }
+
+    /// Waits until the first segment is available or the session has ended.
+    async fn wait_until_playable(&self, timeout: Duration) {
+        let _ = tokio::time::timeout(timeout, async {
+            loop {
+                let changed = self.changed.notified();
+                {
+                    let state = self.state.read().unwrap();
+                    if !state.segments.is_empty() || state.ended {
+                        return;
+                    }
+                }
+                changed.await;
+            }
+        })
+        .await;
+    }
+
+    fn segment(&self, sequence: u64) -> Option<Bytes> {
+        self.state
+            .read()
+            .unwrap()
+            .segments
+            .iter()
+            .find(|segment| segment.info.sequence == sequence)
+            .map(|segment| segment.data.clone())
+    }
+
+    fn playlist(&self) -> Result<String> {
+        let state = self.state.read().unwrap();
+        if state.segments.is_empty() {
+            return Err(anyhow!(state
+                .error
+                .clone()
+                .unwrap_or_else(|| "no segments available yet".to_string())));
+        }
+        Ok(render_playlist(
+            &format!("/hls-segment/{}", self.id),
+            state.segments.iter().map(|segment| &segment.info),
+            state.discontinuity_sequence,
+            state.ended,
+        ))
+    }
+}
+
+/// Renders a live media playlist whose segment URIs are `{segment_path}/{sequence}.ts`.
+/// Segments may drop off the front as the media sequence advances, players can still
+/// seek back within whatever the playlist currently lists.
+pub fn render_playlist<'a>(
+    segment_path: &str,
+    segments: impl Iterator<Item = &'a SegmentInfo> + Clone,
+    discontinuity_sequence: u64,
+    ended: bool,
+) -> String {
+    let target_duration = segments
+        .clone()
+        .map(|segment| segment.duration.as_secs_f64().ceil() as u64)
+        .max()
+        .unwrap_or(1)
+        .max(1);
+    let media_sequence = segments
+        .clone()
+        .next()
+        .map_or(0, |segment| segment.sequence);
+
+    let mut playlist = String::from("#EXTM3U\n#EXT-X-VERSION:3\n");
+    playlist.push_str(&format!(
+        "#EXT-X-TARGETDURATION:{target_duration}\n#EXT-X-MEDIA-SEQUENCE:{media_sequence}\n#EXT-X-DISCONTINUITY-SEQUENCE:{discontinuity_sequence}\n"
+    ));
+    for segment in segments {
+        if segment.discontinuity {
+            playlist.push_str("#EXT-X-DISCONTINUITY\n");
+        }
+        playlist.push_str(&format!(
+            "#EXTINF:{:.3},\n{}/{}.ts\n",
+            segment.duration.as_secs_f64(),
+            segment_path,
+            segment.sequence
+        ));
+    }
+    if ended {
+        playlist.push_str("#EXT-X-ENDLIST\n");
+    }
+    playlist
+}
+
+/// Live HLS sessions keyed by upstream URL.
+#[derive(Debug, Clone, Default)]
+pub struct HlsSessions(Arc<Mutex<HashMap<String, Arc<HlsSession>>>>);
+
+impl HlsSessions {
+    /// The session with the random id from its playlist's segment URIs.
+    fn get(&self, id: &str) -> Option<Arc<HlsSession>> {
+        self.0
+            .lock()
+            .unwrap()
+            .values()
+            .find(|session| session.id == id)
+            .cloned()
+    }
+
+    fn get_or_start(&self, app_state: &AppState, url: &str) -> Arc<HlsSession> {
+        let mut sessions = self.0.lock().unwrap();
+        if let Some(session) = sessions.get(url).filter(|session| !session.is_ended()) {
+            return session.clone();
+        }
+
+        let session = Arc::new(HlsSession::new(random_token(), url.to_string()));
+        sessions.insert(url.to_string(), session.clone());
+        tokio::spawn(run_session(app_state.clone(), session.clone()));
+        session
+    }
+
+    fn remove(&self, session: &Arc<HlsSession>) {
+        let mut sessions = self.0.lock().unwrap();
+        if sessions
+            .get(&session.url)
+            .is_some_and(|current| Arc::ptr_eq(current, session))
+        {
+            sessions.remove(&session.url);
+        }
+    }
+}
+
+async fn run_session(app_state: AppState, session: Arc<HlsSession>) {
+    tracing::info!(url = %session.url, "Starting HLS session");
+    let error = match segment_stream(&app_state, &session).await {
+        Ok(()) => None,
+        Err(error) => {
+            tracing::warn!(?error, url = %session.url, "HLS session failed");
+            Some(error.to_string())
+        }
+    };
+    session.finish(error);
+    app_state.hls_sessions.remove(&session);
+    tracing::info!(url = %session.url, "Stopped HLS session");
+}
+
+async fn segment_stream(app_state: &AppState, session: &HlsSession) -> Result<()> {
+    let config = &app_state.hls_config;
+    let upstream = proxy::open_live_stream(app_state, &session.url, OnLag::Skip).await?;
+    if !upstream.status.is_success() {
+        return Err(anyhow!("upstream returned {}", upstream.status));
+    }
+
+    let mut body = upstream.body;
+    let mut segmenter = StreamSegmenter::new(config.segment_duration);
+
+    while let Some(chunk) = body.next().await {
+        let chunk = chunk?;
+        if session.idle_for() > config.idle_timeout {
+            return Ok(());
+        }
+
+        for segment in segmenter.push(&chunk)? {
+            session.push_segment(segment, config.window_segments);
+        }
+    }
+
+    if let Some(segment) = segmenter.finish() {
+        session.push_segment(segment, config.window_segments);
+    }
+    Ok(())
+}
+
+pub async fn hls_playlist(
+    Path(stream_path): Path<String>,
+    State(app_state): State<AppState>,
+) -> Result<Response<Body>, (StatusCode, String)> {
+    let session = app_state
+        .hls_sessions
+        .get_or_start(&app_state, &stream_path);
+    session.touch();
+    session.wait_until_playable(FIRST_SEGMENT_TIMEOUT).await;
+
+    let playlist = session.playlist().map_err(|error| {
+        (
+            StatusCode::BAD_GATEWAY,
+            format!("Failed to start HLS stream: {}", error),
+        )
+    })?;
+
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", "application/vnd.apple.mpegurl")
+        .header("Cache-Control", "no-cache")
+        .body(Body::from(playlist))
+        .unwrap())
+}
+
+pub async fn hls_segment(
+    Path((session_id, segment)): Path<(String, String)>,
+    State(app_state): State<AppState>,
+) -> Result<Response<Body>, (StatusCode, &'static str)> {
+    let sequence: u64 = segment
+        .strip_suffix(".ts")
+        .and_then(|sequence| sequence.parse().ok())
+        .ok_or((StatusCode::NOT_FOUND, "Unknown segment"))?;
+    let session = app_state
+        .hls_sessions
+        .get(&session_id)
+        .ok_or((StatusCode::NOT_FOUND, "Unknown HLS session"))?;
+    session.touch();
+    let data = session
+        .segment(sequence)
+        .ok_or((StatusCode::NOT_FOUND, "Segment is no longer available"))?;
+
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", "video/mp2t")
+        .header("Cache-Control", "max-age=60")
+        .body(Body::from(data))
+        .unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ts::tests::{packet, pat_packet, pmt_packet};
+
+    const PMT_PID: u16 = 0x1000;
+    const VIDEO_PID: u16 = 0x100;
+
+    fn video(seconds: u64, keyframe: bool) -> Vec<u8> {
+        packet(
+            VIDEO_PID,
+            true,
+            keyframe,
+            Some(seconds * CLOCK_HZ),
+            b"frame",
+        )
+    }
+
+    #[test]
+    fn test_segmenter_cuts_on_keyframes_after_target_duration() {
+        let mut segmenter = Segmenter::new(Duration::from_secs(4));
+        let mut segments = Vec::new();
+        let mut push = |data: Vec<u8>| segments.extend(segmenter.push(&data));
+
+        push(pat_packet(PMT_PID));
+        push(pmt_packet(PMT_PID, VIDEO_PID));
+        for second in 0..=10 {
+            // Keyframe every other second.
+            push(video(second, second % 2 == 0));
+        }
+
+        assert_eq!(segments.len(), 2);
+        assert_eq!(segments[0].duration, Duration::from_secs(4));
+        assert!(!segments[0].discontinuity);
+        // The second segment was cut mid-stream, so it must lead with PAT and PMT.
+        let second = &segments[1].data;
+        assert_eq!(
+            TsPacket::new(&second[..TS_PACKET_SIZE]).unwrap().pid(),
+            PAT_PID
+        );
+        assert_eq!(
+            TsPacket::new(&second[TS_PACKET_SIZE..2 * TS_PACKET_SIZE])
+                .unwrap()
+                .pid(),
+            PMT_PID
+        );
+        let first_video = TsPacket::new(&second[2 * TS_PACKET_SIZE..3 * TS_PACKET_SIZE]).unwrap();
+        assert!(first_video.random_access());
+    }
+
+    #[test]
+    fn test_segmenter_marks_discontinuity_on_pcr_jump() {
+        let mut segmenter = Segmenter::new(Duration::from_secs(4));
+        segmenter.push(&pat_packet(PMT_PID));
+        segmenter.push(&pmt_packet(PMT_PID, VIDEO_PID));
+        segmenter.push(&video(100, true));
+        segmenter.push(&video(101, false));
+
+        let cut = segmenter.push(&video(5, true)).unwrap();
+        assert!(!cut.discontinuity);
+        segmenter.push(&video(6, false));
+        let after_jump = segmenter.finish().unwrap();
+        assert!(after_jump.discontinuity);
+    }
+
+    #[test]
+    fn test_render_playlist() {
+        let segments = [
+            SegmentInfo {
+                sequence: 7,
+                duration: Duration::from_millis(4000),
+                discontinuity: false,
+            },
+            SegmentInfo {
+                sequence: 8,
+                duration: Duration::from_millis(4480),
+                discontinuity: true,
+            },
+        ];
+
+        let playlist = render_playlist("/hls-segment/abc", segments.iter(), 2, false);
+        assert_eq!(
+            playlist,
+            "#EXTM3U\n#EXT-X-VERSION:3\n#EXT-X-TARGETDURATION:5\n#EXT-X-MEDIA-SEQUENCE:7\n#EXT-X-DISCONTINUITY-SEQUENCE:2\n\
+             #EXTINF:4.000,\n/hls-segment/abc/7.ts\n\
+             #EXT-X-DISCONTINUITY\n#EXTINF:4.480,\n/hls-segment/abc/8.ts\n"
+        );
+
+        let ended = render_playlist("/timeshift-segment/abc", segments.iter(), 0, true);
+        assert!(!ended.contains("#EXT-X-PLAYLIST-TYPE"));
+        assert!(ended.ends_with("/timeshift-segment/abc/8.ts\n#EXT-X-ENDLIST\n"));
+    }
+}
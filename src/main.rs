@@ -1,9 +1,7 @@
 use anyhow::{anyhow, Context, Result};
-use futures::StreamExt;
 use http::{HeaderName, HeaderValue};
 use reqwest::Client;
 use std::{
-    io,
     net::SocketAddr,
     sync::{Arc, RwLock},
     time::{Duration, Instant},
@@ -11,9 +9,28 @@ use std::{
 use tokio::{fs, net::TcpListener, sync::Mutex};
 use tower::ServiceBuilder;
 
-use axum::{body::Body, extract::Path, http::Response, routing::get, Router};
+use axum::{
+    middleware,
+    routing::{delete, get, post, put},
+    Router,
+};
+use admin::FetchError;
+use alerts::{AlertConfig, SavedSearches};
+use auth::Users;
 use epg::Epg;
+use favourites::Favourites;
+use health::HealthConfig;
+use history::WatchHistory;
+use hls::{HlsConfig, HlsSessions};
+use metrics::Metrics;
 use playlist::Playlist;
+use probe::{ProbeConfig, StreamHealthRegistry};
+use proxy::{ProxyConfig, SharedStreams, UpstreamConnections};
+use recordings::{RecordingConfig, Recordings};
+use refresh::{Backoff, SourceConfig};
+use reminders::StarredProgrammes;
+use series::SeriesRules;
+use timeshift::{TimeshiftBuffers, TimeshiftConfig};
 use tower_http::{
     cors::CorsLayer,
     services::{ServeDir, ServeFile},
@@ -22,18 +39,40 @@ use tower_http::{
 };
 use tracing_subscriber::EnvFilter;
 
+mod admin;
+mod alerts;
+mod auth;
+mod catchup;
 mod epg;
+mod favourites;
+mod guide;
+mod health;
+mod history;
+mod hls;
+mod metrics;
 mod playlist;
+mod probe;
+mod proxy;
+mod recordings;
+mod refresh;
+mod reminders;
 mod routes;
+mod search;
+mod series;
+mod store;
+mod timeshift;
+mod ts;
 
-const CACHE_TTL: Duration = Duration::from_secs(6 * 60 * 60);
-const REFRESH_RETRY_BACKOFF: Duration = Duration::from_secs(60);
 const FETCH_TIMEOUT: Duration = Duration::from_secs(30);
 const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
 const APP_USER_AGENT: &str = "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) Chrome/121.0.0.0 Safari/537.36";
 
 trait FileFetch {
-    fn is_stale(&self) -> bool;
+    fn fetched(&self) -> Instant;
+
+    fn is_stale(&self, ttl: Duration) -> bool {
+        self.fetched().elapsed() > ttl
+    }
 }
 
 #[derive(Debug)]
@@ -44,19 +83,19 @@ struct PlaylistFetch {
 
 #[derive(Debug)]
 struct EpgFetch {
-    epg: Epg,
+    epg: Arc<Epg>,
     fetched: Instant,
 }
 
 impl FileFetch for PlaylistFetch {
-    fn is_stale(&self) -> bool {
-        self.fetched.elapsed() > CACHE_TTL
+    fn fetched(&self) -> Instant {
+        self.fetched
     }
 }
 
 impl FileFetch for EpgFetch {
-    fn is_stale(&self) -> bool {
-        self.fetched.elapsed() > CACHE_TTL
+    fn fetched(&self) -> Instant {
+        self.fetched
     }
 }
 
@@ -64,12 +103,37 @@ impl FileFetch for EpgFetch {
 struct AppState {
     pub cached_playlist: Arc<RwLock<Option<PlaylistFetch>>>,
     pub cached_epg: Arc<RwLock<Option<EpgFetch>>>,
-    playlist_last_attempt: Arc<RwLock<Option<Instant>>>,
-    epg_last_attempt: Arc<RwLock<Option<Instant>>>,
+    playlist_config: SourceConfig,
+    epg_config: SourceConfig,
+    playlist_backoff: Arc<RwLock<Backoff>>,
+    epg_backoff: Arc<RwLock<Backoff>>,
+    playlist_last_error: Arc<RwLock<Option<FetchError>>>,
+    epg_last_error: Arc<RwLock<Option<FetchError>>>,
     playlist_refresh_lock: Arc<Mutex<()>>,
     epg_refresh_lock: Arc<Mutex<()>>,
     client: Client,
+    playlist_client: Client,
+    epg_client: Client,
     stream_client: Client,
+    proxy_config: ProxyConfig,
+    upstream_connections: UpstreamConnections,
+    shared_streams: SharedStreams,
+    probe_config: ProbeConfig,
+    stream_health: StreamHealthRegistry,
+    hls_config: HlsConfig,
+    hls_sessions: HlsSessions,
+    timeshift_config: TimeshiftConfig,
+    timeshift_buffers: TimeshiftBuffers,
+    recordings: Recordings,
+    series_rules: SeriesRules,
+    alert_config: AlertConfig,
+    saved_searches: SavedSearches,
+    starred: StarredProgrammes,
+    favourites: Favourites,
+    history: WatchHistory,
+    users: Users,
+    metrics: Metrics,
+    health_config: HealthConfig,
 }
 
 impl AppState {
@@ -87,6 +151,10 @@ impl AppState {
             .build()
             .expect("failed to build stream HTTP client");
 
+        let recording_config = RecordingConfig::from_env();
+        let playlist_config = SourceConfig::from_env("PLAYLIST");
+        let epg_config = SourceConfig::from_env("EPG");
+
         #[cfg(debug_assertions)]
         let (cached_playlist, cached_epg) = {
             let playlist_file = std::fs::read_to_string("./examples/playlist.m3u").unwrap();
@@ -102,7 +170,7 @@ impl AppState {
                     fetched: in_a_year,
                 }))),
                 Arc::new(RwLock::new(Some(EpgFetch {
-                    epg,
+                    epg: Arc::new(epg),
                     fetched: in_a_year,
                 }))),
             )
@@ -115,12 +183,37 @@ impl AppState {
         Self {
             cached_playlist,
             cached_epg,
-            playlist_last_attempt: Arc::new(RwLock::new(None)),
-            epg_last_attempt: Arc::new(RwLock::new(None)),
+            playlist_client: playlist_config.client(),
+            epg_client: epg_config.client(),
+            playlist_config,
+            epg_config,
+            playlist_backoff: Arc::new(RwLock::new(Backoff::default())),
+            epg_backoff: Arc::new(RwLock::new(Backoff::default())),
+            playlist_last_error: Arc::new(RwLock::new(None)),
+            epg_last_error: Arc::new(RwLock::new(None)),
             playlist_refresh_lock: Arc::new(Mutex::new(())),
             epg_refresh_lock: Arc::new(Mutex::new(())),
             client,
             stream_client,
+            proxy_config: ProxyConfig::from_env(),
+            upstream_connections: UpstreamConnections::default(),
+            shared_streams: SharedStreams::default(),
+            probe_config: ProbeConfig::from_env(),
+            stream_health: StreamHealthRegistry::default(),
+            hls_config: HlsConfig::from_env(),
+            hls_sessions: HlsSessions::default(),
+            timeshift_config: TimeshiftConfig::from_env(),
+            timeshift_buffers: TimeshiftBuffers::default(),
+            series_rules: SeriesRules::load(&recording_config.dir),
+            recordings: Recordings::load(recording_config),
+            alert_config: AlertConfig::from_env(),
+            saved_searches: SavedSearches::load(&store::data_dir()),
+            starred: StarredProgrammes::load(&store::data_dir()),
+            favourites: Favourites::load(&store::data_dir()),
+            history: WatchHistory::load(&store::data_dir()),
+            users: Users::load(&store::data_dir()),
+            metrics: Metrics::default(),
+            health_config: HealthConfig::from_env(),
         }
     }
 
@@ -132,7 +225,7 @@ impl AppState {
             .map(|fetch| fetch.playlist.clone())
     }
 
-    fn cached_epg_snapshot(&self) -> Option<Epg> {
+    fn cached_epg_snapshot(&self) -> Option<Arc<Epg>> {
         self.cached_epg
             .read()
             .unwrap()
@@ -146,7 +239,7 @@ impl AppState {
             .unwrap()
             .as_ref()
             .and_then(|fetch| {
-                if fetch.is_stale() {
+                if fetch.is_stale(self.playlist_config.cache_ttl) {
                     None
                 } else {
                     Some(fetch.playlist.clone())
@@ -154,9 +247,9 @@ impl AppState {
             })
     }
 
-    fn fresh_epg(&self) -> Option<Epg> {
+    fn fresh_epg(&self) -> Option<Arc<Epg>> {
         self.cached_epg.read().unwrap().as_ref().and_then(|fetch| {
-            if fetch.is_stale() {
+            if fetch.is_stale(self.epg_config.cache_ttl) {
                 None
             } else {
                 Some(fetch.epg.clone())
@@ -166,45 +259,50 @@ impl AppState {
 
     fn playlist_needs_refresh(&self) -> bool {
         match self.cached_playlist.read().unwrap().as_ref() {
-            Some(fetch) => fetch.is_stale(),
+            Some(fetch) => fetch.is_stale(self.playlist_config.cache_ttl),
             None => true,
         }
     }
 
     fn epg_needs_refresh(&self) -> bool {
         match self.cached_epg.read().unwrap().as_ref() {
-            Some(fetch) => fetch.is_stale(),
+            Some(fetch) => fetch.is_stale(self.epg_config.cache_ttl),
             None => true,
         }
     }
 
     fn playlist_attempt_in_backoff(&self) -> bool {
-        Self::is_attempt_in_backoff(*self.playlist_last_attempt.read().unwrap())
+        self.playlist_backoff.read().unwrap().is_active()
     }
 
     fn epg_attempt_in_backoff(&self) -> bool {
-        Self::is_attempt_in_backoff(*self.epg_last_attempt.read().unwrap())
+        self.epg_backoff.read().unwrap().is_active()
     }
 
-    fn is_attempt_in_backoff(last_attempt: Option<Instant>) -> bool {
-        last_attempt.is_some_and(|attempt| attempt.elapsed() < REFRESH_RETRY_BACKOFF)
-    }
-
-    fn mark_playlist_attempt(&self) {
-        *self.playlist_last_attempt.write().unwrap() = Some(Instant::now());
+    fn record_cache(&self, source: &str, result: &str) {
+        self.metrics.inc(
+            metrics::CACHE_REQUESTS,
+            &[("source", source), ("result", result)],
+        );
     }
 
-    fn mark_epg_attempt(&self) {
-        *self.epg_last_attempt.write().unwrap() = Some(Instant::now());
+    async fn fetch_playlist(&self) -> Result<Playlist> {
+        let mut playlist = self.fetch_cached_playlist().await?;
+        if let Some(min_failures) = self.probe_config.hide_after_failures {
+            playlist.exclude_dead_streams(&self.stream_health.dead_urls(min_failures));
+        }
+        Ok(playlist)
     }
 
-    async fn fetch_playlist(&self) -> Result<Playlist> {
+    async fn fetch_cached_playlist(&self) -> Result<Playlist> {
         if let Some(playlist) = self.fresh_playlist() {
+            self.record_cache("playlist", "hit");
             return Ok(playlist);
         }
 
         if self.playlist_attempt_in_backoff() {
             if let Some(playlist) = self.cached_playlist_snapshot() {
+                self.record_cache("playlist", "stale");
                 return Ok(playlist);
             }
             return Err(anyhow!(
@@ -215,11 +313,13 @@ impl AppState {
         let _guard = self.playlist_refresh_lock.lock().await;
 
         if let Some(playlist) = self.fresh_playlist() {
+            self.record_cache("playlist", "hit");
             return Ok(playlist);
         }
 
         if self.playlist_attempt_in_backoff() {
             if let Some(playlist) = self.cached_playlist_snapshot() {
+                self.record_cache("playlist", "stale");
                 return Ok(playlist);
             }
             return Err(anyhow!(
@@ -227,14 +327,9 @@ impl AppState {
             ));
         }
 
-        self.mark_playlist_attempt();
-        match self.fetch_playlist_uncached().await {
+        match self.refresh_playlist_locked().await {
             Ok(playlist) => {
-                let mut cached_playlist = self.cached_playlist.write().unwrap();
-                *cached_playlist = Some(PlaylistFetch {
-                    playlist: playlist.clone(),
-                    fetched: Instant::now(),
-                });
+                self.record_cache("playlist", "miss");
                 Ok(playlist)
             }
             Err(error) => {
@@ -243,6 +338,7 @@ impl AppState {
                         error = ?error,
                         "Failed to refresh playlist, serving stale cached playlist"
                     );
+                    self.record_cache("playlist", "stale");
                     Ok(playlist)
                 } else {
                     Err(error)
@@ -251,8 +347,58 @@ impl AppState {
         }
     }
 
+    /// Refreshes the playlist now, ignoring the cache TTL and backoff. Waits for a refresh
+    /// already in progress and uses its result if it succeeded.
+    async fn force_refresh_playlist(&self) -> Result<Playlist> {
+        let requested = Instant::now();
+        let _guard = self.playlist_refresh_lock.lock().await;
+        let refreshed_meanwhile = self
+            .playlist_backoff
+            .read()
+            .unwrap()
+            .last_attempt
+            .is_some_and(|attempt| attempt >= requested)
+            && self.playlist_last_error.read().unwrap().is_none();
+        if refreshed_meanwhile {
+            if let Some(fetch) = self.cached_playlist.read().unwrap().as_ref() {
+                return Ok(fetch.playlist.clone());
+            }
+        }
+        self.refresh_playlist_locked().await
+    }
+
+    /// Fetches and caches the playlist. Callers must hold `playlist_refresh_lock`.
+    async fn refresh_playlist_locked(&self) -> Result<Playlist> {
+        self.playlist_backoff.write().unwrap().attempt();
+        match self.fetch_playlist_uncached().await {
+            Ok(playlist) => {
+                let mut cached_playlist = self.cached_playlist.write().unwrap();
+                *cached_playlist = Some(PlaylistFetch {
+                    playlist: playlist.clone(),
+                    fetched: Instant::now(),
+                });
+                *self.playlist_last_error.write().unwrap() = None;
+                self.playlist_backoff.write().unwrap().succeeded();
+                Ok(playlist)
+            }
+            Err(error) => {
+                *self.playlist_last_error.write().unwrap() = Some(FetchError::new(&error));
+                let mut backoff = self.playlist_backoff.write().unwrap();
+                let retry_in = backoff.failed(&self.playlist_config);
+                tracing::warn!(
+                    failures = backoff.failures,
+                    retry_in_secs = retry_in.as_secs(),
+                    "Playlist refresh failed, backing off"
+                );
+                Err(error)
+            }
+        }
+    }
+
     async fn fetch_playlist_uncached(&self) -> Result<Playlist> {
-        let playlist_content = self.read_source_text("M3U_PATH").await?;
+        let playlist_content = self
+            .read_source_text("M3U_PATH", "playlist", &self.playlist_client)
+            .await?;
         let playlist_content = strip_utf8_bom(&playlist_content);
         if !playlist_content.trim_start().starts_with("#EXTM3U") {
             return Err(anyhow!(
@@ -261,9 +407,14 @@ impl AppState {
             ));
         }
 
-        let mut playlist: Playlist = playlist_content
-            .parse()
-            .context("failed to parse playlist response")?;
+        let parse_started = Instant::now();
+        let playlist = playlist_content.parse::<Playlist>();
+        self.metrics.observe(
+            metrics::PARSE_DURATION,
+            &[("source", "playlist")],
+            parse_started.elapsed().as_secs_f64(),
+        );
+        let mut playlist = playlist.context("failed to parse playlist response")?;
         playlist.exclude_groups(GROUPS_TO_EXCLUDE.to_vec());
         playlist.exclude_containing(SNIPPETS_TO_EXCLUDE.to_vec());
         playlist.exclude_all_extensions();
@@ -275,13 +426,15 @@ impl AppState {
         Ok(playlist)
     }
 
-    async fn fetch_epg(&self) -> Result<Epg> {
+    async fn fetch_epg(&self) -> Result<Arc<Epg>> {
         if let Some(epg) = self.fresh_epg() {
+            self.record_cache("epg", "hit");
             return Ok(epg);
         }
 
         if self.epg_attempt_in_backoff() {
             if let Some(epg) = self.cached_epg_snapshot() {
+                self.record_cache("epg", "stale");
                 return Ok(epg);
             }
             return Err(anyhow!(
@@ -292,11 +445,13 @@ impl AppState {
         let _guard = self.epg_refresh_lock.lock().await;
 
         if let Some(epg) = self.fresh_epg() {
+            self.record_cache("epg", "hit");
             return Ok(epg);
         }
 
         if self.epg_attempt_in_backoff() {
             if let Some(epg) = self.cached_epg_snapshot() {
+                self.record_cache("epg", "stale");
                 return Ok(epg);
             }
             return Err(anyhow!(
@@ -304,19 +459,15 @@ impl AppState {
             ));
         }
 
-        self.mark_epg_attempt();
-        match self.fetch_epg_uncached().await {
+        match self.refresh_epg_locked().await {
             Ok(epg) => {
-                let mut cached_epg = self.cached_epg.write().unwrap();
-                *cached_epg = Some(EpgFetch {
-                    epg: epg.clone(),
-                    fetched: Instant::now(),
-                });
+                self.record_cache("epg", "miss");
                 Ok(epg)
             }
             Err(error) => {
                 if let Some(epg) = self.cached_epg_snapshot() {
                     tracing::warn!(error = ?error, "Failed to refresh EPG, serving stale cached EPG");
+                    self.record_cache("epg", "stale");
                     Ok(epg)
                 } else {
                     Err(error)
@@ -325,17 +476,101 @@ impl AppState {
         }
     }
 
+    /// Refreshes the EPG now, ignoring the cache TTL and backoff. Waits for a refresh already
+    /// in progress and uses its result if it succeeded.
+    async fn force_refresh_epg(&self) -> Result<Arc<Epg>> {
+        let requested = Instant::now();
+        let _guard = self.epg_refresh_lock.lock().await;
+        let refreshed_meanwhile = self
+            .epg_backoff
+            .read()
+            .unwrap()
+            .last_attempt
+            .is_some_and(|attempt| attempt >= requested)
+            && self.epg_last_error.read().unwrap().is_none();
+        if refreshed_meanwhile {
+            if let Some(fetch) = self.cached_epg.read().unwrap().as_ref() {
+                return Ok(fetch.epg.clone());
+            }
+        }
+        self.refresh_epg_locked().await
+    }
+
+    /// Fetches and caches the EPG. Callers must hold `epg_refresh_lock`.
+    async fn refresh_epg_locked(&self) -> Result<Arc<Epg>> {
+        self.epg_backoff.write().unwrap().attempt();
+        match self.fetch_epg_uncached().await {
+            Ok(epg) => {
+                let epg = Arc::new(epg);
+                *self.cached_epg.write().unwrap() = Some(EpgFetch {
+                    epg: epg.clone(),
+                    fetched: Instant::now(),
+                });
+                *self.epg_last_error.write().unwrap() = None;
+                self.epg_backoff.write().unwrap().succeeded();
+                // Both scan the whole EPG and write JSON, so keep them off the runtime
+                // and out from under the refresh lock.
+                let (app_state, refreshed) = (self.clone(), epg.clone());
+                tokio::task::spawn_blocking(move || {
+                    series::evaluate_rules(&app_state, &refreshed);
+                    alerts::evaluate_saved_searches(&app_state, &refreshed);
+                });
+                Ok(epg)
+            }
+            Err(error) => {
+                *self.epg_last_error.write().unwrap() = Some(FetchError::new(&error));
+                let mut backoff = self.epg_backoff.write().unwrap();
+                let retry_in = backoff.failed(&self.epg_config);
+                tracing::warn!(
+                    failures = backoff.failures,
+                    retry_in_secs = retry_in.as_secs(),
+                    "EPG refresh failed, backing off"
+                );
+                Err(error)
+            }
+        }
+    }
+
     async fn fetch_epg_uncached(&self) -> Result<Epg> {
-        let epg_content = self.read_source_text("EPG_PATH").await?;
-        Epg::from_reader(epg_content.as_bytes())
-            .map_err(|error| anyhow!("failed to parse EPG response: {error}"))
+        let epg_content = self
+            .read_source_text("EPG_PATH", "epg", &self.epg_client)
+            .await?;
+        let parse_started = Instant::now();
+        let epg = Epg::from_reader(epg_content.as_bytes());
+        self.metrics.observe(
+            metrics::PARSE_DURATION,
+            &[("source", "epg")],
+            parse_started.elapsed().as_secs_f64(),
+        );
+        epg.map_err(|error| anyhow!("failed to parse EPG response: {error}"))
     }
 
-    async fn read_source_text(&self, env_name: &str) -> Result<String> {
+    /// Reads a source, recording the download in the metrics under `source`.
+    async fn read_source_text(
+        &self,
+        env_name: &str,
+        source: &str,
+        client: &Client,
+    ) -> Result<String> {
+        let started = Instant::now();
+        let text = Self::read_source_text_unmetered(env_name, client).await;
+        self.metrics.observe(
+            metrics::UPSTREAM_FETCH_DURATION,
+            &[("source", source)],
+            started.elapsed().as_secs_f64(),
+        );
+        let result = if text.is_ok() { "success" } else { "failure" };
+        self.metrics.inc(
+            metrics::UPSTREAM_FETCHES,
+            &[("source", source), ("result", result)],
+        );
+        text
+    }
+
+    async fn read_source_text_unmetered(env_name: &str, client: &Client) -> Result<String> {
         let source = std::env::var(env_name).with_context(|| format!("{env_name} is not set"))?;
         if source.starts_with("http://") || source.starts_with("https://") {
-            let response = self
-                .client
+            let response = client
                 .get(&source)
                 .send()
                 .await
@@ -376,33 +611,9 @@ async fn main() {
         tracing::warn!(error = ?error, "Initial EPG warmup failed");
     }
 
-    let app_state_clone = app_state.clone();
-    tokio::spawn(async move {
-        loop {
-            tokio::time::sleep(Duration::from_secs(5)).await;
-            if app_state_clone.playlist_needs_refresh()
-                && !app_state_clone.playlist_attempt_in_backoff()
-            {
-                tracing::info!("Playlist is stale, fetching a refresh");
-                if let Err(error) = app_state_clone.fetch_playlist().await {
-                    tracing::warn!(error = ?error, "Playlist refresh failed");
-                }
-            }
-        }
-    });
-
-    let app_state_clone = app_state.clone();
-    tokio::spawn(async move {
-        loop {
-            tokio::time::sleep(Duration::from_secs(5)).await;
-            if app_state_clone.epg_needs_refresh() && !app_state_clone.epg_attempt_in_backoff() {
-                tracing::info!("EPG is stale, fetching a refresh");
-                if let Err(error) = app_state_clone.fetch_epg().await {
-                    tracing::warn!(error = ?error, "EPG refresh failed");
-                }
-            }
-        }
-    });
+    refresh::spawn_scheduler(app_state.clone());
+    probe::spawn_prober(app_state.clone());
+    recordings::spawn_recorder(app_state.clone());
 
     let serve_index = ServiceBuilder::new()
         .layer(SetResponseHeaderLayer::if_not_present(
@@ -417,9 +628,95 @@ async fn main() {
         .route("/", get(routes::download_playlist))
         .route("/epg", get(routes::download_epg))
         .route("/search", get(routes::search))
-        .route("/proxy/*stream_path", get(proxy_stream))
+        .route("/calendar.ics", get(reminders::calendar_feed))
+        .route("/proxy/*stream_path", get(proxy::proxy_stream))
+        .route("/catchup/:channel/:start", get(catchup::catchup_stream))
+        .route("/hls/*stream_path", get(hls::hls_playlist))
+        .route("/timeshift/*stream_path", get(timeshift::timeshift_playlist))
+        .route("/api/stream-health", get(probe::stream_health))
+        .route("/api/now", get(guide::now_playing))
+        .route("/api/guide", get(guide::guide))
+        .route("/api/channels/:id/schedule", get(guide::channel_schedule))
+        .route(
+            "/api/recordings",
+            get(recordings::list_recordings).post(recordings::schedule_recording),
+        )
+        .route(
+            "/api/recordings/:id",
+            delete(recordings::cancel_recording),
+        )
+        .route(
+            "/api/recordings/:id/download",
+            get(recordings::download_recording),
+        )
+        .route(
+            "/api/series-rules",
+            get(series::list_series_rules).post(series::create_series_rule),
+        )
+        .route("/api/series-rules/:id", delete(series::delete_series_rule))
+        .route(
+            "/api/saved-searches",
+            get(alerts::list_saved_searches).post(alerts::create_saved_search),
+        )
+        .route(
+            "/api/saved-searches/:id",
+            delete(alerts::delete_saved_search),
+        )
+        .route(
+            "/api/starred",
+            get(reminders::list_starred).post(reminders::star_programme),
+        )
+        .route(
+            "/api/starred/:channel/:start",
+            delete(reminders::unstar_programme),
+        )
+        .route(
+            "/api/favourites",
+            get(favourites::list_favourites).put(favourites::set_favourites),
+        )
+        .route(
+            "/api/favourites/:id",
+            post(favourites::add_favourite).delete(favourites::remove_favourite),
+        )
+        .route(
+            "/api/history",
+            get(history::list_history).delete(history::clear_history),
+        )
+        .route("/api/history/channels", get(history::list_recent_channels))
+        .route("/api/history/stats", get(history::list_channel_stats))
+        .route("/metrics", get(metrics::metrics))
+        .route("/admin/status", get(admin::status))
+        .route("/admin/refresh/:source", post(admin::refresh))
+        .route("/api/logout", post(auth::logout))
+        .route("/api/me", get(auth::me))
+        .route("/api/me/token", post(auth::regenerate_token))
+        .route("/api/me/password", put(auth::change_password))
+        .route(
+            "/api/users",
+            get(auth::list_users).post(auth::create_user),
+        )
+        .route("/api/users/:id", delete(auth::delete_user))
+        .route_layer(middleware::from_fn_with_state(
+            app_state.clone(),
+            auth::require_auth,
+        ))
+        .route("/api/login", post(auth::login))
+        .route("/healthz", get(health::healthz))
+        .route("/readyz", get(health::readyz))
+        // Players fetch segments by the plain URLs in the playlists they were served, so
+        // these stay open. Their ids are random per session and only appear in playlists
+        // served to authenticated users.
+        .route("/hls-segment/:session_id/:segment", get(hls::hls_segment))
+        .route(
+            "/timeshift-segment/:buffer_id/:segment",
+            get(timeshift::timeshift_segment),
+        )
         .nest_service("/app", serve_dir.clone())
         .fallback_service(serve_dir)
+        .layer(middleware::from_fn_with_state(
+            app_state.clone(),
+            metrics::track_requests,
+        ))
         .with_state(app_state)
         .layer(cors_options)
         .layer(TraceLayer::new_for_http());
@@ -438,63 +735,6 @@ async fn main() {
     axum::serve(listener, app).await.unwrap()
 }
 
-async fn proxy_stream(
-    Path(stream_path): Path<String>,
-    axum::extract::State(app_state): axum::extract::State<AppState>,
-) -> Result<Response<Body>, (axum::http::StatusCode, String)> {
-    let response = app_state
-        .stream_client
-        .get(&stream_path)
-        .send()
-        .await
-        .map_err(|e| {
-            (
-                axum::http::StatusCode::BAD_GATEWAY,
-                format!("Failed to fetch stream: {}", e),
-            )
-        })?;
-
-    let status = response.status();
-    let headers = response.headers().clone();
-    let stream = response
-        .bytes_stream()
-        .map(|result| result.map_err(io::Error::other));
-
-    let mut builder = Response::builder().status(status);
-    for (name, value) in headers.iter() {
-        if should_forward_proxy_response_header(name) {
-            builder = builder.header(name, value);
-        }
-    }
-
-    builder = builder
-        .header("Access-Control-Allow-Origin", "*")
-        .header("Access-Control-Allow-Methods", "GET, OPTIONS");
-
-    let response = builder.body(Body::from_stream(stream)).map_err(|e| {
-        (
-            axum::http::StatusCode::INTERNAL_SERVER_ERROR,
-            format!("Failed to build response: {}", e),
-        )
-    })?;
-
-    Ok(response)
-}
-
-fn should_forward_proxy_response_header(name: &HeaderName) -> bool {
-    !matches!(
-        name.as_str(),
-        "connection"
-            | "keep-alive"
-            | "proxy-authenticate"
-            | "proxy-authorization"
-            | "te"
-            | "trailer"
-            | "transfer-encoding"
-            | "upgrade"
-    )
-}
-
 fn strip_utf8_bom(input: &str) -> &str {
     input.strip_prefix('\u{feff}').unwrap_or(input)
 }
@@ -0,0 +1,328 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::{
+        atomic::{AtomicI64, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Instant,
+};
+
+use axum::{
+    extract::{MatchedPath, Request, State},
+    http::{header, Response, StatusCode},
+    middleware::Next,
+    response::IntoResponse,
+};
+
+use crate::AppState;
+
+pub const UPSTREAM_FETCH_DURATION: &str = "sparrow_upstream_fetch_duration_seconds";
+pub const UPSTREAM_FETCHES: &str = "sparrow_upstream_fetches_total";
+pub const PARSE_DURATION: &str = "sparrow_parse_duration_seconds";
+pub const CACHE_REQUESTS: &str = "sparrow_cache_requests_total";
+pub const PLAYLIST_ENTRIES: &str = "sparrow_playlist_entries";
+pub const EPG_CHANNELS: &str = "sparrow_epg_channels";
+pub const EPG_PROGRAMMES: &str = "sparrow_epg_programmes";
+pub const HTTP_REQUEST_DURATION: &str = "sparrow_http_request_duration_seconds";
+pub const ACTIVE_PROXY_STREAMS: &str = "sparrow_active_proxy_streams";
+pub const UPSTREAM_CONNECTIONS: &str = "sparrow_upstream_connections";
+pub const PROXIED_BYTES: &str = "sparrow_proxied_bytes_total";
+
+/// Name, type and help text of every metric, in the order they are exposed.
+const DESCRIPTIONS: &[(&str, &str, &str)] = &[
+    (
+        UPSTREAM_FETCH_DURATION,
+        "histogram",
+        "Time spent downloading a playlist or EPG source.",
+    ),
+    (
+        UPSTREAM_FETCHES,
+        "counter",
+        "Playlist and EPG source downloads by result.",
+    ),
+    (
+        PARSE_DURATION,
+        "histogram",
+        "Time spent parsing a downloaded playlist or EPG.",
+    ),
+    (
+        CACHE_REQUESTS,
+        "counter",
+        "Playlist and EPG lookups by whether they were served fresh (hit), stale, or refreshed (miss).",
+    ),
+    (PLAYLIST_ENTRIES, "gauge", "Entries in the cached playlist."),
+    (EPG_CHANNELS, "gauge", "Channels in the cached EPG."),
+    (EPG_PROGRAMMES, "gauge", "Programmes in the cached EPG."),
+    (
+        HTTP_REQUEST_DURATION,
+        "histogram",
+        "Time until response headers, by route.",
+    ),
+    (
+        ACTIVE_PROXY_STREAMS,
+        "gauge",
+        "Clients currently reading a stream through /proxy.",
+    ),
+    (
+        UPSTREAM_CONNECTIONS,
+        "gauge",
+        "Open stream connections to the provider.",
+    ),
+    (
+        PROXIED_BYTES,
+        "counter",
+        "Bytes sent to clients through /proxy, by channel.",
+    ),
+];
+
+/// Upper bounds of the histogram buckets in seconds, from quick requests to slow
+/// provider downloads.
+const BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0,
+];
+
+type Series = (&'static str, Vec<(&'static str, String)>);
+
+#[derive(Debug, Default)]
+struct Histogram {
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+#[derive(Debug, Default)]
+struct Registry {
+    counters: BTreeMap<Series, Arc<AtomicU64>>,
+    gauges: BTreeMap<Series, Arc<AtomicI64>>,
+    histograms: BTreeMap<Series, Histogram>,
+}
+
+/// A minimal Prometheus registry rendered in the text exposition format.
+#[derive(Debug, Clone, Default)]
+pub struct Metrics(Arc<Mutex<Registry>>);
+
+fn series(name: &'static str, labels: &[(&'static str, &str)]) -> Series {
+    (
+        name,
+        labels
+            .iter()
+            .map(|(label, value)| (*label, value.to_string()))
+            .collect(),
+    )
+}
+
+impl Metrics {
+    /// The counter for `name` and `labels`, which callers on hot paths can keep around.
+    pub fn counter(&self, name: &'static str, labels: &[(&'static str, &str)]) -> Arc<AtomicU64> {
+        self.0
+            .lock()
+            .unwrap()
+            .counters
+            .entry(series(name, labels))
+            .or_default()
+            .clone()
+    }
+
+    pub fn inc(&self, name: &'static str, labels: &[(&'static str, &str)]) {
+        self.counter(name, labels).fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn gauge(&self, name: &'static str, labels: &[(&'static str, &str)]) -> Arc<AtomicI64> {
+        self.0
+            .lock()
+            .unwrap()
+            .gauges
+            .entry(series(name, labels))
+            .or_default()
+            .clone()
+    }
+
+    pub fn set(&self, name: &'static str, labels: &[(&'static str, &str)], value: usize) {
+        self.gauge(name, labels)
+            .store(value as i64, Ordering::Relaxed);
+    }
+
+    /// Increments the gauge until the returned guard is dropped.
+    pub fn track(&self, name: &'static str, labels: &[(&'static str, &str)]) -> GaugeGuard {
+        let gauge = self.gauge(name, labels);
+        gauge.fetch_add(1, Ordering::Relaxed);
+        GaugeGuard(gauge)
+    }
+
+    pub fn observe(&self, name: &'static str, labels: &[(&'static str, &str)], seconds: f64) {
+        let mut registry = self.0.lock().unwrap();
+        let histogram = registry.histograms.entry(series(name, labels)).or_default();
+        histogram.counts.resize(BUCKETS.len(), 0);
+        if let Some(bucket) = BUCKETS.iter().position(|bound| seconds <= *bound) {
+            histogram.counts[bucket] += 1;
+        }
+        histogram.sum += seconds;
+        histogram.count += 1;
+    }
+
+    fn render(&self) -> String {
+        let registry = self.0.lock().unwrap();
+        let mut out = String::new();
+        for (name, kind, help) in DESCRIPTIONS {
+            let _ = writeln!(out, "# HELP {name} {help}\n# TYPE {name} {kind}");
+            let in_family = |series: &&Series| series.0 == *name;
+            for series in registry.counters.keys().filter(in_family) {
+                let value = registry.counters[series].load(Ordering::Relaxed);
+                let _ = writeln!(out, "{name}{} {value}", render_labels(&series.1, None));
+            }
+            for series in registry.gauges.keys().filter(in_family) {
+                let value = registry.gauges[series].load(Ordering::Relaxed);
+                let _ = writeln!(out, "{name}{} {value}", render_labels(&series.1, None));
+            }
+            for series in registry.histograms.keys().filter(in_family) {
+                let histogram = &registry.histograms[series];
+                let mut cumulative = 0;
+                for (bound, count) in BUCKETS.iter().zip(&histogram.counts) {
+                    cumulative += count;
+                    let labels = render_labels(&series.1, Some(&bound.to_string()));
+                    let _ = writeln!(out, "{name}_bucket{labels} {cumulative}");
+                }
+                let labels = render_labels(&series.1, Some("+Inf"));
+                let _ = writeln!(out, "{name}_bucket{labels} {}", histogram.count);
+                let labels = render_labels(&series.1, None);
+                let _ = writeln!(out, "{name}_sum{labels} {}", histogram.sum);
+                let _ = writeln!(out, "{name}_count{labels} {}", histogram.count);
+            }
+        }
+        out
+    }
+}
+
+/// Decrements its gauge when dropped.
+#[derive(Debug)]
+pub struct GaugeGuard(Arc<AtomicI64>);
+
+impl Drop for GaugeGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+fn render_labels(labels: &[(&'static str, String)], le: Option<&str>) -> String {
+    let labels: Vec<String> = labels
+        .iter()
+        .map(|(label, value)| (*label, value.as_str()))
+        .chain(le.map(|le| ("le", le)))
+        .map(|(label, value)| format!("{label}=\"{}\"", escape_label(value)))
+        .collect();
+    if labels.is_empty() {
+        String::new()
+    } else {
+        format!("{{{}}}", labels.join(","))
+    }
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Records how long each request takes to produce its response headers, labelled by
+/// route pattern rather than path so stream URLs don't explode the series count.
+pub async fn track_requests(
+    State(app_state): State<AppState>,
+    request: Request,
+    next: Next,
+) -> Response<axum::body::Body> {
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map_or("unmatched", |path| path.as_str())
+        .to_string();
+    let method = request.method().to_string();
+    let started = Instant::now();
+    let response = next.run(request).await;
+    app_state.metrics.observe(
+        HTTP_REQUEST_DURATION,
+        &[
+            ("route", &route),
+            ("method", &method),
+            ("status", response.status().as_str()),
+        ],
+        started.elapsed().as_secs_f64(),
+    );
+    response
+}
+
+/// Prometheus scrape endpoint. Like the rest of the API it needs a token, which
+/// Prometheus can send as a bearer token.
+pub async fn metrics(State(app_state): State<AppState>) -> impl IntoResponse {
+    let metrics = &app_state.metrics;
+    if let Some(fetch) = app_state.cached_playlist.read().unwrap().as_ref() {
+        let playlist = &fetch.playlist;
+        metrics.set(PLAYLIST_ENTRIES, &[("set", "all")], playlist.entries.len());
+        metrics.set(
+            PLAYLIST_ENTRIES,
+            &[("set", "filtered")],
+            playlist.filtered_entries.len(),
+        );
+    }
+    if let Some(epg) = app_state.cached_epg_snapshot() {
+        metrics.set(EPG_CHANNELS, &[], epg.channels.len());
+        metrics.set(EPG_PROGRAMMES, &[], epg.programmes.len());
+    }
+    metrics.set(
+        UPSTREAM_CONNECTIONS,
+        &[],
+        app_state.upstream_connections.count(),
+    );
+
+    (
+        StatusCode::OK,
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        metrics.render(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_counters_gauges_and_histograms() {
+        let metrics = Metrics::default();
+        metrics.inc(
+            UPSTREAM_FETCHES,
+            &[("source", "epg"), ("result", "success")],
+        );
+        metrics.inc(
+            UPSTREAM_FETCHES,
+            &[("source", "epg"), ("result", "success")],
+        );
+        let guard = metrics.track(ACTIVE_PROXY_STREAMS, &[]);
+        metrics.observe(PARSE_DURATION, &[("source", "epg")], 0.3);
+        metrics.observe(PARSE_DURATION, &[("source", "epg")], 120.0);
+        metrics.counter(PROXIED_BYTES, &[("channel", "A \"1\"")]);
+
+        let text = metrics.render();
+        assert!(text.contains("# TYPE sparrow_upstream_fetches_total counter\n"));
+        assert!(
+            text.contains("sparrow_upstream_fetches_total{source=\"epg\",result=\"success\"} 2\n")
+        );
+        assert!(text.contains("sparrow_active_proxy_streams 1\n"));
+        assert!(
+            text.contains("sparrow_parse_duration_seconds_bucket{source=\"epg\",le=\"0.25\"} 0\n")
+        );
+        assert!(
+            text.contains("sparrow_parse_duration_seconds_bucket{source=\"epg\",le=\"0.5\"} 1\n")
+        );
+        assert!(
+            text.contains("sparrow_parse_duration_seconds_bucket{source=\"epg\",le=\"+Inf\"} 2\n")
+        );
+        assert!(text.contains("sparrow_parse_duration_seconds_sum{source=\"epg\"} 120.3\n"));
+        assert!(text.contains("sparrow_proxied_bytes_total{channel=\"A \\\"1\\\"\"} 0\n"));
+
+        drop(guard);
+        assert!(metrics
+            .render()
+            .contains("sparrow_active_proxy_streams 0\n"));
+    }
+}
@@ -1,5 +1,5 @@
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     fmt::{self, Display, Formatter},
     num::ParseIntError,
     str::FromStr,
@@ -8,6 +8,8 @@ use std::{
 use itertools::Itertools;
 use thiserror::Error;
 
+pub const FAVOURITES_GROUP: &str = "Favourites";
+
 #[derive(Debug, PartialEq, Clone)]
 pub struct PlaylistEntry {
     pub duration: i32,
@@ -17,6 +19,22 @@ pub struct PlaylistEntry {
     pub group_title: String,
     pub name: String,
     pub url: String,
+    pub http_user_agent: Option<String>,
+    pub http_referrer: Option<String>,
+    pub catchup: Option<Catchup>,
+    /// Channel number players sort and tune by.
+    pub tvg_chno: Option<String>,
+}
+
+/// The provider's archive settings for a channel, from the `catchup*` attributes.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Catchup {
+    /// `default`, `append`, `shift` or `xc` (Xtream Codes).
+    pub mode: String,
+    /// How many days back the archive reaches.
+    pub days: Option<u32>,
+    /// URL template with placeholders such as `{utc}` and `{duration}`.
+    pub source: Option<String>,
 }
 
 #[derive(Debug, Clone)]
@@ -62,11 +80,18 @@ impl Playlist {
             .collect()
     }
 
-    pub fn to_m3u(&self) -> String {
+    /// The filtered playlist, led by a "Favourites" group holding copies of `favourites`
+    /// numbered in the given order.
+    pub fn to_m3u(&self, favourites: &[&PlaylistEntry]) -> String {
+        let favourites = favourites.iter().enumerate().map(|(index, entry)| PlaylistEntry {
+            group_title: FAVOURITES_GROUP.to_string(),
+            tvg_chno: Some((index + 1).to_string()),
+            ..(*entry).clone()
+        });
         format!(
             "#EXTM3U\n{}",
-            self.filtered_entries
-                .iter()
+            favourites
+                .chain(self.filtered_entries.iter().cloned())
                 .map(|entry| entry.to_string())
                 .join("\n")
         )
@@ -93,6 +118,11 @@ impl Playlist {
         });
     }
 
+    pub fn exclude_dead_streams(&mut self, dead_urls: &HashSet<String>) {
+        self.filtered_entries
+            .retain(|entry| !dead_urls.contains(&entry.url));
+    }
+
     pub fn exclude_all_extensions(&mut self) {
         self.filtered_entries.retain(|entry| {
             entry
@@ -123,17 +153,21 @@ impl FromStr for Playlist {
         let mut entries = Vec::new();
         let mut entry_index = 1;
 
-        loop {
-            let Some(info_line) = lines.next() else {
-                break;
-            };
-            let url_line = lines
-                .next()
-                .ok_or_else(|| PlaylistParseError::IncompleteEntry {
-                    entry_index,
-                    chunk: info_line.to_string(),
-                })?;
-            let chunk = format!("{info_line}\n{url_line}");
+        while let Some(info_line) = lines.next() {
+            let mut chunk = info_line.to_string();
+            loop {
+                let line = lines
+                    .next()
+                    .ok_or_else(|| PlaylistParseError::IncompleteEntry {
+                        entry_index,
+                        chunk: chunk.clone(),
+                    })?;
+                chunk.push('\n');
+                chunk.push_str(line);
+                if !is_entry_directive(line) {
+                    break;
+                }
+            }
             let entry = PlaylistEntry::parse(entry_index, &chunk)?;
             entries.push(entry);
             entry_index += 1;
@@ -153,12 +187,29 @@ impl PlaylistEntry {
                 entry_index,
                 chunk: input.to_string(),
             })?;
-        let url_line = lines
-            .next()
-            .ok_or_else(|| PlaylistParseError::IncompleteEntry {
-                entry_index,
-                chunk: input.to_string(),
-            })?;
+        let mut http_user_agent = None;
+        let mut http_referrer = None;
+        let url_line = loop {
+            let line = lines
+                .next()
+                .ok_or_else(|| PlaylistParseError::IncompleteEntry {
+                    entry_index,
+                    chunk: input.to_string(),
+                })?;
+            if !is_entry_directive(line) {
+                break line;
+            }
+            if let Some((key, value)) = line
+                .strip_prefix("#EXTVLCOPT:")
+                .and_then(|option| option.split_once('='))
+            {
+                match key.trim() {
+                    "http-user-agent" => http_user_agent = Some(value.trim().to_string()),
+                    "http-referrer" => http_referrer = Some(value.trim().to_string()),
+                    _ => {}
+                }
+            }
+        };
 
         let info = info_line.strip_prefix("#EXTINF:").ok_or_else(|| {
             PlaylistParseError::MalformedEntry {
@@ -203,6 +254,10 @@ impl PlaylistEntry {
             group_title: attrs.get("group-title").cloned().unwrap_or_default(),
             name: name.to_string(),
             url: url_line.trim().to_string(),
+            http_user_agent,
+            http_referrer,
+            catchup: parse_catchup(&attrs),
+            tvg_chno: attrs.get("tvg-chno").filter(|chno| !chno.is_empty()).cloned(),
         })
     }
 }
@@ -211,18 +266,65 @@ impl Display for PlaylistEntry {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         write!(
             f,
-            "#EXTINF:{} xui-id=\"{{XUI_ID}}\" tvg-id=\"{}\" tvg-name=\"{}\" tvg-logo=\"{}\" group-title=\"{}\",{}\n{}",
+            "#EXTINF:{} xui-id=\"{{XUI_ID}}\" tvg-id=\"{}\" tvg-name=\"{}\" tvg-logo=\"{}\" group-title=\"{}\"",
             self.duration,
             self.tvg_id,
             self.tvg_name,
             self.tvg_logo,
             self.group_title,
-            self.name,
-            self.url
-        )
+        )?;
+        if let Some(chno) = &self.tvg_chno {
+            write!(f, " tvg-chno=\"{chno}\"")?;
+        }
+        if let Some(catchup) = &self.catchup {
+            write!(f, " catchup=\"{}\"", catchup.mode)?;
+            if let Some(days) = catchup.days {
+                write!(f, " catchup-days=\"{days}\"")?;
+            }
+            if let Some(source) = &catchup.source {
+                write!(f, " catchup-source=\"{source}\"")?;
+            }
+        }
+        write!(f, ",{}", self.name)?;
+        if let Some(user_agent) = &self.http_user_agent {
+            write!(f, "\n#EXTVLCOPT:http-user-agent={user_agent}")?;
+        }
+        if let Some(referrer) = &self.http_referrer {
+            write!(f, "\n#EXTVLCOPT:http-referrer={referrer}")?;
+        }
+        write!(f, "\n{}", self.url)
     }
 }
 
+/// Directive lines such as `#EXTVLCOPT` that sit between `#EXTINF` and the URL.
+fn is_entry_directive(line: &str) -> bool {
+    line.starts_with('#') && !line.starts_with("#EXTINF:")
+}
+
+fn parse_catchup(attrs: &HashMap<String, String>) -> Option<Catchup> {
+    let mode = attrs
+        .get("catchup")
+        .or_else(|| attrs.get("catchup-type"))
+        .filter(|mode| !mode.is_empty());
+    let days = attrs
+        .get("catchup-days")
+        .or_else(|| attrs.get("tvg-rec"))
+        .and_then(|days| days.trim().parse().ok())
+        .filter(|&days| days > 0);
+    let source = attrs
+        .get("catchup-source")
+        .filter(|source| !source.is_empty());
+    if mode.is_none() && days.is_none() && source.is_none() {
+        return None;
+    }
+
+    Some(Catchup {
+        mode: mode.map_or_else(|| "default".to_string(), |mode| mode.to_lowercase()),
+        days,
+        source: source.cloned(),
+    })
+}
+
 fn split_extinf_metadata(input: &str) -> Option<(&str, &str)> {
     let mut in_quotes = false;
     for (index, ch) in input.char_indices() {
@@ -279,9 +381,27 @@ fn parse_attributes(input: &str) -> Result<HashMap<String, String>, String> {
 }
 
 #[cfg(test)]
-mod tests {
+pub mod tests {
     use super::*;
 
+    /// A live channel in the "Sweden" group without catchup. Tests set any other fields
+    /// with struct update syntax.
+    pub fn entry(tvg_id: &str, name: &str, url: &str) -> PlaylistEntry {
+        PlaylistEntry {
+            duration: -1,
+            tvg_id: tvg_id.to_string(),
+            tvg_name: name.to_string(),
+            tvg_logo: String::new(),
+            group_title: "Sweden".to_string(),
+            name: name.to_string(),
+            url: url.to_string(),
+            http_user_agent: None,
+            http_referrer: None,
+            catchup: None,
+            tvg_chno: None,
+        }
+    }
+
     #[test]
     fn test_parse_playlist_entry() {
         let test_channel = r#"
@@ -297,7 +417,11 @@ http://abc.xyz:8080/user/pass/360
                 tvg_logo: "https://logo.com".to_string(),
                 group_title: "Sweden".to_string(),
                 name: "ABC FHD SE".to_string(),
-                url: "http://abc.xyz:8080/user/pass/360".to_string()
+                url: "http://abc.xyz:8080/user/pass/360".to_string(),
+                http_user_agent: None,
+                http_referrer: None,
+                catchup: None,
+                tvg_chno: None,
             }
         );
     }
@@ -317,11 +441,60 @@ http://abc.xyz:8080/user/pass/360
                 tvg_logo: "https://logo.com".to_string(),
                 group_title: "Sweden".to_string(),
                 name: "ABC FHD SE".to_string(),
-                url: "http://abc.xyz:8080/user/pass/360".to_string()
+                url: "http://abc.xyz:8080/user/pass/360".to_string(),
+                http_user_agent: None,
+                http_referrer: None,
+                catchup: None,
+                tvg_chno: None,
             }
         );
     }
 
+    #[test]
+    fn test_parse_playlist_entry_with_vlc_options() {
+        let playlist = r#"#EXTM3U
+#EXTINF:-1 tvg-id="ABC.se" tvg-name="ABC FHD SE" tvg-logo="https://logo.com" group-title="Sweden",ABC FHD SE
+#EXTVLCOPT:http-user-agent=VLC/3.0.20
+#EXTVLCOPT:http-referrer=https://abc.xyz/
+http://abc.xyz:8080/user/pass/360
+#EXTINF:-1 tvg-id="DEF.se" group-title="Sweden",DEF SE
+http://abc.xyz:8080/user/pass/361
+"#;
+        let playlist: Playlist = playlist.parse().unwrap();
+        assert_eq!(playlist.entries.len(), 2);
+
+        let entry = &playlist.entries[0];
+        assert_eq!(entry.url, "http://abc.xyz:8080/user/pass/360");
+        assert_eq!(entry.http_user_agent.as_deref(), Some("VLC/3.0.20"));
+        assert_eq!(entry.http_referrer.as_deref(), Some("https://abc.xyz/"));
+        assert!(entry
+            .to_string()
+            .contains("\n#EXTVLCOPT:http-referrer=https://abc.xyz/\n"));
+        assert_eq!(playlist.entries[1].http_user_agent, None);
+    }
+
+    #[test]
+    fn test_parse_playlist_entry_with_catchup() {
+        let test_channel = r#"
+#EXTINF:-1 tvg-id="ABC.se" catchup="default" catchup-days="7" catchup-source="http://abc.xyz/archive/360?start={utc}&dur={duration}" group-title="Sweden",ABC FHD SE
+http://abc.xyz:8080/user/pass/360
+        "#;
+        let entry = PlaylistEntry::parse(1, test_channel.trim()).unwrap();
+        assert_eq!(
+            entry.catchup,
+            Some(Catchup {
+                mode: "default".to_string(),
+                days: Some(7),
+                source: Some("http://abc.xyz/archive/360?start={utc}&dur={duration}".to_string()),
+            })
+        );
+        assert_eq!(PlaylistEntry::parse(1, &entry.to_string()).unwrap(), entry);
+
+        let xtream = "#EXTINF:-1 tvg-id=\"ABC.se\" tvg-rec=\"3\",ABC\nhttp://abc.xyz:8080/user/pass/360";
+        let catchup = PlaylistEntry::parse(1, xtream).unwrap().catchup.unwrap();
+        assert_eq!((catchup.mode.as_str(), catchup.days), ("default", Some(3)));
+    }
+
     #[test]
     fn test_parse_playlist_returns_error_instead_of_panicking() {
         let invalid_playlist = "#EXTM3U\n<head><title>502 Bad Gateway</title></head>\n<body>";
@@ -331,4 +504,19 @@ http://abc.xyz:8080/user/pass/360
             PlaylistParseError::MalformedEntry { entry_index: 1, .. }
         ));
     }
+
+    #[test]
+    fn test_to_m3u_lists_favourites_first() {
+        let playlist: Playlist = "#EXTM3U\n#EXTINF:-1 tvg-id=\"ABC.se\" group-title=\"Sweden\",ABC\nhttp://abc.xyz/1\n#EXTINF:-1 tvg-id=\"DEF.se\" group-title=\"Sweden\",DEF\nhttp://abc.xyz/2"
+            .parse()
+            .unwrap();
+        let m3u = playlist.to_m3u(&[&playlist.entries[1]]);
+        let entries: Playlist = m3u.parse().unwrap();
+
+        let names: Vec<_> = entries.entries.iter().map(|e| e.name.as_str()).collect();
+        assert_eq!(names, ["DEF", "ABC", "DEF"]);
+        assert_eq!(entries.entries[0].group_title, FAVOURITES_GROUP);
+        assert_eq!(entries.entries[0].tvg_chno.as_deref(), Some("1"));
+        assert_eq!(entries.entries[2].group_title, "Sweden");
+    }
 }
@@ -0,0 +1,346 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, RwLock},
+    time::{Duration, Instant},
+};
+
+use axum::{extract::State, Json};
+use chrono::{DateTime, Utc};
+use futures::StreamExt;
+use itertools::Itertools;
+use serde::Serialize;
+
+use crate::{
+    playlist::{Playlist, PlaylistEntry},
+    proxy::StreamCandidate,
+    ts::find_ts_sync,
+    AppState,
+};
+
+const DEFAULT_PROBE_TIMEOUT: Duration = Duration::from_secs(10);
+const DEFAULT_PROBE_CONCURRENCY: usize = 1;
+const PROBE_SAMPLE_DURATION: Duration = Duration::from_secs(3);
+const PROBE_SAMPLE_BYTES: usize = 2 * 1024 * 1024;
+const CONNECTION_SLOT_POLL: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone)]
+pub struct ProbeConfig {
+    /// How often every stream is probed; probing is disabled when unset.
+    pub interval: Option<Duration>,
+    pub timeout: Duration,
+    pub concurrency: usize,
+    /// Hide entries from the filtered playlist after this many failed probes in a row.
+    pub hide_after_failures: Option<u32>,
+}
+
+impl ProbeConfig {
+    pub fn from_env() -> Self {
+        let secs = |name: &str| {
+            std::env::var(name)
+                .ok()
+                .and_then(|value| value.parse().ok())
+                .filter(|&secs| secs > 0)
+                .map(Duration::from_secs)
+        };
+
+        Self {
+            interval: secs("STREAM_PROBE_INTERVAL_SECS"),
+            timeout: secs("STREAM_PROBE_TIMEOUT_SECS").unwrap_or(DEFAULT_PROBE_TIMEOUT),
+            concurrency: std::env::var("STREAM_PROBE_CONCURRENCY")
+                .ok()
+                .and_then(|value| value.parse().ok())
+                .filter(|&concurrency| concurrency > 0)
+                .unwrap_or(DEFAULT_PROBE_CONCURRENCY),
+            hide_after_failures: std::env::var("STREAM_PROBE_HIDE_AFTER")
+                .ok()
+                .and_then(|value| value.parse().ok())
+                .filter(|&failures| failures > 0),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum StreamFormat {
+    MpegTs,
+    Hls,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ProbeOutcome {
+    Alive {
+        format: StreamFormat,
+        latency: Duration,
+        bitrate_kbps: Option<u64>,
+    },
+    Dead {
+        reason: String,
+    },
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StreamHealth {
+    pub name: String,
+    pub url: String,
+    pub alive: bool,
+    pub format: Option<StreamFormat>,
+    pub latency_ms: Option<u64>,
+    pub bitrate_kbps: Option<u64>,
+    pub error: Option<String>,
+    pub consecutive_failures: u32,
+    pub checked_at: DateTime<Utc>,
+}
+
+/// Latest probe result per stream URL.
+#[derive(Debug, Clone, Default)]
+pub struct StreamHealthRegistry(Arc<RwLock<HashMap<String, StreamHealth>>>);
+
+impl StreamHealthRegistry {
+    pub fn record(&self, entry: &PlaylistEntry, outcome: ProbeOutcome) {
+        let mut records = self.0.write().unwrap();
+        let previous_failures = records
+            .get(&entry.url)
+            .map_or(0, |health| health.consecutive_failures);
+
+        let health = match outcome {
+            ProbeOutcome::Alive {
+                format,
+                latency,
+                bitrate_kbps,
+            } => StreamHealth {
+                name: entry.name.clone(),
+                url: entry.url.clone(),
+                alive: true,
+                format: Some(format),
+                latency_ms: Some(latency.as_millis() as u64),
+                bitrate_kbps,
+                error: None,
+                consecutive_failures: 0,
+                checked_at: Utc::now(),
+            },
+            ProbeOutcome::Dead { reason } => StreamHealth {
+                name: entry.name.clone(),
+                url: entry.url.clone(),
+                alive: false,
+                format: None,
+                latency_ms: None,
+                bitrate_kbps: None,
+                error: Some(reason),
+                consecutive_failures: previous_failures + 1,
+                checked_at: Utc::now(),
+            },
+        };
+        records.insert(entry.url.clone(), health);
+    }
+
+    pub fn snapshot(&self) -> Vec<StreamHealth> {
+        self.0
+            .read()
+            .unwrap()
+            .values()
+            .cloned()
+            .sorted_by(|a, b| a.name.cmp(&b.name))
+            .collect()
+    }
+
+    pub fn dead_urls(&self, min_failures: u32) -> HashSet<String> {
+        self.0
+            .read()
+            .unwrap()
+            .values()
+            .filter(|health| health.consecutive_failures >= min_failures)
+            .map(|health| health.url.clone())
+            .collect()
+    }
+}
+
+pub fn spawn_prober(app_state: AppState) {
+    let Some(interval) = app_state.probe_config.interval else {
+        return;
+    };
+
+    tokio::spawn(async move {
+        loop {
+            match app_state.cached_playlist_snapshot() {
+                Some(playlist) => probe_round(&app_state, &playlist).await,
+                None => tracing::debug!("No cached playlist yet, skipping stream probes"),
+            }
+            tokio::time::sleep(interval).await;
+        }
+    });
+}
+
+async fn probe_round(app_state: &AppState, playlist: &Playlist) {
+    let entries: Vec<&PlaylistEntry> = playlist
+        .filtered_entries
+        .iter()
+        .unique_by(|entry| &entry.url)
+        .collect();
+    let started = Instant::now();
+    tracing::info!("Probing {} streams", entries.len());
+
+    futures::stream::iter(entries)
+        .for_each_concurrent(app_state.probe_config.concurrency, |entry| async move {
+            // Wait for a free provider connection so probes never starve live viewers.
+            let _connection = loop {
+                match app_state
+                    .upstream_connections
+                    .try_open(app_state.proxy_config.max_connections)
+                {
+                    Some(connection) => break connection,
+                    None => tokio::time::sleep(CONNECTION_SLOT_POLL).await,
+                }
+            };
+            let outcome = probe_stream(app_state, entry).await;
+            if let ProbeOutcome::Dead { reason } = &outcome {
+                tracing::debug!(name = %entry.name, %reason, "Stream probe failed");
+            }
+            app_state.stream_health.record(entry, outcome);
+        })
+        .await;
+
+    tracing::info!("Finished probing streams in {:?}", started.elapsed());
+}
+
+async fn probe_stream(app_state: &AppState, entry: &PlaylistEntry) -> ProbeOutcome {
+    let timeout = app_state.probe_config.timeout;
+    let request = StreamCandidate::from_entry(entry).request(&app_state.stream_client);
+
+    let sample = tokio::time::timeout(timeout, async {
+        let started = Instant::now();
+        let response = request.send().await.map_err(|e| e.to_string())?;
+        let latency = started.elapsed();
+        if !response.status().is_success() {
+            return Err(format!("upstream returned {}", response.status()));
+        }
+
+        let mut stream = response.bytes_stream();
+        let mut data = Vec::new();
+        let mut first_byte_at = None;
+        while data.len() < PROBE_SAMPLE_BYTES
+            && first_byte_at.is_none_or(|at: Instant| at.elapsed() < PROBE_SAMPLE_DURATION)
+        {
+            match stream.next().await {
+                Some(Ok(chunk)) => {
+                    first_byte_at.get_or_insert_with(Instant::now);
+                    data.extend_from_slice(&chunk);
+                }
+                Some(Err(error)) if data.is_empty() => return Err(error.to_string()),
+                Some(Err(_)) | None => break,
+            }
+        }
+        let sample_duration = first_byte_at.map_or(Duration::ZERO, |at| at.elapsed());
+        Ok((latency, data, sample_duration))
+    })
+    .await;
+
+    match sample {
+        Ok(Ok((latency, data, sample_duration))) => {
+            classify_sample(&data, latency, sample_duration)
+        }
+        Ok(Err(reason)) => ProbeOutcome::Dead { reason },
+        Err(_) => ProbeOutcome::Dead {
+            reason: format!("timed out after {}s", timeout.as_secs()),
+        },
+    }
+}
+
+fn classify_sample(data: &[u8], latency: Duration, sample_duration: Duration) -> ProbeOutcome {
+    let text_start = String::from_utf8_lossy(&data[..data.len().min(64)]);
+    if text_start
+        .trim_start_matches('\u{feff}')
+        .trim_start()
+        .starts_with("#EXTM3U")
+    {
+        return ProbeOutcome::Alive {
+            format: StreamFormat::Hls,
+            latency,
+            bitrate_kbps: None,
+        };
+    }
+
+    if find_ts_sync(data).is_some() {
+        let bitrate_kbps = (sample_duration >= Duration::from_millis(500))
+            .then(|| (data.len() as f64 * 8.0 / sample_duration.as_secs_f64() / 1000.0) as u64);
+        return ProbeOutcome::Alive {
+            format: StreamFormat::MpegTs,
+            latency,
+            bitrate_kbps,
+        };
+    }
+
+    ProbeOutcome::Dead {
+        reason: if data.is_empty() {
+            "upstream sent no data".to_string()
+        } else {
+            "no TS sync byte or HLS manifest in response".to_string()
+        },
+    }
+}
+
+pub async fn stream_health(State(app_state): State<AppState>) -> Json<Vec<StreamHealth>> {
+    Json(app_state.stream_health.snapshot())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        playlist,
+        ts::{TS_PACKET_SIZE, TS_SYNC_BYTE},
+    };
+
+    #[test]
+    fn test_classify_sample_detects_formats() {
+        let mut ts = vec![0u8; TS_PACKET_SIZE * 4];
+        for packet in ts.chunks_mut(TS_PACKET_SIZE) {
+            packet[0] = TS_SYNC_BYTE;
+        }
+        let latency = Duration::from_millis(120);
+
+        assert_eq!(
+            classify_sample(&ts, latency, Duration::from_secs(1)),
+            ProbeOutcome::Alive {
+                format: StreamFormat::MpegTs,
+                latency,
+                bitrate_kbps: Some(6),
+            }
+        );
+        assert!(matches!(
+            classify_sample(b"#EXTM3U\n#EXT-X-VERSION:3\n", latency, Duration::ZERO),
+            ProbeOutcome::Alive {
+                format: StreamFormat::Hls,
+                ..
+            }
+        ));
+        assert!(matches!(
+            classify_sample(b"<html>Not found</html>", latency, Duration::ZERO),
+            ProbeOutcome::Dead { .. }
+        ));
+    }
+
+    #[test]
+    fn test_registry_counts_consecutive_failures() {
+        let registry = StreamHealthRegistry::default();
+        let dead = playlist::tests::entry("svt1.se", "SVT1 HD SE", "http://example.com/1");
+        let dead_outcome = || ProbeOutcome::Dead {
+            reason: "timed out".to_string(),
+        };
+
+        registry.record(&dead, dead_outcome());
+        registry.record(&dead, dead_outcome());
+        assert!(registry.dead_urls(2).contains(&dead.url));
+        assert!(!registry.dead_urls(3).contains(&dead.url));
+
+        registry.record(
+            &dead,
+            ProbeOutcome::Alive {
+                format: StreamFormat::Hls,
+                latency: Duration::from_millis(50),
+                bitrate_kbps: None,
+            },
+        );
+        assert!(registry.dead_urls(1).is_empty());
+    }
+}
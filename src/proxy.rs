@@ -0,0 +1,1072 @@
+use std::{
+    collections::{HashMap, HashSet},
+    io,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use axum::{
+    body::{Body, Bytes},
+    extract::{Path, State},
+    http::{
+        header::{
+            CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE, IF_RANGE, RANGE, REFERER, USER_AGENT,
+        },
+        HeaderMap, HeaderName, Response, StatusCode,
+    },
+    Extension,
+};
+use futures::{stream::BoxStream, StreamExt};
+use reqwest::Client;
+use tokio::sync::{broadcast, mpsc, watch};
+
+use crate::{
+    auth::CurrentUser,
+    history,
+    metrics::{self, GaugeGuard},
+    playlist::PlaylistEntry,
+    timeshift,
+    ts::{TsPacketAligner, TS_SYNC_BYTE},
+    AppState,
+};
+
+const DEFAULT_STALL_TIMEOUT: Duration = Duration::from_secs(10);
+const DEFAULT_RECONNECT_ATTEMPTS: u32 = 3;
+const RECONNECT_DELAY: Duration = Duration::from_millis(500);
+const STREAM_CHANNEL_CAPACITY: usize = 32;
+/// Chunks a shared stream consumer may fall behind by before it starts skipping data.
+const SHARED_STREAM_CAPACITY: usize = 1024;
+
+#[derive(Debug, Clone)]
+pub struct ProxyConfig {
+    /// Name snippets (lowercased) used to order duplicate entries, best first.
+    pub failover_priority: Vec<String>,
+    /// How long an upstream may go without sending bytes before it is abandoned.
+    pub stall_timeout: Duration,
+    /// How many times a dropped live stream is reconnected before failing over.
+    pub reconnect_attempts: u32,
+    /// Simultaneous upstream connections the provider allows, if limited.
+    pub max_connections: Option<usize>,
+}
+
+impl ProxyConfig {
+    pub fn from_env() -> Self {
+        let failover_priority = std::env::var("STREAM_FAILOVER_PRIORITY")
+            .map(|value| parse_priority_list(&value))
+            .unwrap_or_default();
+        let stall_timeout = std::env::var("STREAM_STALL_TIMEOUT_SECS")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .map(Duration::from_secs)
+            .unwrap_or(DEFAULT_STALL_TIMEOUT);
+        let reconnect_attempts = std::env::var("STREAM_RECONNECT_ATTEMPTS")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(DEFAULT_RECONNECT_ATTEMPTS);
+        let max_connections = std::env::var("MAX_UPSTREAM_CONNECTIONS")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .filter(|&max| max > 0);
+
+        Self {
+            failover_priority,
+            stall_timeout,
+            reconnect_attempts,
+            max_connections,
+        }
+    }
+}
+
+/// Counts open upstream stream connections so background work can stay within the
+/// provider's connection limit. Viewers are always let through.
+#[derive(Debug, Clone, Default)]
+pub struct UpstreamConnections(Arc<AtomicUsize>);
+
+#[derive(Debug)]
+pub struct UpstreamConnectionGuard(Arc<AtomicUsize>);
+
+impl UpstreamConnections {
+    pub fn count(&self) -> usize {
+        self.0.load(Ordering::SeqCst)
+    }
+
+    pub fn open(&self) -> UpstreamConnectionGuard {
+        self.0.fetch_add(1, Ordering::SeqCst);
+        UpstreamConnectionGuard(self.0.clone())
+    }
+
+    /// Opens a connection only if doing so keeps the total within `max`.
+    pub fn try_open(&self, max: Option<usize>) -> Option<UpstreamConnectionGuard> {
+        let Some(max) = max else {
+            return Some(self.open());
+        };
+        self.0
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |count| {
+                (count < max).then_some(count + 1)
+            })
+            .ok()
+            .map(|_| UpstreamConnectionGuard(self.0.clone()))
+    }
+}
+
+impl Drop for UpstreamConnectionGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct StreamCandidate {
+    pub name: String,
+    pub url: String,
+    pub user_agent: Option<String>,
+    pub referrer: Option<String>,
+}
+
+impl StreamCandidate {
+    fn from_url(url: &str) -> Self {
+        Self {
+            name: url.to_string(),
+            url: url.to_string(),
+            user_agent: None,
+            referrer: None,
+        }
+    }
+
+    pub fn from_entry(entry: &PlaylistEntry) -> Self {
+        Self {
+            name: entry.name.clone(),
+            url: entry.url.clone(),
+            user_agent: entry.http_user_agent.clone(),
+            referrer: entry.http_referrer.clone(),
+        }
+    }
+
+    /// Starts a GET for this entry with its `#EXTVLCOPT` headers applied.
+    pub fn request(&self, client: &Client) -> reqwest::RequestBuilder {
+        let mut request = client.get(&self.url);
+        if let Some(user_agent) = &self.user_agent {
+            request = request.header(USER_AGENT, user_agent);
+        }
+        if let Some(referrer) = &self.referrer {
+            request = request.header(REFERER, referrer);
+        }
+        request
+    }
+}
+
+/// The set of upstream entries a proxied request may be served from, along with the
+/// client request headers that are forwarded to whichever one is used.
+struct Upstreams {
+    client: Client,
+    candidates: Vec<StreamCandidate>,
+    request_headers: HeaderMap,
+}
+
+type UpstreamStream = BoxStream<'static, reqwest::Result<Bytes>>;
+
+/// An upstream response whose body keeps flowing across reconnects and failovers.
+pub struct UpstreamResponse {
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    pub body: BoxStream<'static, Result<Bytes, io::Error>>,
+}
+
+type UpstreamHead = Result<(StatusCode, HeaderMap), String>;
+
+/// What a shared stream consumer does when it falls too far behind the upstream.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OnLag {
+    /// Skip ahead to live, fine for anything watched as it airs.
+    Skip,
+    /// End the stream with an error, for consumers that must not silently lose data.
+    Fail,
+}
+
+/// Whether a response can be fanned out to consumers joining mid-stream. Anything with a
+/// known length, or an HLS playlist, only makes sense read from its first byte.
+fn is_shareable(headers: &HeaderMap) -> bool {
+    let is_playlist = headers
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.to_ascii_lowercase().contains("mpegurl"));
+    !headers.contains_key(CONTENT_LENGTH) && !is_playlist
+}
+
+/// One upstream connection fanned out to every local consumer of a live channel.
+#[derive(Debug)]
+struct SharedStream {
+    url: String,
+    sender: broadcast::Sender<Bytes>,
+    head: watch::Receiver<Option<UpstreamHead>>,
+    ended: AtomicBool,
+}
+
+/// Live upstreams currently being shared, keyed by URL.
+#[derive(Debug, Clone, Default)]
+pub struct SharedStreams(Arc<Mutex<HashMap<String, Arc<SharedStream>>>>);
+
+impl SharedStreams {
+    /// Subscribes to the shared upstream for `url`, connecting to it if nobody else is.
+    fn subscribe(
+        &self,
+        app_state: &AppState,
+        url: &str,
+    ) -> (Arc<SharedStream>, broadcast::Receiver<Bytes>) {
+        let mut streams = self.0.lock().unwrap();
+        if let Some(stream) = streams
+            .get(url)
+            .filter(|stream| !stream.ended.load(Ordering::SeqCst))
+        {
+            return (stream.clone(), stream.sender.subscribe());
+        }
+
+        let (sender, receiver) = broadcast::channel(SHARED_STREAM_CAPACITY);
+        let (head_tx, head) = watch::channel(None);
+        let stream = Arc::new(SharedStream {
+            url: url.to_string(),
+            sender,
+            head,
+            ended: Default::default(),
+        });
+        streams.insert(url.to_string(), stream.clone());
+        tokio::spawn(run_shared_stream(
+            app_state.clone(),
+            stream.clone(),
+            head_tx,
+        ));
+        (stream, receiver)
+    }
+
+    /// The upstream URLs currently being streamed.
+    pub fn urls(&self) -> HashSet<String> {
+        self.0
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|stream| !stream.ended.load(Ordering::SeqCst))
+            .map(|stream| stream.url.clone())
+            .collect()
+    }
+
+    /// How many consumers are reading the shared upstream for `url`.
+    pub fn consumers(&self, url: &str) -> usize {
+        self.0
+            .lock()
+            .unwrap()
+            .get(url)
+            .map_or(0, |stream| stream.sender.receiver_count())
+    }
+
+    fn remove(&self, stream: &Arc<SharedStream>) {
+        let mut streams = self.0.lock().unwrap();
+        // Marked under the lock so no one subscribes to a stream that is about to close.
+        stream.ended.store(true, Ordering::SeqCst);
+        if streams
+            .get(&stream.url)
+            .is_some_and(|current| Arc::ptr_eq(current, stream))
+        {
+            streams.remove(&stream.url);
+        }
+    }
+}
+
+async fn run_shared_stream(
+    app_state: AppState,
+    stream: Arc<SharedStream>,
+    head_tx: watch::Sender<Option<UpstreamHead>>,
+) {
+    match open_stream(&app_state, &stream.url, HeaderMap::new()).await {
+        Ok(upstream) if upstream.status.is_success() => {
+            if !is_shareable(&upstream.headers) {
+                // Consumers that subscribed before the response arrived still get all of
+                // it, nobody else may join.
+                app_state.shared_streams.remove(&stream);
+            }
+            head_tx.send_replace(Some(Ok((upstream.status, upstream.headers))));
+            let mut body = upstream.body;
+            while let Some(chunk) = body.next().await {
+                match chunk {
+                    // Fails once the last consumer has gone.
+                    Ok(chunk) => {
+                        if stream.sender.send(chunk).is_err() {
+                            break;
+                        }
+                    }
+                    Err(error) => {
+                        tracing::warn!(?error, url = %stream.url, "Shared upstream stream failed");
+                        break;
+                    }
+                }
+            }
+        }
+        Ok(upstream) => {
+            head_tx.send_replace(Some(Err(format!("upstream returned {}", upstream.status))));
+        }
+        Err(error) => {
+            head_tx.send_replace(Some(Err(format!("{error:#}"))));
+        }
+    }
+    app_state.shared_streams.remove(&stream);
+}
+
+/// Opens `url` through the shared upstream when it is a live channel from the playlist,
+/// so viewers, HLS sessions, timeshift buffers and recordings of the same channel use a
+/// single provider connection. Anything else gets its own connection via [`open_stream`].
+pub async fn open_live_stream(
+    app_state: &AppState,
+    url: &str,
+    on_lag: OnLag,
+) -> anyhow::Result<UpstreamResponse> {
+    if !is_live_channel(app_state, url) {
+        return open_stream(app_state, url, HeaderMap::new()).await;
+    }
+
+    let (stream, receiver) = app_state.shared_streams.subscribe(app_state, url);
+    let mut head = stream.head.clone();
+    // Only the upstream task may keep the sender alive, so consumers see it close.
+    drop(stream);
+    let head = head
+        .wait_for(Option::is_some)
+        .await
+        .map_err(|_| anyhow::anyhow!("shared stream stopped before connecting"))?
+        .clone()
+        .expect("waited for the upstream head");
+    let (status, mut headers) = head.map_err(|error| anyhow::anyhow!(error))?;
+    // Consumers may join mid-stream, so the upstream's length never describes their body.
+    headers.remove(CONTENT_LENGTH);
+    headers.remove(CONTENT_RANGE);
+
+    Ok(UpstreamResponse {
+        status,
+        headers,
+        body: broadcast_stream(receiver, on_lag),
+    })
+}
+
+/// Whether `url` is a channel in the filtered playlist, which only holds live streams.
+pub fn is_live_channel(app_state: &AppState, url: &str) -> bool {
+    app_state
+        .cached_playlist
+        .read()
+        .unwrap()
+        .as_ref()
+        .is_some_and(|fetch| fetch.playlist.filtered_entries.iter().any(|e| e.url == url))
+}
+
+fn broadcast_stream(
+    receiver: broadcast::Receiver<Bytes>,
+    on_lag: OnLag,
+) -> BoxStream<'static, Result<Bytes, io::Error>> {
+    futures::stream::unfold(Some(receiver), move |receiver| async move {
+        let mut receiver = receiver?;
+        loop {
+            match receiver.recv().await {
+                Ok(chunk) => return Some((Ok(chunk), Some(receiver))),
+                Err(broadcast::error::RecvError::Lagged(skipped)) if on_lag == OnLag::Fail => {
+                    let error = format!("fell behind the shared stream, lost {skipped} chunks");
+                    return Some((Err(io::Error::other(error)), None));
+                }
+                // Live TS chunks are packet aligned, so skipping some keeps the stream decodable.
+                Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                    tracing::warn!(
+                        skipped,
+                        "Shared stream consumer fell behind, skipping chunks"
+                    );
+                }
+                Err(broadcast::error::RecvError::Closed) => return None,
+            }
+        }
+    })
+    .boxed()
+}
+
+pub async fn proxy_stream(
+    Path(stream_path): Path<String>,
+    State(app_state): State<AppState>,
+    Extension(CurrentUser(user)): Extension<CurrentUser>,
+    headers: HeaderMap,
+) -> Result<Response<Body>, (StatusCode, String)> {
+    let mut watch = None;
+    let upstream = if headers.contains_key(RANGE) {
+        open_stream(
+            &app_state,
+            &stream_path,
+            forwarded_request_headers(&headers),
+        )
+        .await
+    } else {
+        let upstream = open_live_stream(&app_state, &stream_path, OnLag::Skip).await;
+        if upstream.is_ok() && is_live_channel(&app_state, &stream_path) {
+            timeshift::start_buffer(&app_state, &stream_path);
+            watch = history::start_session(&app_state, user.id, &stream_path);
+        }
+        upstream
+    }
+    .map_err(|error| {
+        (
+            StatusCode::BAD_GATEWAY,
+            format!("Failed to fetch stream: {}", error),
+        )
+    })?;
+
+    let channel = channel_id(&app_state, &stream_path).unwrap_or_else(|| "unknown".to_string());
+    let tracking = StreamTracking {
+        bytes: app_state
+            .metrics
+            .counter(metrics::PROXIED_BYTES, &[("channel", &channel)]),
+        _active: app_state.metrics.track(metrics::ACTIVE_PROXY_STREAMS, &[]),
+        _watch: watch,
+    };
+    build_response(
+        upstream.status,
+        &upstream.headers,
+        tracked_stream(upstream.body, tracking),
+    )
+}
+
+/// The playlist's EPG channel id for a stream URL.
+fn channel_id(app_state: &AppState, url: &str) -> Option<String> {
+    app_state
+        .cached_playlist
+        .read()
+        .unwrap()
+        .as_ref()?
+        .playlist
+        .entries
+        .iter()
+        .find(|e| e.url == url)
+        .map(|e| e.tvg_id.clone())
+}
+
+/// Metrics and watch history kept for as long as a client reads a proxied stream.
+struct StreamTracking {
+    bytes: Arc<AtomicU64>,
+    _active: GaugeGuard,
+    _watch: Option<history::WatchGuard>,
+}
+
+fn tracked_stream(
+    stream: BoxStream<'static, Result<Bytes, io::Error>>,
+    tracking: StreamTracking,
+) -> BoxStream<'static, Result<Bytes, io::Error>> {
+    futures::stream::unfold((stream, tracking), |(mut stream, tracking)| async move {
+        let item = stream.next().await?;
+        if let Ok(chunk) = &item {
+            tracking
+                .bytes
+                .fetch_add(chunk.len() as u64, Ordering::Relaxed);
+        }
+        Some((item, (stream, tracking)))
+    })
+    .boxed()
+}
+
+/// Opens `url` with failover to duplicate entries and reconnects for live streams.
+/// A final non-2xx upstream response is returned as-is rather than as an error.
+pub async fn open_stream(
+    app_state: &AppState,
+    url: &str,
+    request_headers: HeaderMap,
+) -> anyhow::Result<UpstreamResponse> {
+    let mut candidates = match app_state.cached_playlist_snapshot() {
+        Some(playlist) => failover_candidates(
+            &playlist.entries,
+            url,
+            &app_state.proxy_config.failover_priority,
+        ),
+        None => vec![StreamCandidate::from_url(url)],
+    };
+
+    let is_range_request = request_headers.contains_key(RANGE);
+    if is_range_request {
+        // Byte offsets only make sense against the exact entry the client asked for.
+        candidates.truncate(1);
+    }
+
+    let upstreams = Upstreams {
+        client: app_state.stream_client.clone(),
+        candidates,
+        request_headers,
+    };
+    let order: Vec<usize> = (0..upstreams.candidates.len()).collect();
+    let (index, response) = match upstreams.connect(&order).await {
+        Ok(connected) => connected,
+        Err(ConnectFailure::Response(response)) => {
+            // Nothing better to offer, pass the upstream error through as before.
+            return Ok(UpstreamResponse {
+                status: response.status(),
+                headers: response.headers().clone(),
+                body: response
+                    .bytes_stream()
+                    .map(|result| result.map_err(io::Error::other))
+                    .boxed(),
+            });
+        }
+        Err(ConnectFailure::Error(error)) => return Err(error),
+    };
+
+    let status = response.status();
+    let headers = response.headers().clone();
+    let reconnectable = !is_range_request && response.content_length().is_none();
+    let (tx, rx) = mpsc::channel(STREAM_CHANNEL_CAPACITY);
+    let connection = app_state.upstream_connections.open();
+    tokio::spawn(pump_stream(
+        connection,
+        upstreams,
+        index,
+        response.bytes_stream().boxed(),
+        reconnectable,
+        tx,
+        app_state.proxy_config.clone(),
+    ));
+
+    Ok(UpstreamResponse {
+        status,
+        headers,
+        body: receiver_stream(rx),
+    })
+}
+
+fn receiver_stream(
+    rx: mpsc::Receiver<Result<Bytes, io::Error>>,
+) -> BoxStream<'static, Result<Bytes, io::Error>> {
+    futures::stream::unfold(rx, |mut rx| async move {
+        rx.recv().await.map(|item| (item, rx))
+    })
+    .boxed()
+}
+
+pub fn build_response(
+    status: StatusCode,
+    headers: &HeaderMap,
+    stream: BoxStream<'static, Result<Bytes, io::Error>>,
+) -> Result<Response<Body>, (StatusCode, String)> {
+    let mut builder = Response::builder().status(status);
+    for (name, value) in headers.iter() {
+        if should_forward_proxy_response_header(name) {
+            builder = builder.header(name, value);
+        }
+    }
+
+    builder = builder
+        .header("Access-Control-Allow-Origin", "*")
+        .header("Access-Control-Allow-Methods", "GET, OPTIONS")
+        .header(
+            "Access-Control-Expose-Headers",
+            "Accept-Ranges, Content-Length, Content-Range",
+        );
+
+    builder.body(Body::from_stream(stream)).map_err(|e| {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Failed to build response: {}", e),
+        )
+    })
+}
+
+enum ConnectFailure {
+    Response(reqwest::Response),
+    Error(anyhow::Error),
+}
+
+impl Upstreams {
+    /// Tries each candidate in `order` and returns the first one answering with a 2xx status.
+    async fn connect(&self, order: &[usize]) -> Result<(usize, reqwest::Response), ConnectFailure> {
+        let mut last_failure = ConnectFailure::Error(anyhow::anyhow!("no stream candidates"));
+        for &index in order {
+            let candidate = &self.candidates[index];
+            match self.request(candidate).send().await {
+                Ok(response) if response.status().is_success() => return Ok((index, response)),
+                Ok(response) => {
+                    tracing::warn!(
+                        status = %response.status(),
+                        name = %candidate.name,
+                        "Upstream stream returned an error status, trying next entry"
+                    );
+                    last_failure = ConnectFailure::Response(response);
+                }
+                Err(error) => {
+                    tracing::warn!(
+                        ?error,
+                        name = %candidate.name,
+                        "Failed to connect to upstream stream, trying next entry"
+                    );
+                    last_failure = ConnectFailure::Error(error.into());
+                }
+            }
+        }
+        Err(last_failure)
+    }
+
+    /// Connects to the next working entry after `index`, wrapping around the candidates.
+    async fn failover(&self, index: usize, failure: &str) -> Option<(usize, reqwest::Response)> {
+        let order: Vec<usize> = (index + 1..self.candidates.len()).chain(0..index).collect();
+        if order.is_empty() {
+            return None;
+        }
+
+        tracing::warn!(
+            reason = %failure,
+            name = %self.candidates[index].name,
+            "Upstream stream failed mid-playback, failing over"
+        );
+        let (next_index, response) = self.connect(&order).await.ok()?;
+        tracing::info!(name = %self.candidates[next_index].name, "Failed over to next stream entry");
+        Some((next_index, response))
+    }
+
+    fn request(&self, candidate: &StreamCandidate) -> reqwest::RequestBuilder {
+        candidate
+            .request(&self.client)
+            .headers(self.request_headers.clone())
+    }
+}
+
+async fn pump_stream(
+    _connection: UpstreamConnectionGuard,
+    upstreams: Upstreams,
+    mut index: usize,
+    mut upstream: UpstreamStream,
+    reconnectable: bool,
+    tx: mpsc::Sender<Result<Bytes, io::Error>>,
+    config: ProxyConfig,
+) {
+    // Only live MPEG-TS is realigned and reconnected on drop, anything else could be
+    // corrupted by splicing a fresh upstream into it. Decided on the first chunk, until
+    // then nothing has been sent and any failure can move on to the next entry.
+    let mut aligner: Option<Option<TsPacketAligner>> = None;
+    let mut drops_without_data = 0;
+    let max_drops = config.reconnect_attempts.max(1) * upstreams.candidates.len() as u32;
+
+    loop {
+        let failure = match tokio::time::timeout(config.stall_timeout, upstream.next()).await {
+            Ok(Some(Ok(chunk))) => {
+                let aligner = aligner.get_or_insert_with(|| {
+                    (reconnectable && chunk.first() == Some(&TS_SYNC_BYTE))
+                        .then(TsPacketAligner::default)
+                });
+                let chunk = match aligner {
+                    Some(aligner) => match aligner.push(&chunk) {
+                        Some(packets) => packets,
+                        None => continue,
+                    },
+                    None => chunk,
+                };
+                drops_without_data = 0;
+                if tx.send(Ok(chunk)).await.is_err() {
+                    return;
+                }
+                continue;
+            }
+            Ok(None) if matches!(aligner, Some(None)) => return,
+            Ok(None) if aligner.is_none() => "upstream sent no data".to_string(),
+            Ok(None) => "upstream ended".to_string(),
+            Ok(Some(Err(error))) => error.to_string(),
+            Err(_) => format!("no data for {}s", config.stall_timeout.as_secs()),
+        };
+
+        if tx.is_closed() {
+            return;
+        }
+        if matches!(aligner, Some(None)) {
+            tracing::warn!(
+                reason = %failure,
+                name = %upstreams.candidates[index].name,
+                "Upstream stream failed mid-playback and can't be resumed"
+            );
+            let _ = tx.send(Err(io::Error::other(failure))).await;
+            return;
+        }
+
+        drops_without_data += 1;
+        let live_ts = match aligner.as_mut() {
+            Some(Some(aligner)) => {
+                aligner.resync();
+                true
+            }
+            _ => false,
+        };
+
+        let next = if drops_without_data > max_drops {
+            None
+        } else if live_ts && drops_without_data <= config.reconnect_attempts {
+            tracing::warn!(
+                reason = %failure,
+                name = %upstreams.candidates[index].name,
+                attempt = drops_without_data,
+                "Upstream stream dropped, reconnecting"
+            );
+            tokio::time::sleep(RECONNECT_DELAY * drops_without_data).await;
+            match upstreams.connect(&[index]).await {
+                Ok(connected) => Some(connected),
+                Err(_) => upstreams.failover(index, &failure).await,
+            }
+        } else {
+            upstreams.failover(index, &failure).await
+        };
+
+        match next {
+            Some((next_index, response)) => {
+                index = next_index;
+                upstream = response.bytes_stream().boxed();
+            }
+            None => {
+                let _ = tx
+                    .send(Err(io::Error::other(format!(
+                        "all stream entries failed after: {failure}"
+                    ))))
+                    .await;
+                return;
+            }
+        }
+    }
+}
+
+/// Builds the ordered list of streams to try for `url`: the requested entry first,
+/// followed by every other entry sharing its `tvg_id`, ranked by `priority`.
+fn failover_candidates(
+    entries: &[PlaylistEntry],
+    url: &str,
+    priority: &[String],
+) -> Vec<StreamCandidate> {
+    let requested = entries.iter().find(|entry| entry.url == url);
+    let mut candidates = vec![requested.map_or_else(
+        || StreamCandidate::from_url(url),
+        StreamCandidate::from_entry,
+    )];
+
+    let Some(requested) = requested.filter(|entry| !entry.tvg_id.is_empty()) else {
+        return candidates;
+    };
+
+    let mut alternatives: Vec<&PlaylistEntry> = entries
+        .iter()
+        .filter(|entry| entry.tvg_id == requested.tvg_id && entry.url != url)
+        .collect();
+    alternatives.sort_by_key(|entry| priority_rank(&entry.name, priority));
+
+    for entry in alternatives {
+        if candidates
+            .iter()
+            .any(|candidate| candidate.url == entry.url)
+        {
+            continue;
+        }
+        candidates.push(StreamCandidate::from_entry(entry));
+    }
+
+    candidates
+}
+
+/// The best entry for a channel according to the configured failover priority.
+pub fn preferred_entry<'a>(
+    entries: &'a [PlaylistEntry],
+    tvg_id: &str,
+    priority: &[String],
+) -> Option<&'a PlaylistEntry> {
+    entries
+        .iter()
+        .filter(|entry| !tvg_id.is_empty() && entry.tvg_id == tvg_id)
+        .min_by_key(|entry| priority_rank(&entry.name, priority))
+}
+
+pub fn priority_rank(name: &str, priority: &[String]) -> usize {
+    let name = name.to_lowercase();
+    priority
+        .iter()
+        .position(|snippet| name.contains(snippet.as_str()))
+        .unwrap_or(priority.len())
+}
+
+fn parse_priority_list(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(|snippet| snippet.trim().to_lowercase())
+        .filter(|snippet| !snippet.is_empty())
+        .collect()
+}
+
+/// Picks the client request headers that upstreams need to honour byte-range requests.
+pub fn forwarded_request_headers(headers: &HeaderMap) -> HeaderMap {
+    [RANGE, IF_RANGE]
+        .into_iter()
+        .filter_map(|name| {
+            let value = headers.get(&name)?.clone();
+            Some((name, value))
+        })
+        .collect()
+}
+
+fn should_forward_proxy_response_header(name: &HeaderName) -> bool {
+    !matches!(
+        name.as_str(),
+        "connection"
+            | "keep-alive"
+            | "proxy-authenticate"
+            | "proxy-authorization"
+            | "te"
+            | "trailer"
+            | "transfer-encoding"
+            | "upgrade"
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use super::*;
+    use crate::playlist::tests::entry;
+
+    const PACKETS_PER_REPLY: usize = 4;
+
+    /// What a test upstream sends on one connection before dropping or stalling it.
+    struct Reply {
+        marker: u8,
+        content_length: bool,
+        stall: bool,
+    }
+
+    /// Serves `replies` in order, one per connection, repeating the last one.
+    async fn upstream(replies: Vec<Reply>) -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/stream.ts", listener.local_addr().unwrap());
+        let connections = Arc::new(AtomicUsize::new(0));
+        let replies = Arc::new(replies);
+        let counter = connections.clone();
+        tokio::spawn(async move {
+            while let Ok((mut socket, _)) = listener.accept().await {
+                let count = counter.fetch_add(1, Ordering::SeqCst);
+                let replies = replies.clone();
+                tokio::spawn(async move {
+                    let reply = &replies[count.min(replies.len() - 1)];
+                    let mut request = [0; 1024];
+                    let _ = socket.read(&mut request).await;
+
+                    let mut head = "HTTP/1.1 200 OK\r\nContent-Type: video/mp2t\r\n".to_string();
+                    if reply.content_length {
+                        head += &format!("Content-Length: {}\r\n", 10 * 188);
+                    }
+                    head += "Connection: close\r\n\r\n";
+                    let mut packet = [reply.marker; 188];
+                    packet[0] = TS_SYNC_BYTE;
+                    let _ = socket.write_all(head.as_bytes()).await;
+                    let _ = socket.write_all(&packet.repeat(PACKETS_PER_REPLY)).await;
+                    if reply.stall {
+                        tokio::time::sleep(Duration::from_secs(60)).await;
+                    }
+                });
+            }
+        });
+        (url, connections)
+    }
+
+    /// Connects to `urls` the way `open_stream` does and starts pumping the body.
+    async fn pump(
+        urls: &[&str],
+        reconnect_attempts: u32,
+    ) -> mpsc::Receiver<Result<Bytes, io::Error>> {
+        let upstreams = Upstreams {
+            client: Client::new(),
+            candidates: urls
+                .iter()
+                .map(|url| StreamCandidate::from_url(url))
+                .collect(),
+            request_headers: HeaderMap::new(),
+        };
+        let order: Vec<usize> = (0..urls.len()).collect();
+        let Ok((index, response)) = upstreams.connect(&order).await else {
+            panic!("test upstream refused the connection");
+        };
+        let reconnectable = response.content_length().is_none();
+        let config = ProxyConfig {
+            failover_priority: Vec::new(),
+            stall_timeout: Duration::from_millis(200),
+            reconnect_attempts,
+            max_connections: None,
+        };
+        let (tx, rx) = mpsc::channel(STREAM_CHANNEL_CAPACITY);
+        tokio::spawn(pump_stream(
+            UpstreamConnections::default().open(),
+            upstreams,
+            index,
+            response.bytes_stream().boxed(),
+            reconnectable,
+            tx,
+            config,
+        ));
+        rx
+    }
+
+    /// The markers of the packets received until `count` arrived or the stream failed.
+    async fn markers(rx: &mut mpsc::Receiver<Result<Bytes, io::Error>>, count: usize) -> Vec<u8> {
+        let mut markers = Vec::new();
+        while markers.len() < count {
+            let next = tokio::time::timeout(Duration::from_secs(10), rx.recv()).await;
+            let Ok(Some(Ok(chunk))) = next else {
+                break;
+            };
+            markers.extend(chunk.chunks(188).map(|packet| packet[1]));
+        }
+        markers
+    }
+
+    #[test]
+    fn test_failover_candidates_orders_duplicates_by_priority() {
+        let entries = vec![
+            entry("svt1.se", "SVT1 HD SE", "http://example.com/1"),
+            entry("svt1.se", "SVT1 Backup", "http://example.com/2"),
+            entry("svt2.se", "SVT2 FHD SE", "http://example.com/3"),
+            entry("svt1.se", "SVT1 FHD SE", "http://example.com/4"),
+        ];
+        let priority = parse_priority_list("FHD, HD");
+
+        let candidates = failover_candidates(&entries, "http://example.com/2", &priority);
+        let urls: Vec<&str> = candidates.iter().map(|c| c.url.as_str()).collect();
+        assert_eq!(
+            urls,
+            vec![
+                "http://example.com/2",
+                "http://example.com/4",
+                "http://example.com/1"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_forwarded_request_headers_only_keeps_range_headers() {
+        let mut headers = HeaderMap::new();
+        headers.insert(RANGE, "bytes=100-".parse().unwrap());
+        headers.insert(IF_RANGE, "\"etag\"".parse().unwrap());
+        headers.insert(USER_AGENT, "Browser".parse().unwrap());
+        headers.insert("cookie", "session=1".parse().unwrap());
+
+        let forwarded = forwarded_request_headers(&headers);
+        assert_eq!(forwarded.len(), 2);
+        assert_eq!(forwarded.get(RANGE).unwrap(), "bytes=100-");
+        assert_eq!(forwarded.get(IF_RANGE).unwrap(), "\"etag\"");
+    }
+
+    #[test]
+    fn test_failover_candidates_unknown_url_has_no_alternatives() {
+        let entries = vec![entry("svt1.se", "SVT1 HD SE", "http://example.com/1")];
+
+        let candidates = failover_candidates(&entries, "http://other.com/1", &[]);
+        assert_eq!(candidates.len(), 1);
+        assert_eq!(candidates[0].url, "http://other.com/1");
+    }
+
+    #[test]
+    fn test_only_endless_streams_are_shared() {
+        let headers = |pairs: &[(HeaderName, &str)]| {
+            pairs
+                .iter()
+                .map(|(name, value)| (name.clone(), value.parse().unwrap()))
+                .collect::<HeaderMap>()
+        };
+        assert!(is_shareable(&headers(&[(CONTENT_TYPE, "video/mp2t")])));
+        assert!(!is_shareable(&headers(&[
+            (CONTENT_TYPE, "video/mp2t"),
+            (CONTENT_LENGTH, "1024")
+        ])));
+        assert!(!is_shareable(&headers(&[(
+            CONTENT_TYPE,
+            "application/vnd.apple.mpegURL"
+        )])));
+    }
+
+    #[tokio::test]
+    async fn test_lagging_consumer_fails_or_skips() {
+        let (sender, _) = broadcast::channel(2);
+        let mut failing = broadcast_stream(sender.subscribe(), OnLag::Fail);
+        let mut skipping = broadcast_stream(sender.subscribe(), OnLag::Skip);
+        for chunk in [&b"1"[..], b"2", b"3"] {
+            sender.send(Bytes::from_static(chunk)).unwrap();
+        }
+        drop(sender);
+
+        assert!(failing.next().await.unwrap().is_err());
+        assert!(failing.next().await.is_none());
+        assert_eq!(skipping.next().await.unwrap().unwrap(), "2");
+        assert_eq!(skipping.next().await.unwrap().unwrap(), "3");
+        assert!(skipping.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_dropped_upstream_continues_from_failover_entry() {
+        let (primary, primary_connections) = upstream(vec![Reply {
+            marker: 1,
+            content_length: false,
+            stall: false,
+        }])
+        .await;
+        let (backup, _) = upstream(vec![Reply {
+            marker: 2,
+            content_length: false,
+            stall: true,
+        }])
+        .await;
+
+        let mut rx = pump(&[&primary, &backup], 0).await;
+        let mut expected = vec![1; PACKETS_PER_REPLY];
+        expected.extend([2; PACKETS_PER_REPLY]);
+        assert_eq!(markers(&mut rx, 2 * PACKETS_PER_REPLY).await, expected);
+        assert_eq!(primary_connections.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_stalled_upstream_is_reconnected() {
+        let (url, connections) = upstream(vec![
+            Reply {
+                marker: 1,
+                content_length: false,
+                stall: true,
+            },
+            Reply {
+                marker: 2,
+                content_length: false,
+                stall: true,
+            },
+        ])
+        .await;
+
+        let mut rx = pump(&[&url], 1).await;
+        let mut expected = vec![1; PACKETS_PER_REPLY];
+        expected.extend([2; PACKETS_PER_REPLY]);
+        assert_eq!(markers(&mut rx, 2 * PACKETS_PER_REPLY).await, expected);
+        assert_eq!(connections.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_sized_response_is_never_reconnected() {
+        let (primary, primary_connections) = upstream(vec![Reply {
+            marker: 1,
+            content_length: true,
+            stall: false,
+        }])
+        .await;
+        let (backup, backup_connections) = upstream(vec![Reply {
+            marker: 2,
+            content_length: false,
+            stall: true,
+        }])
+        .await;
+
+        let mut rx = pump(&[&primary, &backup], 1).await;
+        assert_eq!(
+            markers(&mut rx, PACKETS_PER_REPLY).await,
+            vec![1; PACKETS_PER_REPLY]
+        );
+        assert!(matches!(rx.recv().await, Some(Err(_))));
+        assert!(rx.recv().await.is_none());
+        assert_eq!(primary_connections.load(Ordering::SeqCst), 1);
+        assert_eq!(backup_connections.load(Ordering::SeqCst), 0);
+    }
+}
@@ -0,0 +1,677 @@
+use std::{
+    collections::{HashMap, HashSet},
+    path::PathBuf,
+    sync::{Arc, Mutex, RwLock},
+    time::Duration,
+};
+
+use anyhow::{anyhow, Context, Result};
+use axum::{
+    body::Body,
+    extract::{Path, State},
+    http::{Request, StatusCode},
+    response::{IntoResponse, Response},
+    Extension, Json,
+};
+use chrono::{DateTime, FixedOffset, Utc};
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use tokio::{io::AsyncWriteExt, sync::Notify};
+use tower::ServiceExt;
+use tower_http::services::ServeFile;
+
+use crate::{
+    auth::{legacy_owner, CurrentUser},
+    epg::Programme,
+    proxy::{self, OnLag},
+    series,
+    store::{load_json, save_json},
+    AppState,
+};
+
+const DEFAULT_PADDING_BEFORE: Duration = Duration::from_secs(60);
+const DEFAULT_PADDING_AFTER: Duration = Duration::from_secs(5 * 60);
+const SCHEDULER_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone)]
+pub struct RecordingConfig {
+    pub dir: PathBuf,
+    pub padding_before: Duration,
+    pub padding_after: Duration,
+}
+
+impl RecordingConfig {
+    pub fn from_env() -> Self {
+        let secs = |name: &str| {
+            std::env::var(name)
+                .ok()
+                .and_then(|value| value.parse().ok())
+                .map(Duration::from_secs)
+        };
+
+        Self {
+            dir: std::env::var("RECORDINGS_DIR")
+                .unwrap_or_else(|_| "./recordings".to_string())
+                .into(),
+            padding_before: secs("RECORDING_PADDING_BEFORE_SECS").unwrap_or(DEFAULT_PADDING_BEFORE),
+            padding_after: secs("RECORDING_PADDING_AFTER_SECS").unwrap_or(DEFAULT_PADDING_AFTER),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum RecordingStatus {
+    Scheduled,
+    Recording,
+    Completed,
+    Failed,
+    Cancelled,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Recording {
+    pub id: u64,
+    #[serde(default = "legacy_owner")]
+    pub user_id: u64,
+    pub channel: String,
+    pub channel_name: String,
+    pub title: String,
+    pub desc: String,
+    pub programme_start: DateTime<FixedOffset>,
+    pub programme_stop: DateTime<FixedOffset>,
+    /// Programme start minus padding, when recording actually begins.
+    pub start: DateTime<Utc>,
+    /// Programme stop plus padding, when recording actually ends.
+    pub stop: DateTime<Utc>,
+    pub url: String,
+    pub status: RecordingStatus,
+    pub error: Option<String>,
+    pub file_name: String,
+    pub bytes_written: u64,
+    /// The series rule that scheduled this recording, if any.
+    #[serde(default)]
+    pub series_rule: Option<u64>,
+    /// Identifies the episode for deduplication, see [`episode_key`].
+    #[serde(default)]
+    pub episode: Option<String>,
+}
+
+impl Recording {
+    fn overlaps(&self, start: DateTime<Utc>, stop: DateTime<Utc>) -> bool {
+        self.start < stop && start < self.stop
+    }
+
+    pub fn is_pending(&self) -> bool {
+        matches!(
+            self.status,
+            RecordingStatus::Scheduled | RecordingStatus::Recording
+        )
+    }
+
+    /// Whether this recording covers the same episode of the same show as `programme`.
+    pub fn is_same_episode(&self, programme: &Programme) -> bool {
+        self.episode.is_some()
+            && self.episode == episode_key(programme)
+            && self.title.to_lowercase() == programme.title.to_lowercase()
+    }
+}
+
+/// Recording schedules persisted as JSON in the recordings directory.
+#[derive(Debug, Clone)]
+pub struct Recordings {
+    config: RecordingConfig,
+    entries: Arc<RwLock<Vec<Recording>>>,
+    active: Arc<Mutex<HashMap<u64, Arc<Notify>>>>,
+}
+
+impl Recordings {
+    pub fn load(config: RecordingConfig) -> Self {
+        let mut entries: Vec<Recording> = load_json(&config.dir.join("recordings.json"));
+
+        // Recordings that were running when we stopped resume if their window is still open.
+        for recording in entries
+            .iter_mut()
+            .filter(|r| r.status == RecordingStatus::Recording)
+        {
+            recording.status = RecordingStatus::Scheduled;
+        }
+
+        Self {
+            config,
+            entries: Arc::new(RwLock::new(entries)),
+            active: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    pub fn list(&self) -> Vec<Recording> {
+        let mut recordings = self.entries.read().unwrap().clone();
+        recordings.sort_by_key(|r| r.start);
+        recordings
+    }
+
+    pub fn get(&self, id: u64) -> Option<Recording> {
+        self.entries
+            .read()
+            .unwrap()
+            .iter()
+            .find(|r| r.id == id)
+            .cloned()
+    }
+
+    pub fn config(&self) -> &RecordingConfig {
+        &self.config
+    }
+
+    fn file_path(&self, recording: &Recording) -> PathBuf {
+        self.config.dir.join(&recording.file_name)
+    }
+
+    pub fn insert(&self, mut recording: Recording) -> Recording {
+        let mut entries = self.entries.write().unwrap();
+        recording.id = entries.iter().map(|r| r.id).max().unwrap_or(0) + 1;
+        recording.file_name = format!("{}-{}.ts", recording.id, file_slug(&recording.title));
+        entries.push(recording.clone());
+        drop(entries);
+        self.save();
+        recording
+    }
+
+    pub fn update(&self, id: u64, update: impl FnOnce(&mut Recording)) {
+        if let Some(recording) = self
+            .entries
+            .write()
+            .unwrap()
+            .iter_mut()
+            .find(|r| r.id == id)
+        {
+            update(recording);
+        }
+        self.save();
+    }
+
+    /// Deletes a finished recording, file and all.
+    pub fn delete(&self, recording: &Recording) -> std::io::Result<()> {
+        let path = self.file_path(recording);
+        if let Err(error) = std::fs::remove_file(&path) {
+            if error.kind() != std::io::ErrorKind::NotFound {
+                tracing::error!(?error, "Failed to delete {}", path.display());
+                return Err(error);
+            }
+        }
+        self.entries
+            .write()
+            .unwrap()
+            .retain(|r| r.id != recording.id);
+        self.save();
+        Ok(())
+    }
+
+    fn save(&self) {
+        save_json(
+            &self.config.dir.join("recordings.json"),
+            &*self.entries.read().unwrap(),
+        );
+    }
+
+    /// Pending recordings that would run alongside a new recording of `url` in
+    /// `[start, stop)` at the busiest moment, if that exceeds the provider's connection
+    /// limit. `live_urls` are the upstreams currently streamed for anything else.
+    pub fn conflicts(
+        &self,
+        url: &str,
+        start: DateTime<Utc>,
+        stop: DateTime<Utc>,
+        live_urls: &HashSet<String>,
+        max_connections: Option<usize>,
+    ) -> Vec<Recording> {
+        let Some(max_connections) = max_connections else {
+            return Vec::new();
+        };
+        let overlapping: Vec<Recording> = self
+            .entries
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|r| r.is_pending() && r.overlaps(start, stop))
+            .cloned()
+            .collect();
+
+        let peak = peak_connections(&overlapping, url, start, stop, live_urls, Utc::now());
+        if peak <= max_connections {
+            Vec::new()
+        } else {
+            overlapping
+        }
+    }
+}
+
+/// The most upstream connections open at once within `[start, stop)` with a recording
+/// of `url` added. Recordings of the same URL share a connection, and streams that are
+/// live at `now` count for any part of the window that has already begun.
+fn peak_connections(
+    recordings: &[Recording],
+    url: &str,
+    start: DateTime<Utc>,
+    stop: DateTime<Utc>,
+    live_urls: &HashSet<String>,
+    now: DateTime<Utc>,
+) -> usize {
+    // Connections can only peak when something starts, so checking those instants suffices.
+    std::iter::once(start)
+        .chain(recordings.iter().map(|r| r.start.max(start)))
+        .filter(|&instant| instant < stop)
+        .map(|instant| {
+            let mut urls: HashSet<&str> = recordings
+                .iter()
+                .filter(|r| r.start <= instant && instant < r.stop)
+                .map(|r| r.url.as_str())
+                .collect();
+            urls.insert(url);
+            if instant <= now {
+                urls.extend(live_urls.iter().map(String::as_str));
+            }
+            urls.len()
+        })
+        .max()
+        .unwrap_or(0)
+}
+
+fn file_slug(title: &str) -> String {
+    let slug: String = title
+        .chars()
+        .map(|c| if c.is_alphanumeric() { c } else { '-' })
+        .collect();
+    let slug = slug
+        .split('-')
+        .filter(|part| !part.is_empty())
+        .collect::<Vec<_>>()
+        .join("-");
+    if slug.is_empty() {
+        "recording".to_string()
+    } else {
+        slug.chars().take(60).collect()
+    }
+}
+
+pub fn spawn_recorder(app_state: AppState) {
+    tokio::spawn(async move {
+        loop {
+            start_due_recordings(&app_state);
+            tokio::time::sleep(SCHEDULER_INTERVAL).await;
+        }
+    });
+}
+
+fn start_due_recordings(app_state: &AppState) {
+    let now = Utc::now();
+    for recording in app_state.recordings.list() {
+        if recording.status != RecordingStatus::Scheduled || recording.start > now {
+            continue;
+        }
+        if recording.stop <= now {
+            app_state.recordings.update(recording.id, |r| {
+                r.status = RecordingStatus::Failed;
+                r.error = Some("missed, the server was not running".to_string());
+            });
+            continue;
+        }
+
+        let cancel = Arc::new(Notify::new());
+        app_state
+            .recordings
+            .active
+            .lock()
+            .unwrap()
+            .insert(recording.id, cancel.clone());
+        app_state.recordings.update(recording.id, |r| {
+            r.status = RecordingStatus::Recording;
+        });
+        tokio::spawn(run_recording(app_state.clone(), recording, cancel));
+    }
+}
+
+async fn run_recording(app_state: AppState, recording: Recording, cancel: Arc<Notify>) {
+    tracing::info!(id = recording.id, title = %recording.title, "Starting recording");
+    let result = record(&app_state, &recording, &cancel).await;
+    app_state
+        .recordings
+        .active
+        .lock()
+        .unwrap()
+        .remove(&recording.id);
+
+    app_state.recordings.update(recording.id, |r| match result {
+        Ok(RecordOutcome::Finished) => r.status = RecordingStatus::Completed,
+        Ok(RecordOutcome::Cancelled) => r.status = RecordingStatus::Cancelled,
+        Err(error) => {
+            tracing::warn!(?error, id = r.id, "Recording failed");
+            r.status = RecordingStatus::Failed;
+            r.error = Some(format!("{error:#}"));
+        }
+    });
+    tracing::info!(id = recording.id, "Recording stopped");
+
+    if let Some(rule_id) = recording.series_rule {
+        series::prune_recordings(&app_state, rule_id);
+    }
+}
+
+enum RecordOutcome {
+    Finished,
+    Cancelled,
+}
+
+async fn record(
+    app_state: &AppState,
+    recording: &Recording,
+    cancel: &Notify,
+) -> Result<RecordOutcome> {
+    let remaining = (recording.stop - Utc::now())
+        .to_std()
+        .context("recording window has already passed")?;
+    let deadline = tokio::time::Instant::now() + remaining;
+
+    // A recording with a silent gap is worse than one marked failed.
+    let upstream = proxy::open_live_stream(app_state, &recording.url, OnLag::Fail).await?;
+    if !upstream.status.is_success() {
+        return Err(anyhow!("upstream returned {}", upstream.status));
+    }
+
+    tokio::fs::create_dir_all(&app_state.recordings.config.dir).await?;
+    let path = app_state.recordings.file_path(recording);
+    let mut file = tokio::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .await
+        .with_context(|| format!("failed to open {}", path.display()))?;
+
+    let mut body = upstream.body;
+    let mut bytes_written = 0;
+    let result = loop {
+        tokio::select! {
+            _ = tokio::time::sleep_until(deadline) => break Ok(RecordOutcome::Finished),
+            _ = cancel.notified() => break Ok(RecordOutcome::Cancelled),
+            chunk = body.next() => match chunk {
+                Some(Ok(chunk)) => {
+                    if let Err(error) = file.write_all(&chunk).await {
+                        break Err(anyhow!(error).context("failed to write recording"));
+                    }
+                    bytes_written += chunk.len() as u64;
+                }
+                Some(Err(error)) => break Err(anyhow!(error).context("stream failed mid-recording")),
+                None => break Err(anyhow!("stream ended before the programme did")),
+            },
+        }
+    };
+    file.flush().await?;
+
+    app_state.recordings.update(recording.id, |r| {
+        r.bytes_written += bytes_written;
+    });
+    result
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ScheduleRecordingRequest {
+    /// EPG channel id (the playlist's `tvg_id`).
+    channel: String,
+    start: DateTime<FixedOffset>,
+    padding_before_secs: Option<u64>,
+    padding_after_secs: Option<u64>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ConflictResponse {
+    error: String,
+    conflicts: Vec<Recording>,
+}
+
+pub async fn list_recordings(
+    State(app_state): State<AppState>,
+    Extension(CurrentUser(user)): Extension<CurrentUser>,
+) -> Json<Vec<Recording>> {
+    let mut recordings = app_state.recordings.list();
+    recordings.retain(|r| user.can_manage(r.user_id));
+    Json(recordings)
+}
+
+pub async fn schedule_recording(
+    State(app_state): State<AppState>,
+    Extension(CurrentUser(user)): Extension<CurrentUser>,
+    Json(request): Json<ScheduleRecordingRequest>,
+) -> Result<(StatusCode, Json<Recording>), Response> {
+    let epg = app_state.fetch_epg().await.map_err(|e| {
+        tracing::error!("Failed to fetch EPG: {:?}", e);
+        (StatusCode::SERVICE_UNAVAILABLE, "Failed to fetch EPG").into_response()
+    })?;
+    let playlist = app_state.fetch_playlist().await.map_err(|e| {
+        tracing::error!("Failed to fetch playlist: {:?}", e);
+        (StatusCode::SERVICE_UNAVAILABLE, "Failed to fetch playlist").into_response()
+    })?;
+
+    let programme = epg
+        .find(&request.channel, request.start.to_utc())
+        .ok_or_else(|| (StatusCode::NOT_FOUND, "Programme not found").into_response())?;
+    let entry = proxy::preferred_entry(
+        &playlist.entries,
+        &request.channel,
+        &app_state.proxy_config.failover_priority,
+    )
+    .ok_or_else(|| {
+        (
+            StatusCode::NOT_FOUND,
+            "No playlist entry for programme channel",
+        )
+            .into_response()
+    })?;
+
+    let config = &app_state.recordings.config;
+    let recording = new_recording(
+        programme,
+        request
+            .padding_before_secs
+            .map_or(config.padding_before, Duration::from_secs),
+        request
+            .padding_after_secs
+            .map_or(config.padding_after, Duration::from_secs),
+    );
+    if recording.stop <= Utc::now() {
+        return Err((StatusCode::BAD_REQUEST, "Programme has already ended").into_response());
+    }
+
+    let conflicts = app_state.recordings.conflicts(
+        &entry.url,
+        recording.start,
+        recording.stop,
+        &app_state.shared_streams.urls(),
+        app_state.proxy_config.max_connections,
+    );
+    if !conflicts.is_empty() {
+        return Err((
+            StatusCode::CONFLICT,
+            Json(ConflictResponse {
+                error: "Recording would exceed the upstream connection limit".to_string(),
+                conflicts,
+            }),
+        )
+            .into_response());
+    }
+
+    let channel_name = epg
+        .channels
+        .iter()
+        .find(|c| c.id == request.channel)
+        .map_or_else(|| entry.name.clone(), |c| c.display_name.clone());
+    let recording = app_state.recordings.insert(Recording {
+        user_id: user.id,
+        channel_name,
+        url: entry.url.clone(),
+        ..recording
+    });
+    tracing::info!(id = recording.id, title = %recording.title, "Scheduled recording");
+    Ok((StatusCode::CREATED, Json(recording)))
+}
+
+pub fn new_recording(
+    programme: &Programme,
+    padding_before: Duration,
+    padding_after: Duration,
+) -> Recording {
+    Recording {
+        id: 0,
+        user_id: 0,
+        channel: programme.channel.clone(),
+        channel_name: String::new(),
+        title: programme.title.clone(),
+        desc: programme.desc.clone(),
+        programme_start: programme.start,
+        programme_stop: programme.stop,
+        start: programme.start.to_utc() - padding_before,
+        stop: programme.stop.to_utc() + padding_after,
+        url: String::new(),
+        status: RecordingStatus::Scheduled,
+        error: None,
+        file_name: String::new(),
+        bytes_written: 0,
+        series_rule: None,
+        episode: episode_key(programme),
+    }
+}
+
+/// Identifies an episode across airings by its episode number. Descriptions are no use
+/// here, daily shows often repeat the same synopsis for every episode.
+pub fn episode_key(programme: &Programme) -> Option<String> {
+    programme
+        .episode_num()
+        .map(|num| num.split_whitespace().collect())
+}
+
+/// Cancels a scheduled or running recording. Finished recordings are deleted, file and all.
+pub async fn cancel_recording(
+    Path(id): Path<u64>,
+    State(app_state): State<AppState>,
+    Extension(CurrentUser(user)): Extension<CurrentUser>,
+) -> Result<StatusCode, (StatusCode, &'static str)> {
+    let recording = app_state
+        .recordings
+        .get(id)
+        .filter(|r| user.can_manage(r.user_id))
+        .ok_or((StatusCode::NOT_FOUND, "Recording not found"))?;
+
+    match recording.status {
+        RecordingStatus::Scheduled => {
+            app_state.recordings.update(id, |r| {
+                r.status = RecordingStatus::Cancelled;
+            });
+        }
+        RecordingStatus::Recording => {
+            if let Some(cancel) = app_state.recordings.active.lock().unwrap().get(&id) {
+                cancel.notify_one();
+            }
+        }
+        RecordingStatus::Completed | RecordingStatus::Failed | RecordingStatus::Cancelled => {
+            app_state.recordings.delete(&recording).map_err(|_| {
+                (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "Failed to delete recording",
+                )
+            })?;
+        }
+    }
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub async fn download_recording(
+    Path(id): Path<u64>,
+    State(app_state): State<AppState>,
+    Extension(CurrentUser(user)): Extension<CurrentUser>,
+    request: Request<Body>,
+) -> Result<Response, (StatusCode, &'static str)> {
+    let recording = app_state
+        .recordings
+        .get(id)
+        .filter(|r| user.can_manage(r.user_id))
+        .ok_or((StatusCode::NOT_FOUND, "Recording not found"))?;
+    let path = app_state.recordings.file_path(&recording);
+
+    let mut response = ServeFile::new_with_mime(&path, &"video/mp2t".parse().unwrap())
+        .oneshot(request)
+        .await
+        .map_err(|_| {
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Failed to read recording",
+            )
+        })?
+        .into_response();
+    if response.status().is_success() {
+        let disposition = format!("attachment; filename=\"{}\"", recording.file_name);
+        if let Ok(value) = disposition.parse() {
+            response.headers_mut().insert("Content-Disposition", value);
+        }
+    }
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::epg::tests::programme;
+
+    #[test]
+    fn test_new_recording_applies_padding() {
+        let recording = new_recording(
+            &programme("Rapport: Kväll!", "2024-10-17T19:30:00+02:00"),
+            Duration::from_secs(60),
+            Duration::from_secs(300),
+        );
+        assert_eq!(recording.start.to_rfc3339(), "2024-10-17T17:29:00+00:00");
+        assert_eq!(recording.stop.to_rfc3339(), "2024-10-17T18:05:00+00:00");
+        assert_eq!(file_slug(&recording.title), "Rapport-Kväll");
+    }
+
+    #[test]
+    fn test_peak_connections_counts_distinct_simultaneous_upstreams() {
+        let at = |time: &str| {
+            DateTime::parse_from_rfc3339(&format!("2024-10-17T{time}:00+00:00"))
+                .unwrap()
+                .to_utc()
+        };
+        let window = |url: &str, start: &str, stop: &str| {
+            let mut recording = new_recording(
+                &programme("Rapport", "2024-10-17T00:00:00+00:00"),
+                Duration::ZERO,
+                Duration::ZERO,
+            );
+            recording.url = url.to_string();
+            recording.start = at(start);
+            recording.stop = at(stop);
+            recording
+        };
+        let none = HashSet::new();
+        let peak = |recordings: &[Recording], url, start, stop, live_urls, now| {
+            peak_connections(recordings, url, at(start), at(stop), live_urls, at(now))
+        };
+
+        // Two back-to-back recordings never run at the same time.
+        let recordings = [window("a", "18:00", "19:00"), window("b", "19:00", "20:00")];
+        assert_eq!(peak(&recordings, "c", "18:30", "19:30", &none, "12:00"), 2);
+
+        let recordings = [window("a", "18:00", "19:00"), window("b", "18:30", "20:00")];
+        assert_eq!(peak(&recordings, "c", "18:45", "19:30", &none, "12:00"), 3);
+        assert_eq!(peak(&recordings, "c", "19:15", "19:30", &none, "12:00"), 2);
+        // Recording a channel that is already being recorded needs no extra connection.
+        assert_eq!(peak(&recordings, "a", "18:45", "19:30", &none, "12:00"), 2);
+
+        // Channels being watched right now only count once the window has begun.
+        let live = HashSet::from(["d".to_string(), "b".to_string()]);
+        assert_eq!(peak(&recordings, "c", "19:15", "19:30", &live, "12:00"), 2);
+        assert_eq!(peak(&recordings, "c", "19:15", "19:30", &live, "19:15"), 3);
+    }
+}
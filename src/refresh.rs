@@ -0,0 +1,494 @@
+use std::{
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+use chrono::{DateTime, Datelike, Local, NaiveDate, NaiveDateTime, TimeZone, Timelike};
+use rand_core::{OsRng, RngCore};
+use reqwest::Client;
+use tokio::task::JoinHandle;
+
+use crate::{AppState, APP_USER_AGENT};
+
+const DEFAULT_CACHE_TTL: Duration = Duration::from_secs(6 * 60 * 60);
+const DEFAULT_BACKOFF: Duration = Duration::from_secs(60);
+const DEFAULT_BACKOFF_MAX: Duration = Duration::from_secs(60 * 60);
+const DEFAULT_FETCH_TIMEOUT: Duration = Duration::from_secs(30);
+const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// Retry delays are shortened by up to this fraction so instances that failed together
+/// don't retry together.
+const BACKOFF_JITTER: f64 = 0.25;
+/// Longest the scheduler sleeps, so forced refreshes and clock changes are picked up.
+const MAX_SCHEDULER_SLEEP: Duration = Duration::from_secs(60);
+/// How far ahead to look for the next run of a schedule before deciding it never runs.
+const MAX_SCHEDULE_LOOKAHEAD_DAYS: i64 = 5 * 366;
+
+/// How a playlist or EPG source is cached and retried. Each setting is read from
+/// `<SOURCE>_<SETTING>` (e.g. `EPG_CACHE_TTL_SECS`), falling back to the unprefixed
+/// variable shared by both sources and then to a default.
+#[derive(Debug, Clone)]
+pub struct SourceConfig {
+    pub cache_ttl: Duration,
+    /// Delay before retrying after the first failed refresh, doubled for each further one.
+    pub backoff: Duration,
+    pub backoff_max: Duration,
+    pub fetch_timeout: Duration,
+    pub connect_timeout: Duration,
+    /// Cron-style times to refresh at regardless of the TTL, in the server's local time.
+    pub schedule: Option<Schedule>,
+}
+
+impl SourceConfig {
+    pub fn from_env(source: &str) -> Self {
+        let secs = |setting: &str, default: Duration| {
+            std::env::var(format!("{source}_{setting}"))
+                .or_else(|_| std::env::var(setting))
+                .ok()
+                .and_then(|value| value.parse().ok())
+                .map_or(default, Duration::from_secs)
+        };
+        Self {
+            cache_ttl: secs("CACHE_TTL_SECS", DEFAULT_CACHE_TTL),
+            backoff: secs("REFRESH_BACKOFF_SECS", DEFAULT_BACKOFF),
+            backoff_max: secs("REFRESH_BACKOFF_MAX_SECS", DEFAULT_BACKOFF_MAX),
+            fetch_timeout: secs("FETCH_TIMEOUT_SECS", DEFAULT_FETCH_TIMEOUT),
+            connect_timeout: secs("CONNECT_TIMEOUT_SECS", DEFAULT_CONNECT_TIMEOUT),
+            schedule: std::env::var(format!("{source}_REFRESH_SCHEDULE"))
+                .or_else(|_| std::env::var("REFRESH_SCHEDULE"))
+                .ok()
+                .and_then(|schedule| match schedule.parse() {
+                    Ok(schedule) => Some(schedule),
+                    Err(error) => {
+                        tracing::warn!(source, %error, "Ignoring invalid refresh schedule");
+                        None
+                    }
+                }),
+        }
+    }
+
+    pub fn client(&self) -> Client {
+        Client::builder()
+            .user_agent(APP_USER_AGENT)
+            .timeout(self.fetch_timeout)
+            .connect_timeout(self.connect_timeout)
+            .build()
+            .expect("failed to build HTTP client")
+    }
+
+    /// The delay after `failures` consecutive failed refreshes, where `jitter` in `0..=1`
+    /// takes up to `BACKOFF_JITTER` off it.
+    fn backoff_delay(&self, failures: u32, jitter: f64) -> Duration {
+        let doublings = failures.saturating_sub(1).min(31);
+        let delay = self
+            .backoff
+            .saturating_mul(1 << doublings)
+            .min(self.backoff_max);
+        delay.mul_f64(1.0 - BACKOFF_JITTER * jitter.clamp(0.0, 1.0))
+    }
+}
+
+/// Refresh attempts of a source and how long to wait before the next one after failures.
+#[derive(Debug, Clone, Default)]
+pub struct Backoff {
+    pub last_attempt: Option<Instant>,
+    /// Failed refreshes since the last successful one.
+    pub failures: u32,
+    retry_at: Option<Instant>,
+}
+
+impl Backoff {
+    pub fn attempt(&mut self) {
+        self.last_attempt = Some(Instant::now());
+    }
+
+    pub fn succeeded(&mut self) {
+        self.failures = 0;
+        self.retry_at = None;
+    }
+
+    /// Records a failed refresh and returns how long until the next may be tried.
+    pub fn failed(&mut self, config: &SourceConfig) -> Duration {
+        self.failures += 1;
+        let jitter = OsRng.next_u32() as f64 / u32::MAX as f64;
+        let delay = config.backoff_delay(self.failures, jitter);
+        self.retry_at = Some(Instant::now() + delay);
+        delay
+    }
+
+    /// Time left before a refresh may be retried, while backing off.
+    pub fn remaining(&self) -> Option<Duration> {
+        self.retry_at
+            .and_then(|retry_at| retry_at.checked_duration_since(Instant::now()))
+            .filter(|remaining| !remaining.is_zero())
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.remaining().is_some()
+    }
+}
+
+/// A five-field cron expression (minute, hour, day of month, month, day of week) with
+/// `*`, lists, ranges and steps, e.g. `0 4 * * *` or `*/30 6-23 * * 1-5`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Schedule {
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    /// Like cron, a day matches either field when both are restricted, and a field
+    /// starting with `*` (including steps like `*/2`) counts as unrestricted.
+    days_restricted: bool,
+    weekdays_restricted: bool,
+}
+
+fn parse_field(field: &str, min: u32, max: u32) -> Result<u64, String> {
+    let mut bits = 0;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (
+                range,
+                step.parse::<u32>()
+                    .ok()
+                    .filter(|step| *step > 0)
+                    .ok_or_else(|| format!("invalid step in {part:?}"))?,
+            ),
+            None => (part, 1),
+        };
+        let number = |value: &str| {
+            value
+                .parse::<u32>()
+                .ok()
+                .filter(|value| (min..=max).contains(value))
+                .ok_or_else(|| format!("{value:?} is not between {min} and {max}"))
+        };
+        let (start, end) = match range.split_once('-') {
+            _ if range == "*" => (min, max),
+            Some((start, end)) => (number(start)?, number(end)?),
+            // `5/15` means every 15 from 5, like `5-59/15`.
+            None if step > 1 => (number(range)?, max),
+            None => (number(range)?, number(range)?),
+        };
+        if start > end {
+            return Err(format!("range {range:?} is backwards"));
+        }
+        for value in (start..=end).step_by(step as usize) {
+            bits |= 1 << value;
+        }
+    }
+    Ok(bits)
+}
+
+impl FromStr for Schedule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let fields: Vec<&str> = s.split_whitespace().collect();
+        let [minutes, hours, days, months, weekdays] = fields[..] else {
+            return Err(format!("expected 5 fields in {s:?}"));
+        };
+        let mut weekday_bits = parse_field(weekdays, 0, 7)?;
+        // Both 0 and 7 are Sunday.
+        if weekday_bits & (1 << 7) != 0 {
+            weekday_bits = (weekday_bits | 1) & !(1 << 7);
+        }
+        Ok(Self {
+            minutes: parse_field(minutes, 0, 59)?,
+            hours: parse_field(hours, 0, 23)?,
+            days: parse_field(days, 1, 31)?,
+            months: parse_field(months, 1, 12)?,
+            weekdays: weekday_bits,
+            days_restricted: !days.starts_with('*'),
+            weekdays_restricted: !weekdays.starts_with('*'),
+        })
+    }
+}
+
+impl Schedule {
+    fn matches_date(&self, date: NaiveDate) -> bool {
+        let day = self.days & (1 << date.day()) != 0;
+        let weekday = self.weekdays & (1 << date.weekday().num_days_from_sunday()) != 0;
+        let day = if self.days_restricted && self.weekdays_restricted {
+            day || weekday
+        } else {
+            day && weekday
+        };
+        day && self.months & (1 << date.month()) != 0
+    }
+
+    /// The first time strictly after `after` that the schedule fires. Local times skipped
+    /// by a DST change are skipped here too.
+    pub fn next_after<Tz: TimeZone>(&self, after: &DateTime<Tz>) -> Option<DateTime<Tz>> {
+        let timezone = after.timezone();
+        let start =
+            after.naive_local().with_second(0)?.with_nanosecond(0)? + chrono::Duration::minutes(1);
+        let end = start + chrono::Duration::days(MAX_SCHEDULE_LOOKAHEAD_DAYS);
+
+        let mut candidate = start;
+        while candidate < end {
+            if !self.matches_date(candidate.date()) {
+                candidate = NaiveDateTime::from(candidate.date().succ_opt()?);
+                continue;
+            }
+            if self.hours & (1 << candidate.hour()) == 0 {
+                candidate = candidate.with_minute(0)? + chrono::Duration::hours(1);
+                continue;
+            }
+            if self.minutes & (1 << candidate.minute()) != 0 {
+                if let Some(time) = timezone.from_local_datetime(&candidate).earliest() {
+                    if time > *after {
+                        return Some(time);
+                    }
+                }
+            }
+            candidate += chrono::Duration::minutes(1);
+        }
+        None
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Source {
+    Playlist,
+    Epg,
+}
+
+impl Source {
+    fn config(self, app_state: &AppState) -> &SourceConfig {
+        match self {
+            Source::Playlist => &app_state.playlist_config,
+            Source::Epg => &app_state.epg_config,
+        }
+    }
+
+    fn needs_refresh(self, app_state: &AppState) -> bool {
+        match self {
+            Source::Playlist => app_state.playlist_needs_refresh(),
+            Source::Epg => app_state.epg_needs_refresh(),
+        }
+    }
+
+    fn backoff(self, app_state: &AppState) -> Option<Duration> {
+        match self {
+            Source::Playlist => app_state.playlist_backoff.read().unwrap().remaining(),
+            Source::Epg => app_state.epg_backoff.read().unwrap().remaining(),
+        }
+    }
+
+    /// Time until the cached copy expires, or zero if it already has.
+    fn ttl_remaining(self, app_state: &AppState) -> Duration {
+        let fetched = match self {
+            Source::Playlist => app_state
+                .cached_playlist
+                .read()
+                .unwrap()
+                .as_ref()
+                .map(|fetch| fetch.fetched),
+            Source::Epg => app_state
+                .cached_epg
+                .read()
+                .unwrap()
+                .as_ref()
+                .map(|fetch| fetch.fetched),
+        };
+        fetched.map_or(Duration::ZERO, |fetched| {
+            self.config(app_state)
+                .cache_ttl
+                .saturating_sub(fetched.elapsed())
+        })
+    }
+
+    async fn refresh(self, app_state: &AppState, forced: bool) {
+        let result = match (self, forced) {
+            (Source::Playlist, false) => app_state.fetch_playlist().await.map(|_| ()),
+            (Source::Playlist, true) => app_state.force_refresh_playlist().await.map(|_| ()),
+            (Source::Epg, false) => app_state.fetch_epg().await.map(|_| ()),
+            (Source::Epg, true) => app_state.force_refresh_epg().await.map(|_| ()),
+        };
+        if let Err(error) = result {
+            tracing::warn!(source = ?self, ?error, "Scheduled refresh failed");
+        }
+    }
+}
+
+/// One source's place in the scheduler.
+struct ScheduledSource {
+    source: Source,
+    next_run: Option<DateTime<Local>>,
+    refreshing: Option<JoinHandle<()>>,
+}
+
+impl ScheduledSource {
+    fn new(source: Source, app_state: &AppState) -> Self {
+        let next_run = source
+            .config(app_state)
+            .schedule
+            .as_ref()
+            .and_then(|schedule| schedule.next_after(&Local::now()));
+        if let Some(next_run) = next_run {
+            tracing::info!(?source, %next_run, "Next scheduled refresh");
+        }
+        Self {
+            source,
+            next_run,
+            refreshing: None,
+        }
+    }
+
+    fn is_refreshing(&self) -> bool {
+        self.refreshing
+            .as_ref()
+            .is_some_and(|handle| !handle.is_finished())
+    }
+
+    /// Time until this source wants a refresh, by schedule or by TTL once any backoff
+    /// has passed.
+    fn due_in(&self, app_state: &AppState) -> Duration {
+        let by_ttl = self
+            .source
+            .ttl_remaining(app_state)
+            .max(self.source.backoff(app_state).unwrap_or_default());
+        let by_schedule = self
+            .next_run
+            .map(|next_run| (next_run - Local::now()).to_std().unwrap_or(Duration::ZERO));
+        by_schedule.map_or(by_ttl, |by_schedule| by_schedule.min(by_ttl))
+    }
+
+    fn run_due(&mut self, app_state: &AppState) {
+        if self.is_refreshing() {
+            return;
+        }
+        let now = Local::now();
+        let scheduled = self.next_run.is_some_and(|next_run| next_run <= now);
+        if scheduled {
+            self.next_run = self
+                .source
+                .config(app_state)
+                .schedule
+                .as_ref()
+                .and_then(|schedule| schedule.next_after(&now));
+            tracing::info!(
+                source = ?self.source,
+                next_run = ?self.next_run,
+                "Running scheduled refresh"
+            );
+        } else if !self.source.needs_refresh(app_state) || self.source.backoff(app_state).is_some()
+        {
+            return;
+        } else {
+            tracing::info!(source = ?self.source, "Cache is stale, fetching a refresh");
+        }
+
+        let source = self.source;
+        let app_state = app_state.clone();
+        self.refreshing = Some(tokio::spawn(async move {
+            source.refresh(&app_state, scheduled).await;
+        }));
+    }
+}
+
+/// Refreshes the playlist and EPG when their cache expires or their schedule says so,
+/// sleeping until the next source is due.
+pub fn spawn_scheduler(app_state: AppState) {
+    tokio::spawn(async move {
+        let mut sources = [
+            ScheduledSource::new(Source::Playlist, &app_state),
+            ScheduledSource::new(Source::Epg, &app_state),
+        ];
+        loop {
+            for source in &mut sources {
+                source.run_due(&app_state);
+            }
+            let sleep = sources
+                .iter()
+                .filter(|source| !source.is_refreshing())
+                .map(|source| source.due_in(&app_state))
+                .min()
+                .unwrap_or(MAX_SCHEDULER_SLEEP)
+                .clamp(Duration::from_secs(1), MAX_SCHEDULER_SLEEP);
+            tokio::time::sleep(sleep).await;
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff_doubles_up_to_cap_with_jitter() {
+        let config = SourceConfig {
+            cache_ttl: DEFAULT_CACHE_TTL,
+            backoff: Duration::from_secs(60),
+            backoff_max: Duration::from_secs(600),
+            fetch_timeout: DEFAULT_FETCH_TIMEOUT,
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+            schedule: None,
+        };
+        let delays: Vec<u64> = (1..=6)
+            .map(|failures| config.backoff_delay(failures, 0.0).as_secs())
+            .collect();
+        assert_eq!(delays, [60, 120, 240, 480, 600, 600]);
+        assert_eq!(config.backoff_delay(1, 1.0), Duration::from_secs(45));
+        assert_eq!(config.backoff_delay(u32::MAX, 0.0), config.backoff_max);
+
+        let mut backoff = Backoff::default();
+        assert!(!backoff.is_active());
+        let delay = backoff.failed(&config);
+        assert!(delay <= Duration::from_secs(60) && delay >= Duration::from_secs(45));
+        assert!(backoff.is_active());
+        backoff.succeeded();
+        assert!(!backoff.is_active());
+        assert_eq!(backoff.failures, 0);
+    }
+
+    #[test]
+    fn test_schedule_next_after() {
+        let at = |s: &str| {
+            chrono::Utc
+                .from_local_datetime(&NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M").unwrap())
+                .unwrap()
+        };
+        let daily: Schedule = "0 4 * * *".parse().unwrap();
+        assert_eq!(
+            daily.next_after(&at("2026-10-18 03:59")),
+            Some(at("2026-10-18 04:00"))
+        );
+        assert_eq!(
+            daily.next_after(&at("2026-10-18 04:00")),
+            Some(at("2026-10-19 04:00"))
+        );
+
+        let weekdays: Schedule = "*/30 6-7 * * 1-5".parse().unwrap();
+        // 2026-10-17 is a Saturday.
+        assert_eq!(
+            weekdays.next_after(&at("2026-10-17 12:00")),
+            Some(at("2026-10-19 06:00"))
+        );
+        assert_eq!(
+            weekdays.next_after(&at("2026-10-19 07:10")),
+            Some(at("2026-10-19 07:30"))
+        );
+
+        let sundays: Schedule = "15 0 * * 7".parse().unwrap();
+        assert_eq!(
+            sundays.next_after(&at("2026-10-18 00:00")),
+            Some(at("2026-10-18 00:15"))
+        );
+
+        // A stepped `*` still restricts by the other day field alone: odd days that are
+        // Mondays, not every odd day and every Monday.
+        let odd_mondays: Schedule = "0 0 */2 * 1".parse().unwrap();
+        assert_eq!(
+            odd_mondays.next_after(&at("2026-10-19 00:00")),
+            Some(at("2026-11-09 00:00"))
+        );
+
+        let never: Schedule = "0 0 30 2 *".parse().unwrap();
+        assert_eq!(never.next_after(&at("2026-10-18 00:00")), None);
+
+        assert!("0 4 * *".parse::<Schedule>().is_err());
+        assert!("61 * * * *".parse::<Schedule>().is_err());
+        assert!("*/0 * * * *".parse::<Schedule>().is_err());
+    }
+}
@@ -0,0 +1,396 @@
+use std::{
+    collections::HashSet,
+    path::{Path as FsPath, PathBuf},
+    sync::{Arc, RwLock},
+};
+
+use axum::{
+    extract::{Path, State},
+    http::{header, HeaderMap, Response, StatusCode},
+    Extension, Json,
+};
+use chrono::{DateTime, FixedOffset, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    alerts::{find_matches, ChannelGroups},
+    auth::{legacy_owner, CurrentUser},
+    epg::Programme,
+    proxy,
+    store::{load_json, save_json},
+    AppState,
+};
+
+/// Minutes before a starred programme that calendar apps should remind us.
+const REMINDER_MINUTES: u32 = 10;
+
+/// A programme marked to watch, kept even after it leaves the EPG.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StarredProgramme {
+    #[serde(default = "legacy_owner")]
+    pub user_id: u64,
+    /// EPG channel id (the playlist's `tvg_id`).
+    pub channel: String,
+    pub channel_name: String,
+    pub title: String,
+    pub desc: String,
+    pub start: DateTime<FixedOffset>,
+    pub stop: DateTime<FixedOffset>,
+    pub starred_at: DateTime<Utc>,
+}
+
+/// Starred programmes persisted as JSON in the data directory.
+#[derive(Debug, Clone)]
+pub struct StarredProgrammes {
+    path: PathBuf,
+    starred: Arc<RwLock<Vec<StarredProgramme>>>,
+}
+
+impl StarredProgrammes {
+    pub fn load(data_dir: &FsPath) -> Self {
+        let path = data_dir.join("starred.json");
+        Self {
+            starred: Arc::new(RwLock::new(load_json(&path))),
+            path,
+        }
+    }
+
+    /// The user's starred programmes.
+    pub fn list(&self, user_id: u64) -> Vec<StarredProgramme> {
+        self.starred
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|s| s.user_id == user_id)
+            .cloned()
+            .collect()
+    }
+
+    pub fn contains(&self, user_id: u64, channel: &str, start: DateTime<FixedOffset>) -> bool {
+        self.starred
+            .read()
+            .unwrap()
+            .iter()
+            .any(|s| s.user_id == user_id && s.channel == channel && s.start == start)
+    }
+
+    fn insert(&self, starred: StarredProgramme) {
+        self.starred.write().unwrap().push(starred);
+        self.save();
+    }
+
+    fn remove(&self, user_id: u64, channel: &str, start: DateTime<Utc>) -> bool {
+        let mut starred = self.starred.write().unwrap();
+        let count = starred.len();
+        starred.retain(|s| !(s.user_id == user_id && s.channel == channel && s.start == start));
+        let removed = starred.len() != count;
+        drop(starred);
+        self.save();
+        removed
+    }
+
+    fn save(&self) {
+        save_json(&self.path, &*self.starred.read().unwrap());
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StarProgrammeRequest {
+    /// EPG channel id (the playlist's `tvg_id`).
+    channel: String,
+    start: DateTime<FixedOffset>,
+}
+
+pub async fn list_starred(
+    State(app_state): State<AppState>,
+    Extension(CurrentUser(user)): Extension<CurrentUser>,
+) -> Json<Vec<StarredProgramme>> {
+    let mut starred = app_state.starred.list(user.id);
+    starred.sort_by_key(|s| s.start);
+    Json(starred)
+}
+
+pub async fn star_programme(
+    State(app_state): State<AppState>,
+    Extension(CurrentUser(user)): Extension<CurrentUser>,
+    Json(request): Json<StarProgrammeRequest>,
+) -> Result<(StatusCode, Json<StarredProgramme>), (StatusCode, &'static str)> {
+    let epg = app_state.fetch_epg().await.map_err(|e| {
+        tracing::error!("Failed to fetch EPG: {:?}", e);
+        (StatusCode::SERVICE_UNAVAILABLE, "Failed to fetch EPG")
+    })?;
+    let programme = epg
+        .find(&request.channel, request.start.to_utc())
+        .ok_or((StatusCode::NOT_FOUND, "Programme not found"))?;
+    if let Some(existing) = app_state
+        .starred
+        .list(user.id)
+        .into_iter()
+        .find(|s| s.channel == programme.channel && s.start == programme.start)
+    {
+        return Ok((StatusCode::OK, Json(existing)));
+    }
+
+    let starred = StarredProgramme {
+        user_id: user.id,
+        channel: programme.channel.clone(),
+        channel_name: epg
+            .channels
+            .iter()
+            .find(|c| c.id == programme.channel)
+            .map_or_else(|| programme.channel.clone(), |c| c.display_name.clone()),
+        title: programme.title.clone(),
+        desc: programme.desc.clone(),
+        start: programme.start,
+        stop: programme.stop,
+        starred_at: Utc::now(),
+    };
+    app_state.starred.insert(starred.clone());
+    tracing::info!(title = %starred.title, start = %starred.start, "Starred programme");
+    Ok((StatusCode::CREATED, Json(starred)))
+}
+
+pub async fn unstar_programme(
+    Path((channel, start)): Path<(String, i64)>,
+    State(app_state): State<AppState>,
+    Extension(CurrentUser(user)): Extension<CurrentUser>,
+) -> Result<StatusCode, (StatusCode, &'static str)> {
+    let removed = DateTime::from_timestamp(start, 0)
+        .is_some_and(|start| app_state.starred.remove(user.id, &channel, start));
+    if !removed {
+        return Err((StatusCode::NOT_FOUND, "Programme is not starred"));
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// One VEVENT in the calendar feed.
+#[derive(Debug)]
+struct CalendarEvent {
+    channel: String,
+    channel_name: String,
+    title: String,
+    desc: String,
+    start: DateTime<Utc>,
+    stop: DateTime<Utc>,
+    stream_url: Option<String>,
+    /// The saved search that found the programme, for events that weren't starred.
+    saved_search: Option<String>,
+}
+
+/// The user's starred programmes and upcoming saved search matches as an iCalendar
+/// feed, for subscribing from calendar apps with `?token=`. Stream links carry the same
+/// token.
+pub async fn calendar_feed(
+    State(app_state): State<AppState>,
+    Extension(CurrentUser(user)): Extension<CurrentUser>,
+    headers: HeaderMap,
+) -> Result<Response<String>, (StatusCode, &'static str)> {
+    let playlist = app_state.fetch_playlist().await.map_err(|e| {
+        tracing::error!("Failed to fetch playlist: {:?}", e);
+        (StatusCode::SERVICE_UNAVAILABLE, "Failed to fetch playlist")
+    })?;
+    let epg = app_state.cached_epg_snapshot();
+    let base_url = public_base_url(&headers);
+    let stream_url = |channel: &str| {
+        proxy::preferred_entry(
+            &playlist.entries,
+            channel,
+            &app_state.proxy_config.failover_priority,
+        )
+        .map(|entry| format!("{base_url}/proxy/{}?token={}", entry.url, user.token()))
+    };
+
+    let mut events: Vec<CalendarEvent> = app_state
+        .starred
+        .list(user.id)
+        .into_iter()
+        .map(|s| CalendarEvent {
+            stream_url: stream_url(&s.channel),
+            channel: s.channel,
+            channel_name: s.channel_name,
+            title: s.title,
+            desc: s.desc,
+            start: s.start.to_utc(),
+            stop: s.stop.to_utc(),
+            saved_search: None,
+        })
+        .collect();
+
+    if let Some(epg) = &epg {
+        let mut seen: HashSet<(String, DateTime<Utc>)> = events
+            .iter()
+            .map(|e| (e.channel.clone(), e.start))
+            .collect();
+        let channel_map = epg.channel_map();
+        let groups = ChannelGroups::new(&playlist);
+        for search in app_state
+            .saved_searches
+            .list()
+            .into_iter()
+            .filter(|s| s.user_id == user.id)
+        {
+            for programme in find_matches(epg, &groups, &search, Utc::now()) {
+                if !seen.insert((programme.channel.clone(), programme.start.to_utc())) {
+                    continue;
+                }
+                events.push(CalendarEvent {
+                    channel_name: channel_map
+                        .get(&programme.channel)
+                        .map_or_else(|| programme.channel.clone(), |c| c.display_name.clone()),
+                    stream_url: stream_url(&programme.channel),
+                    saved_search: Some(search.name.clone()),
+                    ..CalendarEvent::from(programme)
+                });
+            }
+        }
+    }
+    events.sort_by_key(|e| e.start);
+
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, "text/calendar; charset=utf-8")
+        .header(
+            header::CONTENT_DISPOSITION,
+            "inline; filename=\"sparrow-tv.ics\"",
+        )
+        .body(to_ics(&events, Utc::now()))
+        .unwrap())
+}
+
+impl From<&Programme> for CalendarEvent {
+    fn from(programme: &Programme) -> Self {
+        Self {
+            channel: programme.channel.clone(),
+            channel_name: programme.channel.clone(),
+            title: programme.title.clone(),
+            desc: programme.desc.clone(),
+            start: programme.start.to_utc(),
+            stop: programme.stop.to_utc(),
+            stream_url: None,
+            saved_search: None,
+        }
+    }
+}
+
+/// The scheme and host this request reached us on, honouring a reverse proxy's
+/// `X-Forwarded-*` headers.
+fn public_base_url(headers: &HeaderMap) -> String {
+    let header = |name: &str| headers.get(name).and_then(|value| value.to_str().ok());
+    let scheme = header("x-forwarded-proto").unwrap_or("http");
+    let host = header("x-forwarded-host")
+        .or_else(|| header("host"))
+        .unwrap_or("localhost");
+    format!("{scheme}://{host}")
+}
+
+fn to_ics(events: &[CalendarEvent], now: DateTime<Utc>) -> String {
+    let timestamp = |time: DateTime<Utc>| time.format("%Y%m%dT%H%M%SZ").to_string();
+    let mut lines = vec![
+        "BEGIN:VCALENDAR".to_string(),
+        "VERSION:2.0".to_string(),
+        "PRODID:-//sparrow-tv//EN".to_string(),
+        "CALSCALE:GREGORIAN".to_string(),
+        "X-WR-CALNAME:Sparrow TV".to_string(),
+    ];
+    for event in events {
+        let mut description = event.desc.clone();
+        if let Some(url) = &event.stream_url {
+            description.push_str(&format!("\n\nWatch: {url}"));
+        }
+
+        lines.push("BEGIN:VEVENT".to_string());
+        lines.push(format!(
+            "UID:{}-{}@sparrow-tv",
+            event.channel,
+            event.start.timestamp()
+        ));
+        lines.push(format!("DTSTAMP:{}", timestamp(now)));
+        lines.push(format!("DTSTART:{}", timestamp(event.start)));
+        lines.push(format!("DTEND:{}", timestamp(event.stop)));
+        lines.push(format!("SUMMARY:{}", escape_text(&event.title)));
+        lines.push(format!("LOCATION:{}", escape_text(&event.channel_name)));
+        lines.push(format!("DESCRIPTION:{}", escape_text(description.trim())));
+        if let Some(url) = &event.stream_url {
+            lines.push(format!("URL:{url}"));
+        }
+        match &event.saved_search {
+            Some(search) => lines.push(format!("CATEGORIES:{}", escape_text(search))),
+            None => {
+                lines.push("BEGIN:VALARM".to_string());
+                lines.push("ACTION:DISPLAY".to_string());
+                lines.push(format!("DESCRIPTION:{}", escape_text(&event.title)));
+                lines.push(format!("TRIGGER:-PT{REMINDER_MINUTES}M"));
+                lines.push("END:VALARM".to_string());
+            }
+        }
+        lines.push("END:VEVENT".to_string());
+    }
+    lines.push("END:VCALENDAR".to_string());
+
+    lines
+        .iter()
+        .map(|line| fold_line(line))
+        .collect::<Vec<_>>()
+        .concat()
+}
+
+fn escape_text(text: &str) -> String {
+    text.replace('\\', "\\\\")
+        .replace(';', "\\;")
+        .replace(',', "\\,")
+        .replace("\r\n", "\\n")
+        .replace('\n', "\\n")
+}
+
+/// Splits a content line into CRLF-terminated lines of at most 75 bytes, continuation
+/// lines starting with a space, without breaking UTF-8 characters.
+fn fold_line(line: &str) -> String {
+    let mut folded = String::with_capacity(line.len() + 2);
+    let mut line_length = 0;
+    for c in line.chars() {
+        if line_length + c.len_utf8() > 75 {
+            folded.push_str("\r\n ");
+            line_length = 1;
+        }
+        folded.push(c);
+        line_length += c.len_utf8();
+    }
+    folded.push_str("\r\n");
+    folded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_to_ics_escapes_and_folds_events() {
+        let at = |rfc3339: &str| DateTime::parse_from_rfc3339(rfc3339).unwrap().to_utc();
+        let event = CalendarEvent {
+            channel: "svt1.se".to_string(),
+            channel_name: "SVT1".to_string(),
+            title: "Fotboll: Sverige, Norge".to_string(),
+            desc: "Direktsänt; kvalmatch till världsmästerskapet i fotboll från Friends Arena i Solna.".to_string(),
+            start: at("2024-10-17T17:30:00Z"),
+            stop: at("2024-10-17T19:30:00Z"),
+            stream_url: Some("https://tv.local/proxy/http://abc.xyz/1".to_string()),
+            saved_search: None,
+        };
+
+        let ics = to_ics(&[event], at("2024-10-17T12:00:00Z"));
+        assert!(ics.starts_with("BEGIN:VCALENDAR\r\nVERSION:2.0\r\n"));
+        assert!(ics.contains("UID:svt1.se-1729186200@sparrow-tv\r\n"));
+        assert!(ics.contains("DTSTART:20241017T173000Z\r\nDTEND:20241017T193000Z\r\n"));
+        assert!(ics.contains("SUMMARY:Fotboll: Sverige\\, Norge\r\n"));
+        assert!(ics.contains("TRIGGER:-PT10M\r\n"));
+        assert!(ics.ends_with("END:VEVENT\r\nEND:VCALENDAR\r\n"));
+        assert!(ics.split("\r\n").all(|line| line.len() <= 75));
+
+        let unfolded = ics.replace("\r\n ", "");
+        assert!(unfolded.contains(
+            "DESCRIPTION:Direktsänt\\; kvalmatch till världsmästerskapet i fotboll från Friends Arena i Solna.\\n\\nWatch: https://tv.local/proxy/http://abc.xyz/1\r\n"
+        ));
+    }
+}
@@ -1,39 +1,37 @@
-use std::collections::{HashMap, HashSet};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
 
 use axum::{
     extract::{Query, State},
     http::{Response, StatusCode},
-    response::IntoResponse,
-    Json,
+    Extension, Json,
 };
-use chrono::{DateTime, FixedOffset};
+use chrono::{DateTime, Duration, FixedOffset, Local, NaiveDate, TimeZone, Utc};
 use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
 use serde::{Deserialize, Serialize};
 
 use crate::{
-    epg::{Channel, Epg, Icon},
+    auth::CurrentUser,
+    catchup::{catchup_entry, catchup_path, max_catchup_days},
+    epg::{Channel, Epg, Icon, Programme},
+    favourites::favourite_entries,
     playlist::PlaylistEntry,
+    search::fold,
     AppState,
 };
 
-#[derive(Debug, Deserialize)]
-pub struct DownloadQuery {
-    pw: String,
-}
-
 pub async fn download_playlist(
-    Query(DownloadQuery { pw }): Query<DownloadQuery>,
     State(app_state): State<AppState>,
+    Extension(CurrentUser(user)): Extension<CurrentUser>,
 ) -> Result<Response<String>, (StatusCode, &'static str)> {
-    if pw != std::env::var("PASSWORD").unwrap() {
-        return Err((StatusCode::UNAUTHORIZED, "Unauthorized"));
-    }
-
     let playlist = app_state.fetch_playlist().await.map_err(|e| {
         tracing::error!("Failed to fetch playlist: {:?}", e);
         (StatusCode::SERVICE_UNAVAILABLE, "Failed to fetch playlist")
     })?;
-    let m3u = playlist.to_m3u();
+    let favourites = favourite_entries(&app_state, &playlist, user.id);
+    let m3u = playlist.to_m3u(&favourites);
 
     // return m3u file
     Ok(Response::builder()
@@ -44,20 +42,15 @@ pub async fn download_playlist(
 }
 
 pub async fn download_epg(
-    Query(DownloadQuery { pw }): Query<DownloadQuery>,
     State(app_state): State<AppState>,
-) -> impl IntoResponse {
-    if pw != std::env::var("PASSWORD").unwrap() {
-        return Err((StatusCode::UNAUTHORIZED, "Unauthorized"));
-    }
-
+) -> Result<Response<String>, (StatusCode, &'static str)> {
     let playlist = app_state.fetch_playlist().await.map_err(|e| {
         tracing::error!("Failed to fetch playlist: {:?}", e);
         (StatusCode::SERVICE_UNAVAILABLE, "Failed to fetch playlist")
     })?;
-    let mut epg = app_state.fetch_epg().await.unwrap_or_else(|error| {
+    let epg = app_state.fetch_epg().await.unwrap_or_else(|error| {
         tracing::warn!(?error, "Failed to fetch EPG, serving playlist-only EPG response");
-        epg_from_playlist_entries(&playlist.filtered_entries)
+        Arc::new(epg_from_playlist_entries(&playlist.filtered_entries))
     });
 
     let channels_to_keep: Vec<String> = playlist
@@ -65,7 +58,7 @@ pub async fn download_epg(
         .par_iter()
         .map(|e| e.tvg_id.clone())
         .collect();
-    epg.filter_channels(&channels_to_keep);
+    let epg = epg.filter_channels(&channels_to_keep);
 
     let xml = epg.to_xml().map_err(|e| {
         tracing::error!("Failed to render EPG XML: {:?}", e);
@@ -82,17 +75,136 @@ pub async fn download_epg(
         .unwrap())
 }
 
-#[derive(Debug, Deserialize)]
+const DEFAULT_SEARCH_LIMIT: usize = 50;
+const MAX_SEARCH_LIMIT: usize = 500;
+/// Multiplies the score of programmes on the user's favourite channels, so they rank
+/// higher without hiding better matches elsewhere.
+const FAVOURITE_SEARCH_BOOST: f64 = 2.0;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct SearchQuery {
-    #[serde(rename = "q")]
-    search_query: String,
-    include_hidden: Option<bool>,
+    #[serde(rename = "q", default)]
+    pub search_query: String,
+    pub include_hidden: Option<bool>,
+    /// `now`, `today`, `tonight` or `tomorrow` in the server's time zone.
+    when: Option<String>,
+    from: Option<DateTime<Utc>>,
+    to: Option<DateTime<Utc>>,
+    group: Option<String>,
+    /// Comma-separated channel ids.
+    channels: Option<String>,
+    category: Option<String>,
+    /// Only programmes airing right now.
+    live: Option<bool>,
+    exclude_ended: Option<bool>,
+    offset: Option<usize>,
+    limit: Option<usize>,
+}
+
+/// The structured part of a [`SearchQuery`], resolved against the current time.
+#[derive(Debug)]
+pub struct SearchFilters {
+    now: DateTime<Utc>,
+    from: Option<DateTime<Utc>>,
+    to: Option<DateTime<Utc>>,
+    live: bool,
+    exclude_ended: bool,
+    group: Option<String>,
+    channels: Option<HashSet<String>>,
+    /// Folded with [`fold`].
+    category: Option<String>,
+}
+
+impl SearchFilters {
+    pub fn from_query<Tz: TimeZone>(
+        query: &SearchQuery,
+        now: DateTime<Tz>,
+    ) -> Result<Self, (StatusCode, &'static str)> {
+        let (from, to) = match query.when.as_deref() {
+            None | Some("now") => (None, None),
+            Some(when) => named_window(when, &now)
+                .map(|(from, to)| (Some(from), Some(to)))
+                .ok_or((
+                    StatusCode::BAD_REQUEST,
+                    "Unknown `when`, expected now, today, tonight or tomorrow",
+                ))?,
+        };
+        let from = query.from.or(from);
+        let to = query.to.or(to);
+        if from.zip(to).is_some_and(|(from, to)| to <= from) {
+            return Err((StatusCode::BAD_REQUEST, "`to` must be after `from`"));
+        }
+
+        Ok(Self {
+            now: now.to_utc(),
+            from,
+            to,
+            live: query.live == Some(true) || query.when.as_deref() == Some("now"),
+            exclude_ended: query.exclude_ended == Some(true),
+            group: query.group.clone(),
+            channels: query.channels.as_ref().map(|channels| {
+                channels
+                    .split(',')
+                    .map(|channel| channel.trim().to_string())
+                    .filter(|channel| !channel.is_empty())
+                    .collect()
+            }),
+            category: query.category.as_deref().map(fold),
+        })
+    }
+
+    fn matches_channel(&self, channel_id: &str, group: Option<&str>) -> bool {
+        self.channels
+            .as_ref()
+            .is_none_or(|channels| channels.contains(channel_id))
+            && self.group.as_ref().is_none_or(|wanted| {
+                group.is_some_and(|group| group.eq_ignore_ascii_case(wanted))
+            })
+    }
+
+    pub fn matches(&self, programme: &Programme, group: Option<&str>) -> bool {
+        let start = programme.start.to_utc();
+        let stop = programme.stop.to_utc();
+        self.from.is_none_or(|from| stop > from)
+            && self.to.is_none_or(|to| start < to)
+            && (!self.live || (start <= self.now && self.now < stop))
+            && (!self.exclude_ended || stop > self.now)
+            && self.category.as_ref().is_none_or(|category| {
+                programme
+                    .categories
+                    .iter()
+                    .any(|candidate| fold(candidate) == *category)
+            })
+            && self.matches_channel(&programme.channel, group)
+    }
+}
+
+/// The span of a named part of the day around `now`, in `now`'s time zone.
+fn named_window<Tz: TimeZone>(
+    when: &str,
+    now: &DateTime<Tz>,
+) -> Option<(DateTime<Utc>, DateTime<Utc>)> {
+    let at = |date: NaiveDate, hour: u32| {
+        now.timezone()
+            .from_local_datetime(&date.and_hms_opt(hour, 0, 0)?)
+            .earliest()
+            .map(|time| time.to_utc())
+    };
+    let today = now.date_naive();
+    let tomorrow = today.succ_opt()?;
+    match when {
+        "today" => Some((at(today, 0)?, at(tomorrow, 0)?)),
+        "tonight" => Some((at(today, 18)?, at(tomorrow, 0)?)),
+        "tomorrow" => Some((at(tomorrow, 0)?, at(tomorrow.succ_opt()?, 0)?)),
+        _ => None,
+    }
 }
 
 #[derive(Debug, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct ProgrammeResult {
+    channel_id: String,
     channel_name: String,
     channel_group: Option<String>,
     channel_url: Option<String>,
@@ -100,6 +212,10 @@ pub struct ProgrammeResult {
     programme_desc: String,
     start: DateTime<FixedOffset>,
     stop: DateTime<FixedOffset>,
+    /// Set for past programmes that can be played from the provider's archive.
+    catchup_url: Option<String>,
+    starred: bool,
+    favourite: bool,
 }
 
 #[derive(Debug, Serialize)]
@@ -107,31 +223,42 @@ pub struct ProgrammeResult {
 pub struct ChannelResult {
     channel_name: String,
     url: String,
+    favourite: bool,
 }
 
 #[derive(Debug, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct SearchResult {
     programmes: Vec<ProgrammeResult>,
+    /// Matching programmes across all pages.
+    total_programmes: usize,
+    offset: usize,
+    limit: usize,
     channels: Vec<ChannelResult>,
 }
 
 pub async fn search(
-    Query(SearchQuery {
-        search_query,
-        include_hidden,
-    }): Query<SearchQuery>,
+    Query(query): Query<SearchQuery>,
     State(app_state): State<AppState>,
+    Extension(CurrentUser(user)): Extension<CurrentUser>,
 ) -> Result<Json<SearchResult>, (StatusCode, &'static str)> {
+    let filters = SearchFilters::from_query(&query, Local::now())?;
+    let offset = query.offset.unwrap_or(0);
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_SEARCH_LIMIT)
+        .min(MAX_SEARCH_LIMIT);
+    let include_hidden = query.include_hidden == Some(true);
+
     let playlist = app_state.fetch_playlist().await.map_err(|e| {
         tracing::error!("Failed to fetch playlist: {:?}", e);
         (StatusCode::SERVICE_UNAVAILABLE, "Failed to fetch playlist")
     })?;
     let epg = app_state.fetch_epg().await.unwrap_or_else(|error| {
         tracing::warn!(?error, "Failed to fetch EPG, serving channels-only search response");
-        Epg::empty()
+        Arc::new(Epg::empty())
     });
-    let playlist_entries = if let Some(true) = include_hidden {
+    let playlist_entries = if include_hidden {
         playlist.entries.clone()
     } else {
         playlist.filtered_entries.clone()
@@ -143,15 +270,62 @@ pub async fn search(
         .collect();
 
     let channel_map = epg.channel_map();
-    let programmes = epg.search(&search_query);
+    let now = filters.now;
+    // Past programmes are only interesting while they can still be watched as catchup.
+    let since = max_catchup_days(&playlist_entries)
+        .map_or(now, |days| now - Duration::days(days.into()));
+    let favourites: HashSet<String> = app_state.favourites.get(user.id).into_iter().collect();
+    let programmes = epg.search_boosted(&query.search_query, since, now, |p| {
+        if favourites.contains(&p.channel) {
+            FAVOURITE_SEARCH_BOOST
+        } else {
+            1.0
+        }
+    });
+
+    let matching_programmes: Vec<(&Programme, Option<String>)> = programmes
+        .into_iter()
+        .filter(|p| {
+            let group = playlist_channels
+                .get(&p.channel)
+                .map(|pc| pc.group_title.as_str());
+            // Without a playlist entry the channel is hidden unless asked for.
+            (include_hidden || group.is_some()) && filters.matches(p, group)
+        })
+        .filter_map(|p| {
+            let catchup_url = (p.stop < now)
+                .then(|| {
+                    catchup_entry(
+                        &playlist_entries,
+                        &p.channel,
+                        &app_state.proxy_config.failover_priority,
+                    )
+                })
+                .flatten()
+                .and_then(|entry| entry.catchup.as_ref())
+                .filter(|catchup| catchup.is_available(p, now))
+                .map(|_| catchup_path(p));
+            if p.stop < now && catchup_url.is_none() {
+                return None;
+            }
+            Some((p, catchup_url))
+        })
+        .collect();
+    let total_programmes = matching_programmes.len();
 
-    let programme_results: Vec<ProgrammeResult> = programmes
+    let programme_results: Vec<ProgrammeResult> = matching_programmes
         .into_iter()
-        .map(|p| {
+        .skip(offset)
+        .take(limit)
+        .map(|(p, catchup_url)| {
             let channel = channel_map.get(&p.channel);
             ProgrammeResult {
-                programme_title: p.title,
-                programme_desc: p.desc,
+                catchup_url,
+                starred: app_state.starred.contains(user.id, &p.channel, p.start),
+                favourite: favourites.contains(&p.channel),
+                channel_id: p.channel.clone(),
+                programme_title: p.title.clone(),
+                programme_desc: p.desc.clone(),
                 start: p.start,
                 stop: p.stop,
                 channel_name: if let Some(channel) = channel {
@@ -168,27 +342,29 @@ pub async fn search(
                     .and_then(|c| playlist_channels.get(&c.id).map(|pc| pc.url.clone())),
             }
         })
-        .filter(|p| {
-            if let Some(true) = include_hidden {
-                true
-            } else {
-                p.channel_group.is_some()
-            }
-        })
         .collect();
 
-    let lower_search_query = search_query.to_lowercase();
-    let channels: Vec<ChannelResult> = playlist_entries
+    let folded_search_query = fold(&query.search_query);
+    let mut channels: Vec<ChannelResult> = playlist_entries
         .par_iter()
-        .filter(|e| e.name.to_lowercase().contains(&lower_search_query))
+        .filter(|e| {
+            fold(&e.name).contains(&folded_search_query)
+                && filters.matches_channel(&e.tvg_id, Some(&e.group_title))
+        })
         .map(|e| ChannelResult {
             channel_name: format!("{} ({})", e.name, e.group_title),
             url: e.url.clone(),
+            favourite: favourites.contains(&e.tvg_id),
         })
         .collect();
+    // Stable, so favourites keep their playlist order.
+    channels.sort_by_key(|c| !c.favourite);
 
     Ok(Json(SearchResult {
         programmes: programme_results,
+        total_programmes,
+        offset,
+        limit,
         channels,
     }))
 }
@@ -216,37 +392,58 @@ fn epg_from_playlist_entries(entries: &[PlaylistEntry]) -> Epg {
         })
         .collect();
 
-    Epg {
-        channels,
-        programmes: Vec::new(),
-    }
+    Epg::new(channels, Vec::new())
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::{epg, playlist};
+
+    #[test]
+    fn test_search_filters_resolve_named_windows() {
+        let now = DateTime::parse_from_rfc3339("2024-10-17T12:00:00+02:00").unwrap();
+        let query: SearchQuery =
+            serde_json::from_str(r#"{"q": "", "when": "tonight", "group": "sweden"}"#).unwrap();
+        let filters = SearchFilters::from_query(&query, now).unwrap();
+        assert_eq!(
+            filters.from.unwrap().to_rfc3339(),
+            "2024-10-17T16:00:00+00:00"
+        );
+        assert_eq!(filters.to.unwrap().to_rfc3339(), "2024-10-17T22:00:00+00:00");
+
+        let programme = |start: &str, stop: &str| Programme {
+            stop: DateTime::parse_from_rfc3339(stop).unwrap(),
+            categories: vec!["Nyheter".to_string()],
+            ..epg::tests::programme("Rapport", start)
+        };
+        let evening = programme("2024-10-17T19:30:00+02:00", "2024-10-17T20:00:00+02:00");
+        let morning = programme("2024-10-17T09:00:00+02:00", "2024-10-17T09:30:00+02:00");
+        assert!(filters.matches(&evening, Some("Sweden")));
+        assert!(!filters.matches(&evening, Some("Norway")));
+        assert!(!filters.matches(&morning, Some("Sweden")));
+
+        let query: SearchQuery =
+            serde_json::from_str(r#"{"q": "", "when": "now", "category": "nyhéter"}"#).unwrap();
+        let filters = SearchFilters::from_query(&query, now).unwrap();
+        assert!(!filters.matches(&evening, None));
+        let current = programme("2024-10-17T11:30:00+02:00", "2024-10-17T12:30:00+02:00");
+        assert!(filters.matches(&current, None));
+
+        let query: SearchQuery = serde_json::from_str(r#"{"when": "someday"}"#).unwrap();
+        assert!(SearchFilters::from_query(&query, now).is_err());
+    }
 
     #[test]
     fn test_epg_from_playlist_entries_deduplicates_channels() {
+        let entry = |name: &str, url: &str| PlaylistEntry {
+            tvg_name: "SVT1".to_string(),
+            tvg_logo: "https://example.com/svt1.png".to_string(),
+            ..playlist::tests::entry("svt1.se", name, url)
+        };
         let entries = vec![
-            PlaylistEntry {
-                duration: -1,
-                tvg_id: "svt1.se".to_string(),
-                tvg_name: "SVT1".to_string(),
-                tvg_logo: "https://example.com/svt1.png".to_string(),
-                group_title: "Sweden".to_string(),
-                name: "SVT1 FHD SE".to_string(),
-                url: "http://example.com/1".to_string(),
-            },
-            PlaylistEntry {
-                duration: -1,
-                tvg_id: "svt1.se".to_string(),
-                tvg_name: "SVT1".to_string(),
-                tvg_logo: "https://example.com/svt1.png".to_string(),
-                group_title: "Sweden".to_string(),
-                name: "SVT1 Backup".to_string(),
-                url: "http://example.com/2".to_string(),
-            },
+            entry("SVT1 FHD SE", "http://example.com/1"),
+            entry("SVT1 Backup", "http://example.com/2"),
         ];
 
         let epg = epg_from_playlist_entries(&entries);
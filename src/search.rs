@@ -0,0 +1,373 @@
+use std::collections::{HashMap, HashSet};
+
+use rayon::prelude::*;
+use unicode_normalization::{char::is_combining_mark, UnicodeNormalization};
+
+use crate::epg::Programme;
+
+const TITLE_WEIGHT: f64 = 3.0;
+const DESC_WEIGHT: f64 = 1.0;
+const PREFIX_WEIGHT: f64 = 0.6;
+/// Divided by the number of typos.
+const FUZZY_WEIGHT: f64 = 0.5;
+const PHRASE_WEIGHT: f64 = 1.5;
+/// Set on a posting when the term occurs in the programme's title.
+const TITLE_BIT: u32 = 1;
+
+/// Lowercases `text` and strips diacritics, so "Världsmästerskap" becomes
+/// "varldsmasterskap".
+pub fn fold(text: &str) -> String {
+    let mut folded = String::with_capacity(text.len());
+    for c in text.nfkd().filter(|c| !is_combining_mark(*c)) {
+        for c in c.to_lowercase() {
+            // Letters that are distinct characters rather than accented ones.
+            match c {
+                'ø' => folded.push('o'),
+                'æ' => folded.push_str("ae"),
+                'œ' => folded.push_str("oe"),
+                'ß' => folded.push_str("ss"),
+                'đ' | 'ð' => folded.push('d'),
+                'ł' => folded.push('l'),
+                'þ' => folded.push_str("th"),
+                c => folded.push(c),
+            }
+        }
+    }
+    folded
+}
+
+/// The folded words of `text`.
+pub fn tokenize(text: &str) -> Vec<String> {
+    fold(text)
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|token| !token.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+#[derive(Debug, PartialEq)]
+enum Clause {
+    Term(String),
+    /// Words that must appear next to each other, written in double quotes.
+    Phrase(Vec<String>),
+}
+
+fn parse_query(query: &str) -> Vec<Clause> {
+    query
+        .split('"')
+        .enumerate()
+        .flat_map(|(i, part)| {
+            let tokens = tokenize(part);
+            if i % 2 == 1 && tokens.len() > 1 {
+                vec![Clause::Phrase(tokens)]
+            } else {
+                tokens.into_iter().map(Clause::Term).collect()
+            }
+        })
+        .collect()
+}
+
+/// Inverted index over the titles and descriptions of an EPG's programmes.
+#[derive(Debug, Clone, Default)]
+pub struct SearchIndex {
+    /// Programmes containing each folded word, as `index << 1 | TITLE_BIT` for title hits.
+    postings: HashMap<String, Vec<u32>>,
+    /// The keys of `postings`, sorted for prefix lookups.
+    terms: Vec<String>,
+}
+
+impl SearchIndex {
+    pub fn build(programmes: &[Programme]) -> Self {
+        let programme_terms: Vec<Vec<(String, u32)>> = programmes
+            .par_iter()
+            .enumerate()
+            .map(|(index, programme)| {
+                let posting = (index as u32) << 1;
+                let mut terms: Vec<(String, u32)> = tokenize(&programme.title)
+                    .into_iter()
+                    .map(|term| (term, posting | TITLE_BIT))
+                    .chain(
+                        tokenize(&programme.desc)
+                            .into_iter()
+                            .map(|term| (term, posting)),
+                    )
+                    .collect();
+                terms.sort_unstable();
+                terms.dedup();
+                terms
+            })
+            .collect();
+
+        let mut postings: HashMap<String, Vec<u32>> = HashMap::new();
+        for (term, posting) in programme_terms.into_iter().flatten() {
+            postings.entry(term).or_default().push(posting);
+        }
+        let mut terms: Vec<String> = postings.keys().cloned().collect();
+        terms.par_sort_unstable();
+
+        Self { postings, terms }
+    }
+
+    /// Text relevance of every programme matching all words and phrases of `query`, as
+    /// indices into the `programmes` the index was built from. A query without words
+    /// matches everything with a score of zero.
+    pub fn search(&self, programmes: &[Programme], query: &str) -> Vec<(usize, f64)> {
+        let clauses = parse_query(query);
+        if clauses.is_empty() {
+            return (0..programmes.len()).map(|index| (index, 0.0)).collect();
+        }
+
+        let mut matches: Option<HashMap<u32, f64>> = None;
+        for clause in &clauses {
+            let scores = match clause {
+                Clause::Term(term) => self.term_scores(term),
+                Clause::Phrase(tokens) => self.phrase_scores(programmes, tokens),
+            };
+            matches = Some(match matches {
+                None => scores,
+                Some(matches) => matches
+                    .into_iter()
+                    .filter_map(|(index, score)| {
+                        scores
+                            .get(&index)
+                            .map(|clause_score| (index, score + clause_score))
+                    })
+                    .collect(),
+            });
+        }
+
+        let whole_query = tokenize(query).join(" ");
+        matches
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(index, score)| {
+                let index = index as usize;
+                let title = tokenize(&programmes[index].title).join(" ");
+                let bonus = if title == whole_query {
+                    2.0 * TITLE_WEIGHT
+                } else if title.starts_with(&whole_query) {
+                    TITLE_WEIGHT
+                } else {
+                    0.0
+                };
+                (index, score + bonus)
+            })
+            .collect()
+    }
+
+    /// The best score of each programme containing `term` or a word close to it.
+    fn term_scores(&self, term: &str) -> HashMap<u32, f64> {
+        let mut scores = HashMap::new();
+        for (candidate, weight) in self.expand(term) {
+            for &posting in &self.postings[candidate] {
+                let field_weight = if posting & TITLE_BIT != 0 {
+                    TITLE_WEIGHT
+                } else {
+                    DESC_WEIGHT
+                };
+                let score = scores.entry(posting >> 1).or_insert(0.0);
+                *score = f64::max(*score, weight * field_weight);
+            }
+        }
+        scores
+    }
+
+    /// Indexed words matching `term` exactly, by prefix or with a few typos, weighted by
+    /// how close they are.
+    fn expand(&self, term: &str) -> Vec<(&str, f64)> {
+        let term_chars: Vec<char> = term.chars().collect();
+        let max_typos = match term_chars.len() {
+            0..=3 => 0,
+            4..=7 => 1,
+            _ => 2,
+        };
+
+        let mut matches = Vec::new();
+        if let Some((candidate, _)) = self.postings.get_key_value(term) {
+            matches.push((candidate.as_str(), 1.0));
+        }
+        if term_chars.len() >= 3 {
+            let first = self
+                .terms
+                .partition_point(|candidate| candidate.as_str() < term);
+            matches.extend(
+                self.terms[first..]
+                    .iter()
+                    .take_while(|candidate| candidate.starts_with(term))
+                    .filter(|candidate| candidate.as_str() != term)
+                    .map(|candidate| (candidate.as_str(), PREFIX_WEIGHT)),
+            );
+        }
+        if max_typos > 0 {
+            let fuzzy: Vec<(&str, f64)> = self
+                .terms
+                .par_iter()
+                .filter(|candidate| !candidate.starts_with(term))
+                .filter_map(|candidate| {
+                    let candidate_chars: Vec<char> = candidate.chars().collect();
+                    let typos = edit_distance(&term_chars, &candidate_chars, max_typos)?;
+                    Some((candidate.as_str(), FUZZY_WEIGHT / typos as f64))
+                })
+                .collect();
+            matches.extend(fuzzy);
+        }
+        matches
+    }
+
+    /// Programmes containing `tokens` as consecutive words in the title or description.
+    fn phrase_scores(&self, programmes: &[Programme], tokens: &[String]) -> HashMap<u32, f64> {
+        let mut candidates: Option<HashSet<u32>> = None;
+        for token in tokens {
+            let containing: HashSet<u32> = self
+                .postings
+                .get(token)
+                .into_iter()
+                .flatten()
+                .map(|posting| posting >> 1)
+                .collect();
+            candidates = Some(match candidates {
+                None => containing,
+                Some(candidates) => &candidates & &containing,
+            });
+        }
+
+        let contains_phrase = |text: &str| {
+            tokenize(text)
+                .windows(tokens.len())
+                .any(|words| words == tokens)
+        };
+        candidates
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|index| {
+                let programme = &programmes[index as usize];
+                let field_weight = if contains_phrase(&programme.title) {
+                    TITLE_WEIGHT
+                } else if contains_phrase(&programme.desc) {
+                    DESC_WEIGHT
+                } else {
+                    return None;
+                };
+                Some((index, PHRASE_WEIGHT * field_weight))
+            })
+            .collect()
+    }
+}
+
+/// Optimal string alignment distance between `a` and `b`, counting an adjacent swap as one
+/// typo, or `None` once it exceeds `max`.
+fn edit_distance(a: &[char], b: &[char], max: usize) -> Option<usize> {
+    if a.len().abs_diff(b.len()) > max {
+        return None;
+    }
+
+    let mut before_previous: Vec<usize> = Vec::new();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for i in 1..=a.len() {
+        let mut current = vec![i; b.len() + 1];
+        for j in 1..=b.len() {
+            let substitution = previous[j - 1] + usize::from(a[i - 1] != b[j - 1]);
+            current[j] = substitution.min(previous[j] + 1).min(current[j - 1] + 1);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                current[j] = current[j].min(before_previous[j - 2] + 1);
+            }
+        }
+        if current.iter().min().is_some_and(|&distance| distance > max) {
+            return None;
+        }
+        before_previous = std::mem::replace(&mut previous, current);
+    }
+
+    previous.last().copied().filter(|&distance| distance <= max)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::epg;
+
+    fn programme(title: &str, desc: &str) -> Programme {
+        Programme {
+            desc: desc.to_string(),
+            ..epg::tests::programme(title, "2024-10-17T19:30:00+02:00")
+        }
+    }
+
+    fn titles<'a>(programmes: &'a [Programme], query: &str) -> Vec<&'a str> {
+        let index = SearchIndex::build(programmes);
+        let mut matches = index.search(programmes, query);
+        matches.sort_by(|(_, a), (_, b)| b.total_cmp(a));
+        matches
+            .into_iter()
+            .map(|(index, _)| programmes[index].title.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn test_fold_strips_diacritics() {
+        assert_eq!(fold("Världsmästerskap"), "varldsmasterskap");
+        assert_eq!(fold("Færøerne Straße"), "faeroerne strasse");
+        assert_eq!(
+            tokenize("Fotboll: VM-kval, Sverige–Norge"),
+            ["fotboll", "vm", "kval", "sverige", "norge"]
+        );
+    }
+
+    #[test]
+    fn test_search_ranks_title_hits_and_tolerates_typos() {
+        let programmes = [
+            programme("Sportnytt", "Världsmästerskap i ishockey sammanfattas."),
+            programme("Världsmästerskap i fotboll", "Direktsänt från Doha."),
+            programme("Rapport", "Nyheter."),
+        ];
+
+        assert_eq!(
+            titles(&programmes, "Varldsmasterskap"),
+            ["Världsmästerskap i fotboll", "Sportnytt"]
+        );
+        assert_eq!(titles(&programmes, "raport"), ["Rapport"]);
+        assert_eq!(titles(&programmes, "sport"), ["Sportnytt"]);
+        assert_eq!(
+            titles(&programmes, "fotboll doha"),
+            ["Världsmästerskap i fotboll"]
+        );
+        assert!(titles(&programmes, "fotboll rapport").is_empty());
+        assert_eq!(titles(&programmes, "").len(), 3);
+    }
+
+    #[test]
+    fn test_search_phrases_require_adjacent_words() {
+        let programmes = [
+            programme("Ishockey", "Direktsänt från Globen i Stockholm."),
+            programme("Stockholm direkt", "Lokala nyheter från Globen."),
+        ];
+
+        assert_eq!(
+            parse_query(r#"globen "i stockholm""#),
+            [
+                Clause::Term("globen".to_string()),
+                Clause::Phrase(vec!["i".to_string(), "stockholm".to_string()])
+            ]
+        );
+        assert_eq!(titles(&programmes, r#""globen i stockholm""#), ["Ishockey"]);
+        assert_eq!(titles(&programmes, "\"stockholm direkt\"").len(), 1);
+    }
+
+    #[test]
+    fn test_edit_distance_is_bounded() {
+        let chars = |s: &str| s.chars().collect::<Vec<_>>();
+        assert_eq!(
+            edit_distance(&chars("rapport"), &chars("raport"), 1),
+            Some(1)
+        );
+        assert_eq!(
+            edit_distance(&chars("rapport"), &chars("rapoprt"), 1),
+            Some(1)
+        );
+        assert_eq!(edit_distance(&chars("rapport"), &chars("report"), 1), None);
+        assert_eq!(
+            edit_distance(&chars("nyheter"), &chars("nyheter"), 2),
+            Some(0)
+        );
+    }
+}
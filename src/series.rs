@@ -0,0 +1,542 @@
+use std::{
+    collections::BTreeSet,
+    path::PathBuf,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    Extension, Json,
+};
+use chrono::{DateTime, NaiveTime, Utc};
+use regex::{Regex, RegexBuilder};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    auth::{legacy_owner, CurrentUser},
+    epg::{Epg, Programme},
+    playlist::Playlist,
+    proxy,
+    recordings::{episode_key, new_recording, Recording, RecordingStatus},
+    store::{load_json, save_json},
+    AppState,
+};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum TitleMatch {
+    /// Case-insensitive equality.
+    #[default]
+    Exact,
+    /// Case-insensitive regular expression, matched anywhere in the title.
+    Regex,
+}
+
+/// Records every matching programme as the EPG refreshes, "record every new episode of X".
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SeriesRule {
+    pub id: u64,
+    #[serde(default = "legacy_owner")]
+    pub user_id: u64,
+    pub title: String,
+    #[serde(default)]
+    pub title_match: TitleMatch,
+    /// EPG channel id; any channel when unset.
+    pub channel: Option<String>,
+    /// Require one of the programme's categories to equal this, ignoring case.
+    pub category: Option<String>,
+    /// Earliest start time of day, in the programme's own timezone.
+    pub after: Option<NaiveTime>,
+    /// Latest start time of day (exclusive). Windows may wrap past midnight.
+    pub before: Option<NaiveTime>,
+    /// Only record programmes flagged `<new/>` in the EPG.
+    #[serde(default)]
+    pub new_only: bool,
+    /// Delete the oldest completed recordings beyond this many.
+    pub keep_last: Option<usize>,
+    pub padding_before_secs: Option<u64>,
+    pub padding_after_secs: Option<u64>,
+    pub created_at: DateTime<Utc>,
+    /// Episodes recorded and since pruned by `keep_last`, so reruns are not recorded again.
+    #[serde(default)]
+    pub pruned_episodes: BTreeSet<String>,
+}
+
+impl SeriesRule {
+    fn title_regex(&self) -> Result<Option<Regex>, regex::Error> {
+        match self.title_match {
+            TitleMatch::Exact => Ok(None),
+            TitleMatch::Regex => RegexBuilder::new(&self.title)
+                .case_insensitive(true)
+                .build()
+                .map(Some),
+        }
+    }
+
+    fn matches(&self, title_regex: Option<&Regex>, programme: &Programme) -> bool {
+        let title_matches = match title_regex {
+            Some(regex) => regex.is_match(&programme.title),
+            None => programme.title.trim().to_lowercase() == self.title.trim().to_lowercase(),
+        };
+
+        title_matches
+            && self
+                .channel
+                .as_ref()
+                .is_none_or(|channel| *channel == programme.channel)
+            && self.category.as_ref().is_none_or(|category| {
+                programme
+                    .categories
+                    .iter()
+                    .any(|c| c.to_lowercase() == category.to_lowercase())
+            })
+            && self.in_window(programme.start.time())
+            && (!self.new_only || programme.new)
+    }
+
+    fn in_window(&self, time: NaiveTime) -> bool {
+        match (self.after, self.before) {
+            (Some(after), Some(before)) if after > before => time >= after || time < before,
+            (after, before) => {
+                after.is_none_or(|after| time >= after) && before.is_none_or(|before| time < before)
+            }
+        }
+    }
+}
+
+/// Series rules persisted as JSON next to the recordings.
+#[derive(Debug, Clone)]
+pub struct SeriesRules {
+    path: PathBuf,
+    rules: Arc<RwLock<Vec<SeriesRule>>>,
+}
+
+impl SeriesRules {
+    pub fn load(recordings_dir: &std::path::Path) -> Self {
+        let path = recordings_dir.join("series_rules.json");
+        Self {
+            rules: Arc::new(RwLock::new(load_json(&path))),
+            path,
+        }
+    }
+
+    pub fn list(&self) -> Vec<SeriesRule> {
+        self.rules.read().unwrap().clone()
+    }
+
+    fn insert(&self, mut rule: SeriesRule) -> SeriesRule {
+        let mut rules = self.rules.write().unwrap();
+        rule.id = rules.iter().map(|r| r.id).max().unwrap_or(0) + 1;
+        rules.push(rule.clone());
+        drop(rules);
+        self.save();
+        rule
+    }
+
+    fn update(&self, id: u64, update: impl FnOnce(&mut SeriesRule)) {
+        if let Some(rule) = self.rules.write().unwrap().iter_mut().find(|r| r.id == id) {
+            update(rule);
+        }
+        self.save();
+    }
+
+    fn remove(&self, id: u64) -> bool {
+        let mut rules = self.rules.write().unwrap();
+        let count = rules.len();
+        rules.retain(|r| r.id != id);
+        let removed = rules.len() != count;
+        drop(rules);
+        self.save();
+        removed
+    }
+
+    fn save(&self) {
+        save_json(&self.path, &*self.rules.read().unwrap());
+    }
+}
+
+/// Schedules recordings for every upcoming programme matching a series rule. Runs on a
+/// blocking thread after each EPG refresh and whenever a rule is added.
+pub fn evaluate_rules(app_state: &AppState, epg: &Epg) {
+    let rules = app_state.series_rules.list();
+    if rules.is_empty() {
+        return;
+    }
+    let Some(playlist) = app_state.cached_playlist_snapshot() else {
+        tracing::warn!("No cached playlist yet, skipping series rules");
+        return;
+    };
+
+    for rule in &rules {
+        schedule_matches(app_state, epg, &playlist, rule);
+        prune_recordings(app_state, rule.id);
+    }
+}
+
+fn schedule_matches(app_state: &AppState, epg: &Epg, playlist: &Playlist, rule: &SeriesRule) {
+    let title_regex = match rule.title_regex() {
+        Ok(regex) => regex,
+        Err(error) => {
+            tracing::warn!(
+                ?error,
+                id = rule.id,
+                "Skipping series rule with invalid title regex"
+            );
+            return;
+        }
+    };
+
+    let now = Utc::now();
+    let mut matches: Vec<&Programme> = epg
+        .overlapping_all(now, DateTime::<Utc>::MAX_UTC)
+        .filter(|p| p.start > now && rule.matches(title_regex.as_ref(), p))
+        .collect();
+    matches.sort_by_key(|p| p.start);
+
+    let config = app_state.recordings.config();
+    let padding_before = rule
+        .padding_before_secs
+        .map_or(config.padding_before, Duration::from_secs);
+    let padding_after = rule
+        .padding_after_secs
+        .map_or(config.padding_after, Duration::from_secs);
+
+    for programme in matches {
+        // Re-read on every iteration so reruns later in this EPG dedupe against
+        // airings scheduled moments ago. Other users' recordings don't count.
+        let mut recordings = app_state.recordings.list();
+        recordings.retain(|r| r.user_id == rule.user_id);
+        if is_already_recorded(rule, &recordings, programme) {
+            continue;
+        }
+
+        let Some(entry) = proxy::preferred_entry(
+            &playlist.entries,
+            &programme.channel,
+            &app_state.proxy_config.failover_priority,
+        ) else {
+            tracing::debug!(channel = %programme.channel, "No playlist entry for series match");
+            continue;
+        };
+
+        let recording = new_recording(programme, padding_before, padding_after);
+        let conflicts = app_state.recordings.conflicts(
+            &entry.url,
+            recording.start,
+            recording.stop,
+            &app_state.shared_streams.urls(),
+            app_state.proxy_config.max_connections,
+        );
+        if !conflicts.is_empty() {
+            tracing::warn!(
+                rule = rule.id,
+                title = %programme.title,
+                start = %programme.start,
+                "Skipping series match, it would exceed the upstream connection limit"
+            );
+            continue;
+        }
+
+        let channel_name = epg
+            .channels
+            .iter()
+            .find(|c| c.id == programme.channel)
+            .map_or_else(|| entry.name.clone(), |c| c.display_name.clone());
+        let recording = app_state.recordings.insert(Recording {
+            user_id: rule.user_id,
+            channel_name,
+            url: entry.url.clone(),
+            series_rule: Some(rule.id),
+            ..recording
+        });
+        tracing::info!(
+            id = recording.id,
+            rule = rule.id,
+            title = %recording.title,
+            "Scheduled series recording"
+        );
+    }
+}
+
+/// Whether this airing, or another airing of the same episode, is already covered.
+/// Failed recordings don't count so a later rerun gets another chance.
+fn is_already_recorded(rule: &SeriesRule, recordings: &[Recording], programme: &Programme) -> bool {
+    let same_airing =
+        |r: &Recording| r.channel == programme.channel && r.programme_start == programme.start;
+    recordings.iter().any(|r| {
+        same_airing(r) || (r.status != RecordingStatus::Failed && r.is_same_episode(programme))
+    }) || episode_key(programme).is_some_and(|episode| rule.pruned_episodes.contains(&episode))
+}
+
+/// Deletes the oldest completed recordings of a rule beyond its `keep_last`.
+pub fn prune_recordings(app_state: &AppState, rule_id: u64) {
+    let Some(keep_last) = app_state
+        .series_rules
+        .list()
+        .into_iter()
+        .find(|r| r.id == rule_id)
+        .and_then(|r| r.keep_last)
+    else {
+        return;
+    };
+
+    let mut completed: Vec<Recording> = app_state
+        .recordings
+        .list()
+        .into_iter()
+        .filter(|r| r.series_rule == Some(rule_id) && r.status == RecordingStatus::Completed)
+        .collect();
+    completed.sort_by_key(|r| std::cmp::Reverse(r.programme_start));
+
+    for recording in completed.into_iter().skip(keep_last) {
+        if app_state.recordings.delete(&recording).is_err() {
+            continue;
+        }
+        tracing::info!(
+            id = recording.id,
+            rule = rule_id,
+            "Pruned old series recording"
+        );
+        if let Some(episode) = recording.episode {
+            app_state.series_rules.update(rule_id, |rule| {
+                rule.pruned_episodes.insert(episode);
+            });
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CreateSeriesRuleRequest {
+    title: String,
+    #[serde(default)]
+    title_match: TitleMatch,
+    channel: Option<String>,
+    category: Option<String>,
+    after: Option<NaiveTime>,
+    before: Option<NaiveTime>,
+    #[serde(default)]
+    new_only: bool,
+    keep_last: Option<usize>,
+    padding_before_secs: Option<u64>,
+    padding_after_secs: Option<u64>,
+}
+
+pub async fn list_series_rules(
+    State(app_state): State<AppState>,
+    Extension(CurrentUser(user)): Extension<CurrentUser>,
+) -> Json<Vec<SeriesRule>> {
+    let mut rules = app_state.series_rules.list();
+    rules.retain(|r| user.can_manage(r.user_id));
+    Json(rules)
+}
+
+pub async fn create_series_rule(
+    State(app_state): State<AppState>,
+    Extension(CurrentUser(user)): Extension<CurrentUser>,
+    Json(request): Json<CreateSeriesRuleRequest>,
+) -> Result<(StatusCode, Json<SeriesRule>), (StatusCode, String)> {
+    if request.title.trim().is_empty() {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "Title must not be empty".to_string(),
+        ));
+    }
+    if request.keep_last == Some(0) {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "keepLast must be at least 1".to_string(),
+        ));
+    }
+
+    let rule = SeriesRule {
+        id: 0,
+        user_id: user.id,
+        title: request.title,
+        title_match: request.title_match,
+        channel: request.channel.filter(|c| !c.is_empty()),
+        category: request.category.filter(|c| !c.is_empty()),
+        after: request.after,
+        before: request.before,
+        new_only: request.new_only,
+        keep_last: request.keep_last,
+        padding_before_secs: request.padding_before_secs,
+        padding_after_secs: request.padding_after_secs,
+        created_at: Utc::now(),
+        pruned_episodes: BTreeSet::new(),
+    };
+    rule.title_regex().map_err(|error| {
+        (
+            StatusCode::BAD_REQUEST,
+            format!("Invalid title regex: {error}"),
+        )
+    })?;
+
+    let rule = app_state.series_rules.insert(rule);
+    tracing::info!(id = rule.id, title = %rule.title, "Added series rule");
+    if let Some(epg) = app_state.cached_epg_snapshot() {
+        let app_state = app_state.clone();
+        let _ = tokio::task::spawn_blocking(move || evaluate_rules(&app_state, &epg)).await;
+    }
+    Ok((StatusCode::CREATED, Json(rule)))
+}
+
+/// Deletes a rule and cancels its scheduled recordings. Finished recordings are kept.
+pub async fn delete_series_rule(
+    Path(id): Path<u64>,
+    State(app_state): State<AppState>,
+    Extension(CurrentUser(user)): Extension<CurrentUser>,
+) -> Result<StatusCode, (StatusCode, &'static str)> {
+    let owned = app_state
+        .series_rules
+        .list()
+        .iter()
+        .any(|r| r.id == id && user.can_manage(r.user_id));
+    if !owned || !app_state.series_rules.remove(id) {
+        return Err((StatusCode::NOT_FOUND, "Series rule not found"));
+    }
+
+    for recording in app_state.recordings.list() {
+        if recording.series_rule == Some(id) && recording.status == RecordingStatus::Scheduled {
+            app_state.recordings.update(recording.id, |r| {
+                r.status = RecordingStatus::Cancelled;
+            });
+        }
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::epg::{self, EpisodeNum};
+
+    fn rule(title: &str, title_match: TitleMatch) -> SeriesRule {
+        SeriesRule {
+            id: 1,
+            user_id: 1,
+            title: title.to_string(),
+            title_match,
+            channel: None,
+            category: None,
+            after: None,
+            before: None,
+            new_only: false,
+            keep_last: None,
+            padding_before_secs: None,
+            padding_after_secs: None,
+            created_at: Utc::now(),
+            pruned_episodes: BTreeSet::new(),
+        }
+    }
+
+    fn programme(title: &str, start: &str, episode: Option<&str>) -> Programme {
+        Programme {
+            categories: vec!["News".to_string()],
+            episode_nums: episode
+                .map(|value| EpisodeNum {
+                    system: Some("xmltv_ns".to_string()),
+                    value: value.to_string(),
+                })
+                .into_iter()
+                .collect(),
+            ..epg::tests::programme(title, start)
+        }
+    }
+
+    #[test]
+    fn test_rule_matches_title_channel_category_and_window() {
+        let news = programme("Rapport", "2024-10-17T19:30:00+02:00", None);
+
+        let exact = rule("rapport", TitleMatch::Exact);
+        assert!(exact.matches(None, &news));
+        assert!(!exact.matches(
+            None,
+            &programme("Sportnytt", "2024-10-17T19:30:00+02:00", None)
+        ));
+
+        let pattern = rule("^(rapport|aktuellt)$", TitleMatch::Regex);
+        let regex = pattern.title_regex().unwrap();
+        assert!(pattern.matches(regex.as_ref(), &news));
+
+        let filtered = SeriesRule {
+            channel: Some("svt2.se".to_string()),
+            ..exact.clone()
+        };
+        assert!(!filtered.matches(None, &news));
+        let filtered = SeriesRule {
+            category: Some("news".to_string()),
+            new_only: true,
+            ..exact.clone()
+        };
+        assert!(!filtered.matches(None, &news));
+        assert!(filtered.matches(
+            None,
+            &Programme {
+                new: true,
+                ..news.clone()
+            }
+        ));
+
+        // Time windows compare in the programme's own timezone and may wrap midnight.
+        let evening = SeriesRule {
+            after: NaiveTime::from_hms_opt(19, 0, 0),
+            before: NaiveTime::from_hms_opt(20, 0, 0),
+            ..exact.clone()
+        };
+        assert!(evening.matches(None, &news));
+        let late = SeriesRule {
+            after: NaiveTime::from_hms_opt(22, 0, 0),
+            before: NaiveTime::from_hms_opt(2, 0, 0),
+            ..exact
+        };
+        assert!(!late.matches(None, &news));
+        assert!(late.matches(
+            None,
+            &programme("Rapport", "2024-10-17T23:30:00+02:00", None)
+        ));
+    }
+
+    #[test]
+    fn test_already_recorded_dedupes_episodes_across_airings() {
+        let rule = rule("Bron", TitleMatch::Exact);
+        let first = programme("Bron", "2024-10-17T21:00:00+02:00", Some("0.4."));
+        let rerun = programme("Bron", "2024-10-19T23:00:00+02:00", Some("0 . 4 ."));
+        let next = programme("Bron", "2024-10-24T21:00:00+02:00", Some("0.5."));
+
+        let mut recording = new_recording(&first, Duration::ZERO, Duration::ZERO);
+        assert!(is_already_recorded(&rule, &[recording.clone()], &first));
+        assert!(is_already_recorded(&rule, &[recording.clone()], &rerun));
+        assert!(!is_already_recorded(&rule, &[recording.clone()], &next));
+
+        // A failed recording lets a rerun try again, but never the same airing.
+        recording.status = RecordingStatus::Failed;
+        assert!(!is_already_recorded(&rule, &[recording.clone()], &rerun));
+        assert!(is_already_recorded(&rule, &[recording], &first));
+
+        let pruned = SeriesRule {
+            pruned_episodes: BTreeSet::from(["0.4.".to_string()]),
+            ..rule
+        };
+        assert!(is_already_recorded(&pruned, &[], &rerun));
+    }
+
+    #[test]
+    fn test_shared_description_without_episode_number_is_not_a_duplicate() {
+        let rule = rule("Rapport", TitleMatch::Exact);
+        let airing = |start| Programme {
+            desc: "Nyheter från Sverige och världen.".to_string(),
+            ..programme("Rapport", start, None)
+        };
+        let monday = airing("2024-10-21T19:30:00+02:00");
+        let tuesday = airing("2024-10-22T19:30:00+02:00");
+
+        let recordings = [new_recording(&monday, Duration::ZERO, Duration::ZERO)];
+        assert_eq!(recordings[0].episode, None);
+        assert!(!is_already_recorded(&rule, &recordings, &tuesday));
+        assert!(is_already_recorded(&rule, &recordings, &monday));
+    }
+}
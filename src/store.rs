@@ -0,0 +1,78 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use serde::{de::DeserializeOwned, Serialize};
+
+/// Where user data such as saved searches is kept, `DATA_DIR` or `./data`.
+pub fn data_dir() -> PathBuf {
+    std::env::var("DATA_DIR")
+        .unwrap_or_else(|_| "./data".to_string())
+        .into()
+}
+
+/// Reads a JSON file written by [`save_json`], falling back to the default when it is
+/// missing or unreadable. A file that fails to parse is moved aside to `*.corrupt` first,
+/// so the next save doesn't overwrite what may still be recovered by hand.
+pub fn load_json<T: DeserializeOwned + Default>(path: &Path) -> T {
+    match std::fs::read_to_string(path) {
+        Ok(content) => serde_json::from_str(&content).unwrap_or_else(|error| {
+            let corrupt_path = path.with_extension("json.corrupt");
+            match std::fs::rename(path, &corrupt_path) {
+                Ok(()) => tracing::error!(
+                    ?error,
+                    "Failed to parse {}, moved it to {} and starting empty",
+                    path.display(),
+                    corrupt_path.display()
+                ),
+                Err(rename_error) => tracing::error!(
+                    ?error,
+                    ?rename_error,
+                    "Failed to parse {} or move it aside, starting empty",
+                    path.display()
+                ),
+            }
+            T::default()
+        }),
+        Err(_) => T::default(),
+    }
+}
+
+/// Writes `value` as pretty JSON via a temporary file so readers never see a partial write.
+pub fn save_json<T: Serialize>(path: &Path, value: &T) {
+    let result = (|| -> Result<()> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let json = serde_json::to_string_pretty(value)?;
+        let tmp_path = path.with_extension("json.tmp");
+        std::fs::write(&tmp_path, json)?;
+        std::fs::rename(&tmp_path, path)?;
+        Ok(())
+    })();
+    if let Err(error) = result {
+        tracing::error!(?error, "Failed to save {}", path.display());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_load_json_moves_corrupt_file_aside() {
+        let dir = std::env::temp_dir().join(format!("store-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("users.json");
+        std::fs::write(&path, "[{\"id\": 1,").unwrap();
+
+        let users: Vec<u64> = load_json(&path);
+        assert!(users.is_empty());
+        assert!(!path.exists());
+        assert_eq!(
+            std::fs::read_to_string(dir.join("users.json.corrupt")).unwrap(),
+            "[{\"id\": 1,"
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
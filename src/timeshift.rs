@@ -0,0 +1,416 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    path::PathBuf,
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Result};
+use axum::{
+    body::Body,
+    extract::{Path, State},
+    http::{Response, StatusCode},
+};
+use futures::StreamExt;
+use tokio::sync::Notify;
+
+use crate::{
+    auth::random_token,
+    hls::{render_playlist, SegmentData, SegmentInfo, StreamSegmenter},
+    proxy::{self, OnLag},
+    AppState,
+};
+
+const FIRST_SEGMENT_TIMEOUT: Duration = Duration::from_secs(20);
+
+#[derive(Debug, Clone)]
+pub struct TimeshiftConfig {
+    /// How far back viewers can rewind; timeshift is disabled when unset.
+    pub window: Option<Duration>,
+    pub dir: PathBuf,
+}
+
+impl TimeshiftConfig {
+    pub fn from_env() -> Self {
+        let config = Self {
+            window: std::env::var("TIMESHIFT_MINUTES")
+                .ok()
+                .and_then(|value| value.parse::<u64>().ok())
+                .filter(|&minutes| minutes > 0)
+                .map(|minutes| Duration::from_secs(minutes * 60)),
+            dir: std::env::var("TIMESHIFT_DIR")
+                .unwrap_or_else(|_| "./timeshift".to_string())
+                .into(),
+        };
+        config.remove_stale_buffers();
+        config
+    }
+
+    /// Deletes buffer directories left behind by a previous run that didn't get to clean
+    /// up, such as after a crash. Only directories named like buffer ids are touched.
+    fn remove_stale_buffers(&self) {
+        let Ok(entries) = std::fs::read_dir(&self.dir) else {
+            return;
+        };
+        for entry in entries.flatten() {
+            let is_buffer = entry.file_type().is_ok_and(|kind| kind.is_dir())
+                && entry.file_name().to_str().is_some_and(is_buffer_id);
+            if !is_buffer {
+                continue;
+            }
+            if let Err(error) = std::fs::remove_dir_all(entry.path()) {
+                tracing::warn!(?error, "Failed to remove {}", entry.path().display());
+            }
+        }
+    }
+}
+
+/// Whether `name` looks like the random ids buffers are created with.
+fn is_buffer_id(name: &str) -> bool {
+    name.len() == 64 && name.bytes().all(|byte| byte.is_ascii_hexdigit())
+}
+
+#[derive(Debug, Default)]
+struct BufferState {
+    segments: VecDeque<SegmentInfo>,
+    buffered: Duration,
+    next_sequence: u64,
+    discontinuity_sequence: u64,
+    ended: bool,
+    error: Option<String>,
+}
+
+/// The last few minutes of a live channel as segments on disk.
+#[derive(Debug)]
+pub struct TimeshiftBuffer {
+    id: String,
+    url: String,
+    dir: PathBuf,
+    state: RwLock<BufferState>,
+    last_access: Mutex<Instant>,
+    changed: Notify,
+}
+
+impl TimeshiftBuffer {
+    fn new(id: String, url: String, dir: PathBuf) -> Self {
+        Self {
+            id,
+            url,
+            dir,
+            state: RwLock::new(BufferState::default()),
+            last_access: Mutex::new(Instant::now()),
+            changed: Notify::new(),
+        }
+    }
+
+    fn touch(&self) {
+        *self.last_access.lock().unwrap() = Instant::now();
+    }
+
+    fn idle_for(&self) -> Duration {
+        self.last_access.lock().unwrap().elapsed()
+    }
+
+    fn is_ended(&self) -> bool {
+        self.state.read().unwrap().ended
+    }
+
+    fn segment_path(&self, sequence: u64) -> PathBuf {
+        self.dir.join(format!("{sequence}.ts"))
+    }
+
+    async fn push_segment(&self, segment: SegmentData, window: Duration) -> Result<()> {
+        let sequence = self.state.read().unwrap().next_sequence;
+        tokio::fs::write(self.segment_path(sequence), &segment.data).await?;
+
+        let mut evicted = Vec::new();
+        {
+            let mut state = self.state.write().unwrap();
+            state.next_sequence += 1;
+            state.buffered += segment.duration;
+            state.segments.push_back(SegmentInfo {
+                sequence,
+                duration: segment.duration,
+                discontinuity: segment.discontinuity,
+            });
+            while state.buffered > window && state.segments.len() > 1 {
+                let Some(removed) = state.segments.pop_front() else {
+                    break;
+                };
+                state.buffered = state.buffered.saturating_sub(removed.duration);
+                if removed.discontinuity {
+                    state.discontinuity_sequence += 1;
+                }
+                evicted.push(removed.sequence);
+            }
+        }
+        self.changed.notify_waiters();
+
+        for sequence in evicted {
+            let _ = tokio::fs::remove_file(self.segment_path(sequence)).await;
+        }
+        Ok(())
+    }
+
+    fn finish(&self, error: Option<String>) {
+        let mut state = self.state.write().unwrap();
+        state.ended = true;
+        state.error = error;
+        drop(state);
+        self.changed.notify_waiters();
+    }
+
+    /// Waits until the first segment is available or the buffer has stopped.
+    async fn wait_until_playable(&self, timeout: Duration) {
+        let _ = tokio::time::timeout(timeout, async {
+            loop {
+                let changed = self.changed.notified();
+                {
+                    let state = self.state.read().unwrap();
+                    if !state.segments.is_empty() || state.ended {
+                        return;
+                    }
+                }
+                changed.await;
+            }
+        })
+        .await;
+    }
+
+    fn has_segment(&self, sequence: u64) -> bool {
+        self.state
+            .read()
+            .unwrap()
+            .segments
+            .iter()
+            .any(|segment| segment.sequence == sequence)
+    }
+
+    fn playlist(&self) -> Result<String> {
+        let state = self.state.read().unwrap();
+        if state.segments.is_empty() {
+            return Err(anyhow!(state
+                .error
+                .clone()
+                .unwrap_or_else(|| "no segments available yet".to_string())));
+        }
+        Ok(render_playlist(
+            &format!("/timeshift-segment/{}", self.id),
+            state.segments.iter(),
+            state.discontinuity_sequence,
+            state.ended,
+        ))
+    }
+}
+
+/// Running timeshift buffers keyed by upstream URL. Each buffer gets a random id, which
+/// also names its directory so a replacement never shares it with the ended buffer it
+/// replaces while that one is still cleaning up.
+#[derive(Debug, Clone, Default)]
+pub struct TimeshiftBuffers(Arc<Mutex<HashMap<String, Arc<TimeshiftBuffer>>>>);
+
+impl TimeshiftBuffers {
+    fn get(&self, id: &str) -> Option<Arc<TimeshiftBuffer>> {
+        self.0
+            .lock()
+            .unwrap()
+            .values()
+            .find(|buffer| buffer.id == id)
+            .cloned()
+    }
+
+    /// Returns the buffer for `url`, starting one unless timeshift is disabled.
+    fn get_or_start(&self, app_state: &AppState, url: &str) -> Option<Arc<TimeshiftBuffer>> {
+        let window = app_state.timeshift_config.window?;
+        let mut buffers = self.0.lock().unwrap();
+        if let Some(buffer) = buffers.get(url).filter(|buffer| !buffer.is_ended()) {
+            return Some(buffer.clone());
+        }
+
+        let id = random_token();
+        let dir = app_state.timeshift_config.dir.join(&id);
+        let buffer = Arc::new(TimeshiftBuffer::new(id, url.to_string(), dir));
+        buffers.insert(url.to_string(), buffer.clone());
+        tokio::spawn(run_buffer(app_state.clone(), buffer.clone(), window));
+        Some(buffer)
+    }
+
+    fn remove(&self, buffer: &Arc<TimeshiftBuffer>) {
+        let mut buffers = self.0.lock().unwrap();
+        if buffers
+            .get(&buffer.url)
+            .is_some_and(|current| Arc::ptr_eq(current, buffer))
+        {
+            buffers.remove(&buffer.url);
+        }
+    }
+}
+
+/// Starts buffering a channel that is being watched live, if timeshift is enabled.
+pub fn start_buffer(app_state: &AppState, url: &str) {
+    app_state.timeshift_buffers.get_or_start(app_state, url);
+}
+
+async fn run_buffer(app_state: AppState, buffer: Arc<TimeshiftBuffer>, window: Duration) {
+    tracing::info!(url = %buffer.url, "Starting timeshift buffer");
+    let error = match fill_buffer(&app_state, &buffer, window).await {
+        Ok(()) => None,
+        Err(error) => {
+            tracing::warn!(?error, url = %buffer.url, "Timeshift buffer failed");
+            Some(error.to_string())
+        }
+    };
+    buffer.finish(error);
+    app_state.timeshift_buffers.remove(&buffer);
+    if let Err(error) = tokio::fs::remove_dir_all(&buffer.dir).await {
+        tracing::warn!(?error, "Failed to remove {}", buffer.dir.display());
+    }
+    tracing::info!(url = %buffer.url, "Stopped timeshift buffer");
+}
+
+async fn fill_buffer(
+    app_state: &AppState,
+    buffer: &TimeshiftBuffer,
+    window: Duration,
+) -> Result<()> {
+    tokio::fs::create_dir_all(&buffer.dir).await?;
+
+    let upstream = proxy::open_live_stream(app_state, &buffer.url, OnLag::Skip).await?;
+    if !upstream.status.is_success() {
+        return Err(anyhow!("upstream returned {}", upstream.status));
+    }
+
+    let mut body = upstream.body;
+    let mut segmenter = StreamSegmenter::new(app_state.hls_config.segment_duration);
+    while let Some(chunk) = body.next().await {
+        let chunk = chunk?;
+        for segment in segmenter.push(&chunk)? {
+            buffer.push_segment(segment, window).await?;
+        }
+
+        // Keep buffering while anyone watches the channel live or through the playlist.
+        let live_viewers = app_state
+            .shared_streams
+            .consumers(&buffer.url)
+            .saturating_sub(1);
+        if live_viewers == 0 && buffer.idle_for() > app_state.hls_config.idle_timeout {
+            return Ok(());
+        }
+    }
+
+    if let Some(segment) = segmenter.finish() {
+        buffer.push_segment(segment, window).await?;
+    }
+    Ok(())
+}
+
+pub async fn timeshift_playlist(
+    Path(stream_path): Path<String>,
+    State(app_state): State<AppState>,
+) -> Result<Response<Body>, (StatusCode, String)> {
+    let buffer = app_state
+        .timeshift_buffers
+        .get_or_start(&app_state, &stream_path)
+        .ok_or((StatusCode::NOT_FOUND, "Timeshift is disabled".to_string()))?;
+    buffer.touch();
+    buffer.wait_until_playable(FIRST_SEGMENT_TIMEOUT).await;
+
+    let playlist = buffer.playlist().map_err(|error| {
+        (
+            StatusCode::BAD_GATEWAY,
+            format!("Failed to start timeshift buffer: {}", error),
+        )
+    })?;
+
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", "application/vnd.apple.mpegurl")
+        .header("Cache-Control", "no-cache")
+        .body(Body::from(playlist))
+        .unwrap())
+}
+
+pub async fn timeshift_segment(
+    Path((buffer_id, segment)): Path<(String, String)>,
+    State(app_state): State<AppState>,
+) -> Result<Response<Body>, (StatusCode, &'static str)> {
+    let sequence: u64 = segment
+        .strip_suffix(".ts")
+        .and_then(|sequence| sequence.parse().ok())
+        .ok_or((StatusCode::NOT_FOUND, "Unknown segment"))?;
+    let buffer = app_state
+        .timeshift_buffers
+        .get(&buffer_id)
+        .ok_or((StatusCode::NOT_FOUND, "Unknown timeshift buffer"))?;
+    buffer.touch();
+    if !buffer.has_segment(sequence) {
+        return Err((StatusCode::NOT_FOUND, "Segment is no longer available"));
+    }
+    let data = tokio::fs::read(buffer.segment_path(sequence))
+        .await
+        .map_err(|_| (StatusCode::NOT_FOUND, "Segment is no longer available"))?;
+
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", "video/mp2t")
+        .header("Cache-Control", "max-age=3600")
+        .body(Body::from(data))
+        .unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::Bytes;
+
+    fn segment(seconds: u64) -> SegmentData {
+        SegmentData {
+            duration: Duration::from_secs(seconds),
+            discontinuity: false,
+            data: Bytes::from_static(b"segment"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_buffer_evicts_segments_beyond_window() {
+        let dir = std::env::temp_dir().join(format!("timeshift-test-{}", std::process::id()));
+        tokio::fs::create_dir_all(&dir).await.unwrap();
+        let buffer = TimeshiftBuffer::new("abc".to_string(), "http://a/1".to_string(), dir.clone());
+        let window = Duration::from_secs(10);
+
+        for _ in 0..4 {
+            buffer.push_segment(segment(4), window).await.unwrap();
+        }
+
+        // 16s buffered against a 10s window drops the two oldest segments.
+        assert!(!buffer.has_segment(1));
+        assert!(buffer.has_segment(2) && buffer.has_segment(3));
+        assert!(!buffer.segment_path(0).exists());
+        assert!(buffer.segment_path(3).exists());
+        let playlist = buffer.playlist().unwrap();
+        // Evicting segments makes it a sliding live playlist, which EVENT playlists can't be.
+        assert!(!playlist.contains("#EXT-X-PLAYLIST-TYPE"));
+        assert!(playlist.contains("#EXT-X-MEDIA-SEQUENCE:2\n"));
+
+        tokio::fs::remove_dir_all(&dir).await.unwrap();
+    }
+
+    #[test]
+    fn test_remove_stale_buffers_keeps_unrelated_files() {
+        let dir = std::env::temp_dir().join(format!("timeshift-stale-{}", std::process::id()));
+        let stale = dir.join(random_token());
+        std::fs::create_dir_all(&stale).unwrap();
+        std::fs::write(stale.join("0.ts"), b"segment").unwrap();
+        std::fs::create_dir_all(dir.join("keep")).unwrap();
+
+        let config = TimeshiftConfig {
+            window: None,
+            dir: dir.clone(),
+        };
+        config.remove_stale_buffers();
+        assert!(!stale.exists());
+        assert!(dir.join("keep").exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
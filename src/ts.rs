@@ -0,0 +1,322 @@
+//! Minimal MPEG-TS helpers: packet alignment, header fields and the PAT/PMT tables
+//! needed to find keyframes. Nothing here decodes or rewrites media.
+
+use axum::body::Bytes;
+
+pub const TS_PACKET_SIZE: usize = 188;
+pub const TS_SYNC_BYTE: u8 = 0x47;
+pub const PAT_PID: u16 = 0x0000;
+/// PCR and PTS tick at 90kHz.
+pub const CLOCK_HZ: u64 = 90_000;
+
+/// Re-chunks an MPEG-TS byte stream so only whole packets are emitted, which lets a
+/// reconnected upstream be spliced in without the client losing packet sync.
+#[derive(Debug, Default)]
+pub struct TsPacketAligner {
+    pending: Vec<u8>,
+    synced: bool,
+}
+
+impl TsPacketAligner {
+    pub fn push(&mut self, chunk: &[u8]) -> Option<Bytes> {
+        self.pending.extend_from_slice(chunk);
+
+        if self.synced && self.pending.first() != Some(&TS_SYNC_BYTE) {
+            self.synced = false;
+        }
+        if !self.synced {
+            match find_ts_sync(&self.pending) {
+                Some(offset) => {
+                    self.pending.drain(..offset);
+                    self.synced = true;
+                }
+                None => {
+                    let keep_from = self.pending.len().saturating_sub(TS_PACKET_SIZE);
+                    self.pending.drain(..keep_from);
+                    return None;
+                }
+            }
+        }
+
+        let whole_packets = self.pending.len() / TS_PACKET_SIZE * TS_PACKET_SIZE;
+        if whole_packets == 0 {
+            return None;
+        }
+        let rest = self.pending.split_off(whole_packets);
+        Some(Bytes::from(std::mem::replace(&mut self.pending, rest)))
+    }
+
+    /// Drops any partial packet so the next upstream is aligned from its first sync byte.
+    pub fn resync(&mut self) {
+        self.pending.clear();
+        self.synced = false;
+    }
+}
+
+/// Finds the first offset where two consecutive packets start with the sync byte.
+pub fn find_ts_sync(data: &[u8]) -> Option<usize> {
+    (0..data.len().saturating_sub(TS_PACKET_SIZE)).find(|&offset| {
+        data[offset] == TS_SYNC_BYTE && data[offset + TS_PACKET_SIZE] == TS_SYNC_BYTE
+    })
+}
+
+/// A single 188-byte transport stream packet.
+#[derive(Debug, Clone, Copy)]
+pub struct TsPacket<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> TsPacket<'a> {
+    pub fn new(data: &'a [u8]) -> Option<Self> {
+        (data.len() == TS_PACKET_SIZE && data[0] == TS_SYNC_BYTE).then_some(Self { data })
+    }
+
+    pub fn pid(&self) -> u16 {
+        (u16::from(self.data[1] & 0x1f) << 8) | u16::from(self.data[2])
+    }
+
+    pub fn payload_unit_start(&self) -> bool {
+        self.data[1] & 0x40 != 0
+    }
+
+    fn adaptation_field(&self) -> Option<&'a [u8]> {
+        if self.data[3] & 0x20 == 0 {
+            return None;
+        }
+        let length = usize::from(self.data[4]);
+        self.data.get(5..5 + length)
+    }
+
+    /// Set by encoders on the packet that starts a keyframe.
+    pub fn random_access(&self) -> bool {
+        self.adaptation_field()
+            .and_then(|field| field.first())
+            .is_some_and(|flags| flags & 0x40 != 0)
+    }
+
+    /// Program clock reference base in 90kHz ticks.
+    pub fn pcr(&self) -> Option<u64> {
+        let field = self.adaptation_field()?;
+        if field.first()? & 0x10 == 0 || field.len() < 7 {
+            return None;
+        }
+        let base = (u64::from(field[1]) << 25)
+            | (u64::from(field[2]) << 17)
+            | (u64::from(field[3]) << 9)
+            | (u64::from(field[4]) << 1)
+            | (u64::from(field[5]) >> 7);
+        Some(base)
+    }
+
+    pub fn payload(&self) -> Option<&'a [u8]> {
+        if self.data[3] & 0x10 == 0 {
+            return None;
+        }
+        let start = match self.adaptation_field() {
+            Some(field) => 5 + field.len(),
+            None => 4,
+        };
+        self.data.get(start..)
+    }
+}
+
+/// Returns the PSI section that starts in a payload-unit-start packet's payload.
+fn psi_section(payload: &[u8]) -> Option<&[u8]> {
+    let pointer = usize::from(*payload.first()?);
+    let section = payload.get(1 + pointer..)?;
+    let section_length = (usize::from(section.get(1)? & 0x0f) << 8) | usize::from(*section.get(2)?);
+    // Drop the trailing CRC, the tables are small enough to never span packets in practice.
+    section
+        .get(..(3 + section_length).checked_sub(4)?)
+        .filter(|section| !section.is_empty())
+}
+
+/// PID of the first program's PMT, as listed in the PAT.
+pub fn parse_pat(payload: &[u8]) -> Option<u16> {
+    let section = psi_section(payload)?;
+    if *section.first()? != 0x00 {
+        return None;
+    }
+    section
+        .get(8..)?
+        .chunks_exact(4)
+        .find(|program| u16::from_be_bytes([program[0], program[1]]) != 0)
+        .map(|program| (u16::from(program[2] & 0x1f) << 8) | u16::from(program[3]))
+}
+
+/// PID of the first video elementary stream listed in a PMT.
+pub fn parse_pmt_video_pid(payload: &[u8]) -> Option<u16> {
+    let section = psi_section(payload)?;
+    if *section.first()? != 0x02 {
+        return None;
+    }
+    let program_info_length =
+        (usize::from(section.get(10)? & 0x0f) << 8) | usize::from(*section.get(11)?);
+    let mut streams = section.get(12 + program_info_length..)?;
+    while streams.len() >= 5 {
+        let stream_type = streams[0];
+        let pid = (u16::from(streams[1] & 0x1f) << 8) | u16::from(streams[2]);
+        if is_video_stream_type(stream_type) {
+            return Some(pid);
+        }
+        let es_info_length = (usize::from(streams[3] & 0x0f) << 8) | usize::from(streams[4]);
+        streams = streams.get(5 + es_info_length..)?;
+    }
+    None
+}
+
+fn is_video_stream_type(stream_type: u8) -> bool {
+    // MPEG-1, MPEG-2, H.264 and HEVC video.
+    matches!(stream_type, 0x01 | 0x02 | 0x1b | 0x24)
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    /// Builds a packet with an optional adaptation field carrying flags and a PCR.
+    pub fn packet(
+        pid: u16,
+        unit_start: bool,
+        keyframe: bool,
+        pcr: Option<u64>,
+        payload: &[u8],
+    ) -> Vec<u8> {
+        let mut data = vec![TS_SYNC_BYTE, ((pid >> 8) as u8) & 0x1f, pid as u8, 0x10];
+        if unit_start {
+            data[1] |= 0x40;
+        }
+        if keyframe || pcr.is_some() {
+            data[3] |= 0x20;
+            let mut field = vec![if keyframe { 0x40 } else { 0 }];
+            if let Some(pcr) = pcr {
+                field[0] |= 0x10;
+                field.extend_from_slice(&[
+                    (pcr >> 25) as u8,
+                    (pcr >> 17) as u8,
+                    (pcr >> 9) as u8,
+                    (pcr >> 1) as u8,
+                    ((pcr & 1) << 7) as u8,
+                    0,
+                ]);
+            }
+            data.push(field.len() as u8);
+            data.extend_from_slice(&field);
+        }
+        data.extend_from_slice(payload);
+        data.resize(TS_PACKET_SIZE, 0xff);
+        data
+    }
+
+    pub fn pat_packet(pmt_pid: u16) -> Vec<u8> {
+        let section = [
+            0x00,
+            0x00,
+            0xb0,
+            0x0d,
+            0x00,
+            0x01,
+            0xc1,
+            0x00,
+            0x00,
+            0x00,
+            0x01,
+            0xe0 | (pmt_pid >> 8) as u8,
+            pmt_pid as u8,
+            0,
+            0,
+            0,
+            0,
+        ];
+        packet(PAT_PID, true, false, None, &section)
+    }
+
+    pub fn pmt_packet(pmt_pid: u16, video_pid: u16) -> Vec<u8> {
+        let section = [
+            0x00,
+            0x02,
+            0xb0,
+            0x17,
+            0x00,
+            0x01,
+            0xc1,
+            0x00,
+            0x00,
+            0xe1,
+            0x00,
+            0xf0,
+            0x00,
+            0x0f,
+            0xe1,
+            0x01,
+            0xf0,
+            0x00, // AAC audio
+            0x1b,
+            0xe0 | (video_pid >> 8) as u8,
+            video_pid as u8,
+            0xf0,
+            0x00, // H.264 video
+            0,
+            0,
+            0,
+            0,
+        ];
+        packet(pmt_pid, true, false, None, &section)
+    }
+
+    fn ts_packet(marker: u8) -> Vec<u8> {
+        let mut packet = vec![marker; TS_PACKET_SIZE];
+        packet[0] = TS_SYNC_BYTE;
+        packet
+    }
+
+    #[test]
+    fn test_ts_aligner_emits_whole_packets_across_reconnects() {
+        let mut aligner = TsPacketAligner::default();
+        let stream = [ts_packet(1), ts_packet(2), ts_packet(3)].concat();
+
+        let first = aligner.push(&stream[..300]).unwrap();
+        assert_eq!(first.len(), TS_PACKET_SIZE);
+        assert_eq!(first[1], 1);
+
+        // Upstream drops mid-packet; the reconnected stream starts with garbage.
+        aligner.resync();
+        let reconnected = [vec![0xff; 50], ts_packet(4), ts_packet(5)].concat();
+        let second = aligner.push(&reconnected).unwrap();
+        assert_eq!(second.len(), 2 * TS_PACKET_SIZE);
+        assert_eq!(second[0], TS_SYNC_BYTE);
+        assert_eq!(second[1], 4);
+        assert!(aligner.push(&[]).is_none());
+    }
+
+    #[test]
+    fn test_packet_header_fields() {
+        let data = packet(0x100, true, true, Some(90_000 * 5 + 1), b"payload");
+        let packet = TsPacket::new(&data).unwrap();
+        assert_eq!(packet.pid(), 0x100);
+        assert!(packet.payload_unit_start());
+        assert!(packet.random_access());
+        assert_eq!(packet.pcr(), Some(90_000 * 5 + 1));
+        assert!(packet.payload().unwrap().starts_with(b"payload"));
+    }
+
+    #[test]
+    fn test_parse_program_tables() {
+        let pat = pat_packet(0x1000);
+        let pat = TsPacket::new(&pat).unwrap();
+        assert_eq!(parse_pat(pat.payload().unwrap()), Some(0x1000));
+
+        let pmt = pmt_packet(0x1000, 0x100);
+        let pmt = TsPacket::new(&pmt).unwrap();
+        assert_eq!(parse_pmt_video_pid(pmt.payload().unwrap()), Some(0x100));
+    }
+
+    #[test]
+    fn test_parse_truncated_program_tables() {
+        for section_length in [0, 1] {
+            let payload = [0x00, 0x00, 0xb0, section_length, 0xff, 0xff];
+            assert_eq!(parse_pat(&payload), None);
+            assert_eq!(parse_pmt_video_pid(&payload), None);
+        }
+    }
+}